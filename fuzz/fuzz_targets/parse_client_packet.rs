@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use pigeonvc2::protocol;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = protocol::parse_from_client_packet(data);
+});
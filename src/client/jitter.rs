@@ -0,0 +1,277 @@
+// src/client/jitter.rs
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// How many frames above the target depth are tolerated before the oldest
+/// ones are dropped to catch up.
+pub const DEPTH_SLACK: usize = 2;
+/// Sequence jumps larger than this are treated as a restarted stream.
+pub const MAX_SEQ_JUMP: i64 = 1000;
+pub const IDLE_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Debug, Clone)]
+pub struct JitterConfig {
+    /// Media clock rate of the frame timestamps, in Hz.
+    pub clock_rate: u32,
+    pub frame_ms: u32,
+    pub min_depth: usize,
+    pub max_depth: usize,
+    /// Concealed frames played into an empty buffer before the talker is
+    /// considered silent.
+    pub max_conceal: usize,
+}
+
+impl Default for JitterConfig {
+    fn default() -> Self {
+        Self {
+            clock_rate: 48000,
+            frame_ms: 20,
+            min_depth: 1,
+            max_depth: 10,
+            max_conceal: 5,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct JitterStats {
+    pub received: u64,
+    pub played: u64,
+    pub concealed: u64,
+    /// Frames that were missing when their turn came.
+    pub lost: u64,
+    /// Frames that arrived after their turn had passed.
+    pub late: u64,
+    pub duplicates: u64,
+    /// Frames discarded to shrink an overfull buffer.
+    pub dropped: u64,
+    pub jitter_ms: f64,
+    pub target_depth: usize,
+}
+
+/// Receives the playout decisions, one per talker and tick.
+pub trait Playout {
+    fn play(&mut self, talker: u64, seq: u16, timestamp: u32, data: &[u8]);
+    fn conceal(&mut self, talker: u64, seq: u16);
+}
+
+struct TalkerStream {
+    frames: BTreeMap<u64, (u32, Vec<u8>)>,
+    highest: Option<u64>,
+    played: Option<u64>,
+    playing: bool,
+    underruns: usize,
+    buffering_since: Instant,
+    last_arrival: Option<(Instant, u32)>,
+    last_heard: Instant,
+    stats: JitterStats,
+}
+
+impl TalkerStream {
+    fn new(now: Instant, target_depth: usize) -> Self {
+        Self {
+            frames: BTreeMap::new(),
+            highest: None,
+            played: None,
+            playing: false,
+            underruns: 0,
+            buffering_since: now,
+            last_arrival: None,
+            last_heard: now,
+            stats: JitterStats {
+                target_depth,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Maps a wrapping sequence number onto a monotonic one, relative to the
+    /// highest sequence seen so far.
+    fn extend(&self, seq: u16) -> Option<u64> {
+        let highest = self.highest?;
+        let delta = seq.wrapping_sub(highest as u16) as i16 as i64;
+        Some((highest as i64 + delta) as u64)
+    }
+
+    /// Discards the oldest buffered frame. While playing, the slots up to it
+    /// are skipped and any gap before it counts as lost.
+    fn drop_oldest(&mut self) {
+        let Some((dropped, _)) = self.frames.pop_first() else {
+            return;
+        };
+        self.stats.dropped += 1;
+        if self.playing {
+            let next = self.played.map_or(dropped, |played| played + 1);
+            self.stats.lost += dropped.saturating_sub(next);
+            self.played = Some(dropped);
+        }
+    }
+
+    fn restart(&mut self, now: Instant) {
+        self.frames.clear();
+        self.highest = None;
+        self.played = None;
+        self.playing = false;
+        self.underruns = 0;
+        self.buffering_since = now;
+        self.last_arrival = None;
+    }
+}
+
+/// Per-talker playout buffer whose depth follows the measured interarrival
+/// jitter. It never reads the clock itself; callers pass `now` in.
+pub struct JitterBuffer {
+    config: JitterConfig,
+    talkers: HashMap<u64, TalkerStream>,
+}
+
+impl Default for JitterBuffer {
+    fn default() -> Self {
+        Self::new(JitterConfig::default())
+    }
+}
+
+impl JitterBuffer {
+    pub fn new(config: JitterConfig) -> Self {
+        Self {
+            config,
+            talkers: HashMap::new(),
+        }
+    }
+
+    pub fn push(&mut self, talker: u64, seq: u16, timestamp: u32, data: &[u8], now: Instant) {
+        let min_depth = self.config.min_depth;
+        let stream = self
+            .talkers
+            .entry(talker)
+            .or_insert_with(|| TalkerStream::new(now, min_depth));
+        stream.last_heard = now;
+
+        let ext = match stream.extend(seq) {
+            Some(ext) if (ext as i64 - stream.highest.unwrap() as i64).abs() > MAX_SEQ_JUMP => {
+                stream.restart(now);
+                1 << 32 | seq as u64
+            }
+            Some(ext) => ext,
+            // Start far from zero so sequences just below the first one
+            // still extend without underflowing.
+            None => 1 << 32 | seq as u64,
+        };
+
+        if stream.played.is_some_and(|played| ext <= played) {
+            stream.stats.late += 1;
+            return;
+        }
+        if stream.frames.contains_key(&ext) {
+            stream.stats.duplicates += 1;
+            return;
+        }
+
+        if stream.frames.is_empty() && !stream.playing {
+            stream.buffering_since = now;
+        }
+        stream.frames.insert(ext, (timestamp, data.to_vec()));
+        stream.highest = Some(stream.highest.map_or(ext, |highest| highest.max(ext)));
+        stream.stats.received += 1;
+
+        self.update_jitter(talker, timestamp, now);
+
+        let stream = self.talkers.get_mut(&talker).unwrap();
+        while stream.frames.len() > self.config.max_depth + DEPTH_SLACK {
+            stream.drop_oldest();
+        }
+    }
+
+    /// RFC 3550 interarrival jitter, converted to a target depth in frames.
+    fn update_jitter(&mut self, talker: u64, timestamp: u32, now: Instant) {
+        let config = &self.config;
+        let stream = self.talkers.get_mut(&talker).unwrap();
+        if let Some((arrived, last_timestamp)) = stream.last_arrival {
+            let arrival_ms = now.saturating_duration_since(arrived).as_secs_f64() * 1000.0;
+            let media_ms = timestamp.wrapping_sub(last_timestamp) as i32 as f64 * 1000.0
+                / config.clock_rate as f64;
+            let deviation = (arrival_ms - media_ms).abs();
+            stream.stats.jitter_ms += (deviation - stream.stats.jitter_ms) / 16.0;
+        }
+        stream.last_arrival = Some((now, timestamp));
+
+        let depth = (2.0 * stream.stats.jitter_ms / config.frame_ms as f64).ceil() as usize;
+        stream.stats.target_depth =
+            (depth + config.min_depth).clamp(config.min_depth, config.max_depth);
+    }
+
+    /// Advances every talker by one frame. Call once per `frame_ms`.
+    pub fn tick(&mut self, now: Instant, out: &mut impl Playout) {
+        let config = &self.config;
+        self.talkers.retain(|_, stream| {
+            stream.playing
+                || !stream.frames.is_empty()
+                || now.saturating_duration_since(stream.last_heard)
+                    < Duration::from_millis(IDLE_TIMEOUT_MS)
+        });
+
+        for (talker, stream) in self.talkers.iter_mut() {
+            let target = stream.stats.target_depth;
+            if !stream.playing {
+                let Some(&first) = stream.frames.keys().next() else {
+                    continue;
+                };
+                let buffered_for = now.saturating_duration_since(stream.buffering_since);
+                if stream.frames.len() < target
+                    && buffered_for
+                        < Duration::from_millis((target as u64) * config.frame_ms as u64)
+                {
+                    continue;
+                }
+                stream.playing = true;
+                stream.played = Some(first - 1);
+            }
+
+            while stream.frames.len() > target + DEPTH_SLACK {
+                stream.drop_oldest();
+            }
+            let next = stream.played.unwrap() + 1;
+
+            match stream.frames.remove(&next) {
+                Some((timestamp, data)) => {
+                    out.play(*talker, next as u16, timestamp, &data);
+                    stream.played = Some(next);
+                    stream.stats.played += 1;
+                    stream.underruns = 0;
+                }
+                None if !stream.frames.is_empty() => {
+                    out.conceal(*talker, next as u16);
+                    stream.played = Some(next);
+                    stream.stats.concealed += 1;
+                    stream.stats.lost += 1;
+                    stream.underruns = 0;
+                }
+                // An empty buffer is either a late frame or a silent talker;
+                // conceal a few frames without giving up the slot.
+                None if stream.underruns < config.max_conceal => {
+                    out.conceal(*talker, next as u16);
+                    stream.stats.concealed += 1;
+                    stream.underruns += 1;
+                }
+                // Jitter is measured within a talk spurt, not across pauses.
+                None => {
+                    stream.playing = false;
+                    stream.underruns = 0;
+                    stream.last_arrival = None;
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self, talker: u64) -> Option<JitterStats> {
+        self.talkers.get(&talker).map(|stream| stream.stats.clone())
+    }
+
+    pub fn remove(&mut self, talker: u64) {
+        self.talkers.remove(&talker);
+    }
+
+    pub fn talkers(&self) -> impl Iterator<Item = u64> + '_ {
+        self.talkers.keys().copied()
+    }
+}
@@ -0,0 +1,8 @@
+// src/client/mod.rs
+mod jitter;
+mod model;
+mod net;
+mod session;
+
+pub use jitter::{JitterBuffer, JitterConfig, JitterStats, Playout};
+pub use model::{Client, SessionEvent, VoiceSession};
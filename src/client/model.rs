@@ -0,0 +1,145 @@
+// src/client/model.rs
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, AtomicU32};
+
+use tokio::net::UdpSocket;
+use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::protocol::{
+    CodecPolicy, CryptoSession, DisconnectReason, ErrorCode, FecDecoder, FecEncoder, Reassembler,
+    ReliableReceiver, RoomChange,
+};
+
+pub const ALIVE_INTERVAL_MS: u64 = 1000;
+pub const HANDSHAKE_TIMEOUT_MS: u64 = 1000;
+pub const HANDSHAKE_RETRIES: u32 = 3;
+pub const EVENT_CHANNEL_SIZE: usize = 256;
+pub const MTU: usize = 1200;
+
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Joined {
+        room_id: u16,
+        codec: CodecPolicy,
+        users: Vec<(u64, String)>,
+    },
+    UserJoined {
+        room_id: u16,
+        user_id: u64,
+        name: String,
+    },
+    UserLeft {
+        room_id: u16,
+        user_id: u64,
+        name: String,
+    },
+    UserConnectionLost {
+        room_id: u16,
+        user_id: u64,
+        name: String,
+    },
+    UserReconnected {
+        room_id: u16,
+        user_id: u64,
+        name: String,
+    },
+    Snapshot {
+        rooms: Vec<(u16, Vec<(u64, String)>)>,
+    },
+    Audio {
+        talker: u64,
+        seq: u16,
+        timestamp: u32,
+        payload_type: u8,
+        data: Vec<u8>,
+    },
+    /// A pending `switch` is waiting for a spot in `room_id`.
+    Queued { room_id: u16, position: u16 },
+    /// The server refused a request that nobody is waiting on, such as a
+    /// voice frame; `request_id` is then the frame's sequence number.
+    Error {
+        code: ErrorCode,
+        request_type: u32,
+        request_id: u32,
+        detail: String,
+    },
+    /// A room was created, renamed or deleted. If it was our room, the
+    /// server has already moved us and a `Joined` follows.
+    RoomChanged {
+        change: RoomChange,
+        room_id: u16,
+        name: String,
+    },
+    /// `reason` is missing when the connection itself failed.
+    Disconnected {
+        reason: Option<DisconnectReason>,
+        detail: String,
+    },
+}
+
+pub(crate) struct Transport {
+    pub(crate) socket: UdpSocket,
+    pub(crate) crypto: Option<CryptoSession>,
+    pub(crate) next_frag_id: AtomicU32,
+    pub(crate) fragments: std::sync::Mutex<Reassembler<u32>>,
+}
+
+pub struct Client {
+    pub(crate) transport: Transport,
+    pub(crate) server_addr: SocketAddr,
+    pub(crate) capabilities: u32,
+    /// The server identity to insist on during `secure`.
+    pub(crate) server_key: Option<[u8; 32]>,
+}
+
+pub(crate) type RoomState = (u16, u64, Vec<(u64, String)>);
+
+#[derive(Default)]
+pub(crate) struct RoomStream {
+    pub(crate) last_seq: u64,
+    pub(crate) pending: BTreeMap<u64, SessionEvent>,
+}
+
+pub(crate) struct SnapshotAssembly {
+    pub(crate) id: u32,
+    pub(crate) parts: u16,
+    pub(crate) received: BTreeMap<u16, Vec<RoomState>>,
+}
+
+pub(crate) struct SessionState {
+    pub(crate) transport: Transport,
+    pub(crate) user_id: u64,
+    pub(crate) token: u64,
+    pub(crate) version: u16,
+    pub(crate) capabilities: u32,
+    pub(crate) room_id: AtomicU16,
+    pub(crate) talk_seq: AtomicU16,
+    pub(crate) next_request_id: AtomicU32,
+    pub(crate) pending_switches: std::sync::Mutex<HashMap<u32, PendingSwitch>>,
+    /// Present when FEC was negotiated.
+    pub(crate) fec_encoder: Option<std::sync::Mutex<FecEncoder>>,
+    /// Per talker: the last payload type heard and the FEC decoder.
+    pub(crate) fec_decoders: std::sync::Mutex<HashMap<u64, (u8, FecDecoder)>>,
+    pub(crate) streams: std::sync::Mutex<BTreeMap<u16, RoomStream>>,
+    pub(crate) requested_rooms: std::sync::Mutex<Option<(Vec<u16>, u32)>>,
+    pub(crate) reliable: std::sync::Mutex<ReliableReceiver>,
+    pub(crate) snapshot: Mutex<Option<SnapshotAssembly>>,
+    pub(crate) events_tx: mpsc::Sender<SessionEvent>,
+}
+
+/// A SWITCH waiting for SWITCHED or an ERROR.
+pub(crate) struct PendingSwitch {
+    pub(crate) reply: oneshot::Sender<anyhow::Result<()>>,
+    /// Set once the server put us in the room's queue; from then on the
+    /// request is no longer retried or timed out.
+    pub(crate) queued: bool,
+}
+
+pub struct VoiceSession {
+    pub(crate) state: Arc<SessionState>,
+    pub(crate) events_rx: mpsc::Receiver<SessionEvent>,
+    pub(crate) tasks: Vec<JoinHandle<()>>,
+}
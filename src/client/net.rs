@@ -0,0 +1,344 @@
+// src/client/net.rs
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU16, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::net::UdpSocket;
+use tokio::sync::{Mutex, mpsc};
+
+use crate::client::model::{
+    Client, EVENT_CHANNEL_SIZE, HANDSHAKE_RETRIES, HANDSHAKE_TIMEOUT_MS, MTU, SessionState,
+    Transport, VoiceSession,
+};
+use crate::protocol::{
+    self, DisconnectReason, ErrorCode, FecEncoder, KeyExchange, PacketType, Reassembler,
+    ReliableReceiver,
+};
+
+impl Transport {
+    pub(crate) async fn send(&self, pkt: &[u8]) -> std::io::Result<()> {
+        if pkt.len() <= MTU {
+            return self.send_datagram(pkt).await;
+        }
+        let frag_id = self.next_frag_id.fetch_add(1, Ordering::Relaxed);
+        let fragments = protocol::fragment(pkt, frag_id, MTU).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "packet too large to send")
+        })?;
+        for fragment in fragments {
+            self.send_datagram(&fragment).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_datagram(&self, pkt: &[u8]) -> std::io::Result<()> {
+        match &self.crypto {
+            Some(crypto) => self.socket.send(&crypto.seal(pkt)).await?,
+            None => self.socket.send(pkt).await?,
+        };
+        Ok(())
+    }
+
+    pub(crate) async fn recv<'b>(
+        &self,
+        buf: &'b mut [u8],
+    ) -> std::io::Result<Option<Cow<'b, [u8]>>> {
+        let n = self.socket.recv(buf).await?;
+        let data = &buf[..n];
+        let data = match &self.crypto {
+            Some(crypto) => match crypto.open(data) {
+                Ok(plaintext) => Cow::Owned(plaintext),
+                Err(_) => return Ok(None),
+            },
+            None => Cow::Borrowed(data),
+        };
+
+        let Ok(PacketType::Fragment {
+            frag_id,
+            index,
+            count,
+            chunk,
+        }) = protocol::parse_from_server_packet(&data)
+        else {
+            return Ok(Some(data));
+        };
+        Ok(self
+            .fragments
+            .lock()
+            .unwrap()
+            .push(frag_id, index, count, &chunk, Instant::now())
+            .map(Cow::Owned))
+    }
+}
+
+impl Client {
+    pub async fn connect(server_addr: String) -> anyhow::Result<Self> {
+        let server_addr: SocketAddr = tokio::net::lookup_host(&server_addr)
+            .await?
+            .next()
+            .ok_or_else(|| anyhow::format_err!("could not resolve `{server_addr}`"))?;
+        let bind_addr = if server_addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(server_addr).await?;
+
+        Ok(Self {
+            transport: Transport {
+                socket,
+                crypto: None,
+                next_frag_id: AtomicU32::new(1),
+                fragments: std::sync::Mutex::new(Reassembler::new()),
+            },
+            server_addr,
+            capabilities: 0,
+            server_key: None,
+        })
+    }
+
+    pub fn with_capabilities(mut self, capabilities: u32) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Pins the server's identity key, so `secure` fails instead of talking
+    /// to anyone else.
+    pub fn with_server_key(mut self, server_key: [u8; 32]) -> Self {
+        self.server_key = Some(server_key);
+        self
+    }
+
+    pub async fn secure(mut self) -> anyhow::Result<Self> {
+        enum HandshakeReply {
+            Handshaked(u64, [u8; 32], [u8; 32]),
+            Cookie([u8; protocol::COOKIE_LEN]),
+        }
+
+        let kx = KeyExchange::new();
+        let mut cookie = None;
+
+        // One extra attempt covers the cookie round trip.
+        for _ in 0..=HANDSHAKE_RETRIES {
+            let handshake = protocol::new_handshake(kx.public_key(), cookie);
+            self.transport.send(&handshake).await?;
+            let result = self
+                .recv_until(|pkt| match pkt {
+                    PacketType::Handshaked {
+                        session_id,
+                        public_key,
+                        identity,
+                    } => Some(HandshakeReply::Handshaked(session_id, public_key, identity)),
+                    PacketType::Cookie { cookie } => Some(HandshakeReply::Cookie(cookie)),
+                    _ => None,
+                })
+                .await;
+            match result {
+                Ok(HandshakeReply::Handshaked(session_id, server_public, identity)) => {
+                    if self.server_key.is_some_and(|key| key != identity) {
+                        anyhow::bail!("server identity does not match the pinned key");
+                    }
+                    self.transport.crypto =
+                        Some(kx.finish_client(server_public, identity, session_id));
+                    return Ok(self);
+                }
+                Ok(HandshakeReply::Cookie(echo)) => cookie = Some(echo),
+                Err(_) => {}
+            }
+        }
+        anyhow::bail!("handshake timed out")
+    }
+
+    pub fn is_secure(&self) -> bool {
+        self.transport.crypto.is_some()
+    }
+
+    pub fn server_addr(&self) -> SocketAddr {
+        self.server_addr
+    }
+
+    pub async fn ping(&self) -> anyhow::Result<Duration> {
+        let started = Instant::now();
+        self.transport.send(&protocol::new_ping()).await?;
+        self.recv_until(|pkt| matches!(pkt, PacketType::Pong).then_some(()))
+            .await?;
+        Ok(started.elapsed())
+    }
+
+    pub async fn rooms(&self) -> anyhow::Result<Vec<(u16, String)>> {
+        let mut rooms = Vec::new();
+        let mut offset = 1;
+        loop {
+            self.transport.send(&protocol::new_rooms(offset)).await?;
+            let (remaining, list) = self
+                .recv_until(|pkt| match pkt {
+                    PacketType::RoomsList { remaining, list } => Some((
+                        remaining,
+                        list.into_iter()
+                            .map(|(id, name)| (id, name.into_owned()))
+                            .collect::<Vec<_>>(),
+                    )),
+                    _ => None,
+                })
+                .await?;
+            rooms.extend(list);
+            match rooms.last() {
+                // Pages continue after the last id seen, as ids can have gaps.
+                Some((last, _)) if remaining && *last < u16::MAX => offset = last + 1,
+                _ => return Ok(rooms),
+            }
+        }
+    }
+
+    pub async fn join(self, name: &str, hwid: &str, room_id: u16) -> anyhow::Result<VoiceSession> {
+        self.join_with_credential(name, hwid, room_id, "").await
+    }
+
+    /// Joins a room protected by a password or invite code.
+    pub async fn join_with_credential(
+        self,
+        name: &str,
+        hwid: &str,
+        room_id: u16,
+        credential: &str,
+    ) -> anyhow::Result<VoiceSession> {
+        struct Accepted {
+            user_id: u64,
+            version: u16,
+            capabilities: u32,
+            token: u64,
+        }
+
+        enum JoinReply {
+            Accepted(Accepted),
+            Cookie([u8; protocol::COOKIE_LEN]),
+            Rejected(DisconnectReason, String),
+            Refused(ErrorCode, String),
+        }
+
+        let mut cookie = None;
+        let mut early_events = Vec::new();
+
+        // One extra attempt covers the cookie round trip.
+        let mut accepted = None;
+        for _ in 0..=HANDSHAKE_RETRIES {
+            let join = protocol::new_join(
+                name,
+                hwid,
+                room_id,
+                protocol::PROTOCOL_VERSION,
+                self.capabilities,
+                credential,
+                cookie,
+            );
+            self.transport.send(&join).await?;
+            let result = self
+                .recv_until(|pkt| match pkt {
+                    PacketType::Accepted {
+                        user_id,
+                        version,
+                        capabilities,
+                        token,
+                    } => Some(JoinReply::Accepted(Accepted {
+                        user_id,
+                        version,
+                        capabilities,
+                        token,
+                    })),
+                    PacketType::Cookie { cookie } => Some(JoinReply::Cookie(cookie)),
+                    PacketType::Disconnect { reason, detail } => {
+                        Some(JoinReply::Rejected(reason, detail.into_owned()))
+                    }
+                    PacketType::Error {
+                        code,
+                        request_type: protocol::JOIN,
+                        detail,
+                        ..
+                    } => Some(JoinReply::Refused(code, detail.into_owned())),
+                    other => {
+                        early_events.push(other.into_owned());
+                        None
+                    }
+                })
+                .await;
+            match result {
+                Ok(JoinReply::Accepted(reply)) => {
+                    accepted = Some(reply);
+                    break;
+                }
+                Ok(JoinReply::Cookie(echo)) => cookie = Some(echo),
+                Ok(JoinReply::Refused(code, detail)) => {
+                    return Err(anyhow::Error::new(code).context(format!("join refused: {detail}")));
+                }
+                Ok(JoinReply::Rejected(reason, detail)) => {
+                    return Err(
+                        anyhow::Error::new(reason).context(format!("join rejected: {detail}"))
+                    );
+                }
+                Err(_) => continue,
+            }
+        }
+        let Some(Accepted {
+            user_id,
+            version,
+            capabilities,
+            token,
+        }) = accepted
+        else {
+            anyhow::bail!("join timed out");
+        };
+
+        let (events_tx, events_rx) = mpsc::channel(EVENT_CHANNEL_SIZE);
+        let state = Arc::new(SessionState {
+            transport: self.transport,
+            user_id,
+            token,
+            version,
+            capabilities,
+            room_id: AtomicU16::new(room_id),
+            talk_seq: AtomicU16::new(0),
+            next_request_id: AtomicU32::new(1),
+            pending_switches: std::sync::Mutex::new(HashMap::new()),
+            fec_encoder: (capabilities & protocol::CAP_FEC != 0)
+                .then(|| std::sync::Mutex::new(FecEncoder::default())),
+            fec_decoders: std::sync::Mutex::new(HashMap::new()),
+            streams: std::sync::Mutex::new(BTreeMap::new()),
+            requested_rooms: std::sync::Mutex::new(None),
+            reliable: std::sync::Mutex::new(ReliableReceiver::new()),
+            snapshot: Mutex::new(None),
+            events_tx,
+        });
+
+        for pkt in early_events {
+            state.receive(pkt).await;
+        }
+
+        Ok(VoiceSession::start(state, events_rx))
+    }
+
+    async fn recv_until<T>(
+        &self,
+        mut accept: impl FnMut(PacketType) -> Option<T>,
+    ) -> anyhow::Result<T> {
+        let deadline = Instant::now() + Duration::from_millis(HANDSHAKE_TIMEOUT_MS);
+        let mut buf = [0u8; 1500];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let Some(data) =
+                tokio::time::timeout(remaining, self.transport.recv(&mut buf)).await??
+            else {
+                continue;
+            };
+            let Ok(pkt) = protocol::parse_from_server_packet(&data) else {
+                continue;
+            };
+            if let Some(value) = accept(pkt) {
+                return Ok(value);
+            }
+        }
+    }
+}
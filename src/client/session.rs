@@ -0,0 +1,622 @@
+// src/client/session.rs
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_core::Stream;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::client::model::{
+    ALIVE_INTERVAL_MS, HANDSHAKE_RETRIES, HANDSHAKE_TIMEOUT_MS, PendingSwitch, RoomState,
+    RoomStream, SessionEvent, SessionState, SnapshotAssembly, VoiceSession,
+};
+use crate::protocol::{self, EventKind, FecDecoder, PacketType, RoomChange};
+
+impl VoiceSession {
+    pub(crate) fn start(state: Arc<SessionState>, events_rx: mpsc::Receiver<SessionEvent>) -> Self {
+        let recv_task = {
+            let state = state.clone();
+            tokio::spawn(async move { state.recv_loop().await })
+        };
+        let alive_task = {
+            let state = state.clone();
+            tokio::spawn(async move { state.alive_loop().await })
+        };
+
+        Self {
+            state,
+            events_rx,
+            tasks: vec![recv_task, alive_task],
+        }
+    }
+
+    pub fn user_id(&self) -> u64 {
+        self.state.user_id
+    }
+
+    pub fn session_token(&self) -> u64 {
+        self.state.token
+    }
+
+    pub fn protocol_version(&self) -> u16 {
+        self.state.version
+    }
+
+    pub fn capabilities(&self) -> u32 {
+        self.state.capabilities
+    }
+
+    pub fn room_id(&self) -> u16 {
+        self.state.room_id.load(Ordering::Relaxed)
+    }
+
+    pub fn room_seq(&self, room_id: u16) -> Option<u64> {
+        self.state
+            .streams
+            .lock()
+            .unwrap()
+            .get(&room_id)
+            .map(|stream| stream.last_seq)
+    }
+
+    /// Sends one voice frame encoded as `payload_type`, which must match the
+    /// room's codec policy. `timestamp` is the media clock of the frame's
+    /// first sample; the sequence number is assigned here.
+    pub async fn talk(
+        &self,
+        payload_type: u8,
+        timestamp: u32,
+        audio_data: &[u8],
+    ) -> anyhow::Result<()> {
+        let seq = self.state.talk_seq.fetch_add(1, Ordering::Relaxed);
+        self.state
+            .transport
+            .send(&protocol::new_talk(
+                seq,
+                timestamp,
+                payload_type,
+                audio_data,
+            ))
+            .await?;
+
+        let parity = self
+            .state
+            .fec_encoder
+            .as_ref()
+            .and_then(|fec| fec.lock().unwrap().push(seq, timestamp, audio_data));
+        if let Some(parity) = parity {
+            self.state.transport.send(&parity).await?;
+        }
+        Ok(())
+    }
+
+    /// Moves to `room_id` and waits for the server to confirm. A refusal
+    /// leaves the session in its current room and fails with the
+    /// `ErrorCode` the server gave.
+    pub async fn switch(&self, room_id: u16) -> anyhow::Result<()> {
+        self.switch_with_credential(room_id, "").await
+    }
+
+    /// Like `switch`, for a room protected by a password or invite code.
+    pub async fn switch_with_credential(
+        &self,
+        room_id: u16,
+        credential: &str,
+    ) -> anyhow::Result<()> {
+        let request_id = self.state.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (reply, mut reply_rx) = oneshot::channel();
+        self.state.pending_switches.lock().unwrap().insert(
+            request_id,
+            PendingSwitch {
+                reply,
+                queued: false,
+            },
+        );
+
+        let pkt = protocol::new_switch(room_id, self.state.token, request_id, credential);
+        let mut reply = None;
+        for _ in 0..HANDSHAKE_RETRIES {
+            if let Err(e) = self.state.transport.send(&pkt).await {
+                reply = Some(Err(e.into()));
+                break;
+            }
+            let timeout = Duration::from_millis(HANDSHAKE_TIMEOUT_MS);
+            if let Ok(result) = tokio::time::timeout(timeout, &mut reply_rx).await {
+                reply = Some(result.unwrap_or_else(|_| Err(anyhow::anyhow!("session closed"))));
+                break;
+            }
+            let queued = self
+                .state
+                .pending_switches
+                .lock()
+                .unwrap()
+                .get(&request_id)
+                .is_some_and(|pending| pending.queued);
+            if queued {
+                let result = reply_rx.await;
+                reply = Some(result.unwrap_or_else(|_| Err(anyhow::anyhow!("session closed"))));
+                break;
+            }
+        }
+        self.state
+            .pending_switches
+            .lock()
+            .unwrap()
+            .remove(&request_id);
+        reply.unwrap_or_else(|| Err(anyhow::anyhow!("switch timed out")))
+    }
+
+    /// Sets the rooms whose events this session receives. The current room
+    /// is always included.
+    pub async fn subscribe(&self, rooms: &[u16]) -> anyhow::Result<()> {
+        let own_room = self.room_id();
+        self.state
+            .streams
+            .lock()
+            .unwrap()
+            .retain(|room_id, _| *room_id == own_room || rooms.contains(room_id));
+        *self.state.requested_rooms.lock().unwrap() = Some((rooms.to_vec(), 0));
+        self.state
+            .transport
+            .send(&protocol::new_subscribe(self.state.token, rooms))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn recv(&mut self) -> Option<SessionEvent> {
+        self.events_rx.recv().await
+    }
+
+    pub async fn leave(mut self) -> anyhow::Result<()> {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+        self.state
+            .transport
+            .send(&protocol::new_leave(self.state.token))
+            .await?;
+        Ok(())
+    }
+}
+
+impl Drop for VoiceSession {
+    fn drop(&mut self) {
+        for task in self.tasks.iter() {
+            task.abort();
+        }
+    }
+}
+
+impl Stream for VoiceSession {
+    type Item = SessionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events_rx.poll_recv(cx)
+    }
+}
+
+impl SessionState {
+    async fn recv_loop(&self) {
+        let mut buf = [0u8; 1500];
+        loop {
+            let data = match self.transport.recv(&mut buf).await {
+                Ok(Some(data)) => data,
+                Ok(None) => continue,
+                Err(e) => {
+                    let _ = self
+                        .events_tx
+                        .send(SessionEvent::Disconnected {
+                            reason: None,
+                            detail: e.to_string(),
+                        })
+                        .await;
+                    return;
+                }
+            };
+            let Ok(pkt) = protocol::parse_from_server_packet(&data) else {
+                continue;
+            };
+            if !self.receive(pkt).await {
+                return;
+            }
+        }
+    }
+
+    async fn alive_loop(&self) {
+        let mut interval = tokio::time::interval(Duration::from_millis(ALIVE_INTERVAL_MS));
+        loop {
+            interval.tick().await;
+            let (seqs, resubscribe) = {
+                let streams = self.streams.lock().unwrap();
+                let seqs: Vec<(u16, u64)> = streams
+                    .iter()
+                    .map(|(room_id, stream)| (*room_id, stream.last_seq))
+                    .collect();
+
+                let mut requested = self.requested_rooms.lock().unwrap();
+                let resubscribe = match requested.as_mut() {
+                    Some((rooms, attempts))
+                        if *attempts < HANDSHAKE_RETRIES
+                            && rooms.iter().any(|id| !streams.contains_key(id)) =>
+                    {
+                        *attempts += 1;
+                        Some(rooms.clone())
+                    }
+                    _ => None,
+                };
+                if resubscribe.is_none() {
+                    *requested = None;
+                }
+                (seqs, resubscribe)
+            };
+            let _ = self
+                .transport
+                .send(&protocol::new_alive(self.token, &seqs))
+                .await;
+            // SUBSCRIBE is unacknowledged; repeat it until every room's
+            // state has arrived.
+            if let Some(rooms) = resubscribe {
+                let _ = self
+                    .transport
+                    .send(&protocol::new_subscribe(self.token, &rooms))
+                    .await;
+            }
+        }
+    }
+
+    pub(crate) async fn receive(&self, pkt: PacketType<'_>) -> bool {
+        let PacketType::Reliable { seq, payload } = pkt else {
+            return self.dispatch(pkt).await;
+        };
+
+        let (fresh, (cumulative, sack)) = {
+            let mut reliable = self.reliable.lock().unwrap();
+            (reliable.accept(seq), reliable.ack())
+        };
+        let _ = self
+            .transport
+            .send(&protocol::new_ack(self.token, cumulative, sack))
+            .await;
+        if !fresh {
+            return true;
+        }
+
+        match protocol::parse_from_server_packet(&payload) {
+            Ok(inner) => self.dispatch(inner).await,
+            Err(_) => true,
+        }
+    }
+
+    async fn dispatch(&self, pkt: PacketType<'_>) -> bool {
+        match pkt {
+            PacketType::Event {
+                seq,
+                kind,
+                room_id,
+                user_id,
+                name,
+            } => {
+                let name = name.into_owned();
+                if kind == EventKind::Left {
+                    self.fec_decoders.lock().unwrap().remove(&user_id);
+                }
+                let event = match kind {
+                    EventKind::Joined => SessionEvent::UserJoined {
+                        room_id,
+                        user_id,
+                        name,
+                    },
+                    EventKind::Left => SessionEvent::UserLeft {
+                        room_id,
+                        user_id,
+                        name,
+                    },
+                    EventKind::ConnectionLost => SessionEvent::UserConnectionLost {
+                        room_id,
+                        user_id,
+                        name,
+                    },
+                    EventKind::Reconnected => SessionEvent::UserReconnected {
+                        room_id,
+                        user_id,
+                        name,
+                    },
+                };
+                self.deliver_ordered(room_id, seq, event).await;
+            }
+            PacketType::Joined {
+                room_id,
+                seq,
+                codec,
+                users,
+            } => {
+                let ready = self.reset_stream(room_id, seq);
+                let _ = self
+                    .events_tx
+                    .send(SessionEvent::Joined {
+                        room_id,
+                        codec,
+                        users: users
+                            .into_iter()
+                            .map(|(id, name)| (id, name.into_owned()))
+                            .collect(),
+                    })
+                    .await;
+                self.deliver(ready).await;
+            }
+            PacketType::Snapshot {
+                id,
+                part,
+                parts,
+                rooms,
+            } => {
+                let rooms = rooms
+                    .into_iter()
+                    .map(|(room_id, seq, users)| {
+                        (
+                            room_id,
+                            seq,
+                            users
+                                .into_iter()
+                                .map(|(id, name)| (id, name.into_owned()))
+                                .collect(),
+                        )
+                    })
+                    .collect();
+                self.collect_snapshot(id, part, parts, rooms).await;
+            }
+            PacketType::Talked {
+                talker,
+                seq,
+                timestamp,
+                payload_type,
+                audio_data,
+            } => {
+                let mut late = false;
+                self.recover_audio(talker, Some(payload_type), |fec| {
+                    late = fec.is_recovered(seq);
+                    fec.push_frame(seq, timestamp, &audio_data)
+                });
+                // Parity already stood in for this frame.
+                if !late {
+                    let _ = self.events_tx.try_send(SessionEvent::Audio {
+                        talker,
+                        seq,
+                        timestamp,
+                        payload_type,
+                        data: audio_data.into_owned(),
+                    });
+                }
+            }
+            PacketType::TalkedParity {
+                talker,
+                seq,
+                count,
+                timestamp,
+                parity,
+            } => {
+                self.recover_audio(talker, None, |fec| {
+                    fec.push_parity(seq, count, timestamp, &parity)
+                });
+            }
+            PacketType::Switched {
+                request_id,
+                room_id,
+            } => {
+                self.room_id.store(room_id, Ordering::Relaxed);
+                if let Some(pending) = self.pending_switches.lock().unwrap().remove(&request_id) {
+                    let _ = pending.reply.send(Ok(()));
+                }
+            }
+            PacketType::Queued {
+                request_id,
+                room_id,
+                position,
+            } => {
+                if let Some(pending) = self.pending_switches.lock().unwrap().get_mut(&request_id) {
+                    pending.queued = true;
+                }
+                let _ = self
+                    .events_tx
+                    .send(SessionEvent::Queued { room_id, position })
+                    .await;
+            }
+            PacketType::RoomChanged {
+                change,
+                room_id,
+                name,
+            } => {
+                if change == RoomChange::Deleted {
+                    self.streams.lock().unwrap().remove(&room_id);
+                    if let Some((rooms, _)) = self.requested_rooms.lock().unwrap().as_mut() {
+                        rooms.retain(|id| *id != room_id);
+                    }
+                }
+                let _ = self
+                    .events_tx
+                    .send(SessionEvent::RoomChanged {
+                        change,
+                        room_id,
+                        name: name.into_owned(),
+                    })
+                    .await;
+            }
+            PacketType::Error {
+                code,
+                request_type: protocol::SWITCH,
+                request_id,
+                detail,
+            } if self
+                .pending_switches
+                .lock()
+                .unwrap()
+                .contains_key(&request_id) =>
+            {
+                if let Some(pending) = self.pending_switches.lock().unwrap().remove(&request_id) {
+                    let error =
+                        anyhow::Error::new(code).context(format!("switch refused: {detail}"));
+                    let _ = pending.reply.send(Err(error));
+                }
+            }
+            PacketType::Error {
+                code,
+                request_type,
+                request_id,
+                detail,
+            } => {
+                let _ = self
+                    .events_tx
+                    .send(SessionEvent::Error {
+                        code,
+                        request_type,
+                        request_id,
+                        detail: detail.into_owned(),
+                    })
+                    .await;
+            }
+            PacketType::Disconnect { reason, detail } => {
+                let _ = self
+                    .events_tx
+                    .send(SessionEvent::Disconnected {
+                        reason: Some(reason),
+                        detail: detail.into_owned(),
+                    })
+                    .await;
+                return false;
+            }
+            _ => {}
+        }
+        true
+    }
+
+    /// Feeds a talker's FEC decoder and emits whatever frame it recovered.
+    /// Parity carries no payload type, so recovered frames reuse the last
+    /// one heard from that talker.
+    fn recover_audio(
+        &self,
+        talker: u64,
+        payload_type: Option<u8>,
+        feed: impl FnOnce(&mut FecDecoder) -> Option<(u16, u32, Vec<u8>)>,
+    ) {
+        if self.capabilities & protocol::CAP_FEC == 0 {
+            return;
+        }
+        let (payload_type, recovered) = {
+            let mut decoders = self.fec_decoders.lock().unwrap();
+            let (last_payload_type, decoder) = decoders.entry(talker).or_default();
+            if let Some(payload_type) = payload_type {
+                *last_payload_type = payload_type;
+            }
+            (*last_payload_type, feed(decoder))
+        };
+        if let Some((seq, timestamp, data)) = recovered {
+            let _ = self.events_tx.try_send(SessionEvent::Audio {
+                talker,
+                seq,
+                timestamp,
+                payload_type,
+                data,
+            });
+        }
+    }
+
+    async fn collect_snapshot(&self, id: u32, part: u16, parts: u16, rooms: Vec<RoomState>) {
+        let complete = {
+            let mut snapshot = self.snapshot.lock().await;
+            let assembly = match snapshot.as_mut() {
+                Some(assembly) if assembly.id == id && assembly.parts == parts => assembly,
+                Some(assembly) if assembly.id > id => return,
+                _ => snapshot.insert(SnapshotAssembly {
+                    id,
+                    parts,
+                    received: BTreeMap::new(),
+                }),
+            };
+            assembly.received.insert(part, rooms);
+            if assembly.received.len() < parts as usize {
+                return;
+            }
+            snapshot.take().unwrap()
+        };
+
+        let mut merged: BTreeMap<u16, (u64, Vec<(u64, String)>)> = BTreeMap::new();
+        for (room_id, seq, users) in complete.received.into_values().flatten() {
+            let entry = merged.entry(room_id).or_default();
+            entry.0 = seq;
+            entry.1.extend(users);
+        }
+        if let Some((room_id, _)) = merged
+            .iter()
+            .find(|(_, (_, users))| users.iter().any(|(id, _)| *id == self.user_id))
+        {
+            self.room_id.store(*room_id, Ordering::Relaxed);
+        }
+
+        let mut ready = Vec::new();
+        for (room_id, (seq, _)) in merged.iter() {
+            ready.extend(self.reset_stream(*room_id, *seq));
+        }
+        let _ = self
+            .events_tx
+            .send(SessionEvent::Snapshot {
+                rooms: merged
+                    .into_iter()
+                    .map(|(room_id, (_, users))| (room_id, users))
+                    .collect(),
+            })
+            .await;
+        self.deliver(ready).await;
+    }
+
+    /// Starts (or fast-forwards) a room stream at `seq` and returns the
+    /// buffered events that became deliverable.
+    fn reset_stream(&self, room_id: u16, seq: u64) -> Vec<SessionEvent> {
+        let mut streams = self.streams.lock().unwrap();
+        let stream = streams.entry(room_id).or_default();
+        stream.last_seq = stream.last_seq.max(seq);
+        stream.pending = stream.pending.split_off(&(stream.last_seq + 1));
+        self.drain_stream(stream)
+    }
+
+    async fn deliver_ordered(&self, room_id: u16, seq: u64, event: SessionEvent) {
+        let ready = {
+            let mut streams = self.streams.lock().unwrap();
+            // Events for rooms we have no state for yet are covered by the
+            // JOINED that starts the stream.
+            let Some(stream) = streams.get_mut(&room_id) else {
+                return;
+            };
+            if seq <= stream.last_seq {
+                return;
+            }
+            stream.pending.insert(seq, event);
+            self.drain_stream(stream)
+        };
+        self.deliver(ready).await;
+    }
+
+    fn drain_stream(&self, stream: &mut RoomStream) -> Vec<SessionEvent> {
+        let mut ready = Vec::new();
+        while let Some(event) = stream.pending.remove(&(stream.last_seq + 1)) {
+            if let SessionEvent::UserJoined {
+                room_id, user_id, ..
+            } = &event
+                && *user_id == self.user_id
+            {
+                self.room_id.store(*room_id, Ordering::Relaxed);
+            }
+            ready.push(event);
+            stream.last_seq += 1;
+        }
+        ready
+    }
+
+    async fn deliver(&self, events: Vec<SessionEvent>) {
+        for event in events {
+            let _ = self.events_tx.send(event).await;
+        }
+    }
+}
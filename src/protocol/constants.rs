@@ -1,6 +1,18 @@
 // src/protocol/constants.rs
 pub const MAGIC: [u8; 4] = [0xde, 0xad, 0xc0, 0xde];
 
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+pub const CAP_ENCRYPTION: u32 = 1 << 0;
+pub const CAP_FEC: u32 = 1 << 1;
+
+pub const PAYLOAD_OPUS: u8 = 1;
+pub const PAYLOAD_PCM16: u8 = 2;
+
+pub const COOKIE_LEN: usize = 24;
+pub const SNAPSHOT_PART_LEN: usize = 1200;
+
 pub const PING: u32 = 1;
 pub const PONG: u32 = 2;
 pub const JOIN: u32 = 3;
@@ -16,3 +28,20 @@ pub const SWITCH: u32 = 12;
 pub const LEAVE: u32 = 13;
 pub const DISCONNECT: u32 = 14;
 pub const ACCEPTED: u32 = 15;
+pub const HANDSHAKE: u32 = 16;
+pub const HANDSHAKED: u32 = 17;
+pub const SEALED: u32 = 18;
+pub const COOKIE: u32 = 19;
+pub const RELIABLE: u32 = 20;
+pub const ACK: u32 = 21;
+pub const SNAPSHOT: u32 = 22;
+pub const SUBSCRIBE: u32 = 23;
+pub const FRAGMENT: u32 = 24;
+pub const PARITY: u32 = 25;
+pub const ERROR: u32 = 26;
+pub const SWITCHED: u32 = 27;
+pub const QUEUED: u32 = 28;
+pub const ROOM_CHANGED: u32 = 29;
+
+pub const TALKED_AUDIO: u8 = 0;
+pub const TALKED_PARITY: u8 = 1;
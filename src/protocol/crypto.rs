@@ -0,0 +1,266 @@
+// src/protocol/crypto.rs
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand_core::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, ReusableSecret, StaticSecret};
+
+use crate::protocol::constants::*;
+use crate::protocol::decode::Decode;
+use crate::protocol::error::DecodeError;
+use crate::protocol::packet::PacketType;
+
+pub const SEALED_HEADER_LEN: usize = 24;
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// The server's long-term key pair. Every session key depends on it, so a
+/// client that knows the public half in advance cannot be fooled by a man
+/// in the middle.
+pub struct Identity {
+    secret: StaticSecret,
+    public: PublicKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self::from_secret(StaticSecret::random_from_rng(OsRng).to_bytes())
+    }
+
+    pub fn from_secret(secret: [u8; 32]) -> Self {
+        let secret = StaticSecret::from(secret);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn secret(&self) -> [u8; 32] {
+        self.secret.to_bytes()
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+}
+
+pub struct KeyExchange {
+    secret: ReusableSecret,
+    public: PublicKey,
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = ReusableSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+
+    pub fn public_key(&self) -> [u8; 32] {
+        self.public.to_bytes()
+    }
+
+    /// `server_identity` is the public key of the server's `Identity`.
+    pub fn finish_client(
+        self,
+        server_public: [u8; 32],
+        server_identity: [u8; 32],
+        session_id: u64,
+    ) -> CryptoSession {
+        let ephemeral = self.secret.diffie_hellman(&PublicKey::from(server_public));
+        let identity = self
+            .secret
+            .diffie_hellman(&PublicKey::from(server_identity));
+        derive(
+            Role::Client,
+            [self.public.to_bytes(), server_public, server_identity],
+            [ephemeral.to_bytes(), identity.to_bytes()],
+            session_id,
+        )
+    }
+
+    pub fn finish_server(
+        self,
+        client_public: [u8; 32],
+        identity: &Identity,
+        session_id: u64,
+    ) -> CryptoSession {
+        let client = PublicKey::from(client_public);
+        let ephemeral = self.secret.diffie_hellman(&client);
+        let static_shared = identity.secret.diffie_hellman(&client);
+        derive(
+            Role::Server,
+            [client_public, self.public.to_bytes(), identity.public_key()],
+            [ephemeral.to_bytes(), static_shared.to_bytes()],
+            session_id,
+        )
+    }
+}
+
+/// `publics` are the client's and server's ephemeral keys and the server's
+/// identity; `shared` the ephemeral and identity Diffie-Hellman results.
+fn derive(
+    role: Role,
+    publics: [[u8; 32]; 3],
+    shared: [[u8; 32]; 2],
+    session_id: u64,
+) -> CryptoSession {
+    let salt = publics.concat();
+    let hkdf = Hkdf::<Sha256>::new(Some(&salt), &shared.concat());
+    let mut c2s = [0u8; 32];
+    let mut s2c = [0u8; 32];
+    hkdf.expand(b"pigeonvc c2s", &mut c2s)
+        .expect("32 bytes is a valid hkdf output length");
+    hkdf.expand(b"pigeonvc s2c", &mut s2c)
+        .expect("32 bytes is a valid hkdf output length");
+
+    let (seal_key, open_key) = match role {
+        Role::Client => (c2s, s2c),
+        Role::Server => (s2c, c2s),
+    };
+
+    CryptoSession {
+        id: session_id,
+        seal_cipher: ChaCha20Poly1305::new(Key::from_slice(&seal_key)),
+        open_cipher: ChaCha20Poly1305::new(Key::from_slice(&open_key)),
+        next_counter: AtomicU64::new(0),
+        replay: Mutex::new(ReplayWindow::default()),
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct CryptoSession {
+    id: u64,
+    seal_cipher: ChaCha20Poly1305,
+    open_cipher: ChaCha20Poly1305,
+    next_counter: AtomicU64,
+    replay: Mutex<ReplayWindow>,
+}
+
+impl CryptoSession {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let counter = self.next_counter.fetch_add(1, Ordering::Relaxed);
+
+        let mut packet = Vec::with_capacity(SEALED_HEADER_LEN + plaintext.len() + 16);
+        packet.extend_from_slice(&MAGIC);
+        packet.extend_from_slice(&SEALED.to_be_bytes());
+        packet.extend_from_slice(&self.id.to_be_bytes());
+        packet.extend_from_slice(&counter.to_be_bytes());
+
+        let ciphertext = self
+            .seal_cipher
+            .encrypt(
+                &nonce(counter),
+                Payload {
+                    msg: plaintext,
+                    aad: &packet,
+                },
+            )
+            .expect("chacha20poly1305 encryption is infallible for in-memory buffers");
+        packet.extend_from_slice(&ciphertext);
+        packet
+    }
+
+    pub fn open(&self, packet: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        match PacketType::decode(packet)? {
+            PacketType::Sealed {
+                session_id,
+                counter,
+                ciphertext,
+            } => self.open_parsed(packet, session_id, counter, &ciphertext),
+            other => Err(DecodeError::UnknownType(other.code())),
+        }
+    }
+
+    /// Like `open`, for a SEALED `packet` the caller already parsed.
+    pub fn open_parsed(
+        &self,
+        packet: &[u8],
+        session_id: u64,
+        counter: u64,
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, DecodeError> {
+        if session_id != self.id {
+            return Err(DecodeError::Unauthenticated);
+        }
+
+        let plaintext = self
+            .open_cipher
+            .decrypt(
+                &nonce(counter),
+                Payload {
+                    msg: ciphertext,
+                    aad: packet
+                        .get(..SEALED_HEADER_LEN)
+                        .ok_or(DecodeError::Unauthenticated)?,
+                },
+            )
+            .map_err(|_| DecodeError::Unauthenticated)?;
+
+        if !self.replay.lock().unwrap().accept(counter) {
+            return Err(DecodeError::Replayed);
+        }
+        Ok(plaintext)
+    }
+}
+
+fn nonce(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *Nonce::from_slice(&nonce)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ReplayWindow {
+    highest: Option<u64>,
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    pub fn accept(&mut self, counter: u64) -> bool {
+        let Some(highest) = self.highest else {
+            self.highest = Some(counter);
+            self.bitmap = 1;
+            return true;
+        };
+
+        if counter > highest {
+            let shift = counter - highest;
+            self.bitmap = if shift >= REPLAY_WINDOW_SIZE {
+                0
+            } else {
+                self.bitmap << shift
+            };
+            self.bitmap |= 1;
+            self.highest = Some(counter);
+            return true;
+        }
+
+        let offset = highest - counter;
+        if offset >= REPLAY_WINDOW_SIZE {
+            return false;
+        }
+        let bit = 1u64 << offset;
+        if self.bitmap & bit != 0 {
+            return false;
+        }
+        self.bitmap |= bit;
+        true
+    }
+}
@@ -1,109 +1,495 @@
 // src/protocol/decode.rs
-use anyhow::{self, Result};
+use std::borrow::Cow;
 
 use crate::protocol::constants::*;
-use crate::protocol::packet::PacketType;
+use crate::protocol::error::DecodeError;
+use crate::protocol::packet::{
+    CodecPolicy, DisconnectReason, ErrorCode, EventKind, PacketType, RoomChange, is_client_packet,
+    is_server_packet,
+};
 
-pub fn parse_from_client_packet(buf: &[u8]) -> Result<PacketType> {
-    if buf.len() < 8 {
-        return Err(anyhow::format_err!("invalid packet"));
-    }
+pub trait Decode<'a>: Sized {
+    fn decode(buf: &'a [u8]) -> Result<Self, DecodeError>;
+}
 
-    let magic = &buf[..4];
-    if magic != MAGIC {
-        return Err(anyhow::format_err!("invalid packet"));
-    }
+impl<'a> Decode<'a> for PacketType<'a> {
+    fn decode(buf: &'a [u8]) -> Result<Self, DecodeError> {
+        let (packet_type, rest) = take_header(buf)?;
 
-    let packet_type = u32::from_be_bytes(buf[4..8].try_into()?);
-    let rest = &buf[8..];
+        match packet_type {
+            PING => {
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Ping)
+            }
+            JOIN => {
+                let (name, rest) = take_cstring(rest)?;
+                let (hwid, rest) = take_cstring(rest)?;
+                let (room_id, rest) = take_u16(packet_type, rest)?;
 
-    match packet_type {
-        PING if rest.len() == 0 => Ok(PacketType::Ping),
-        JOIN => {
-            let (name, rest) = take_cstring(rest)?;
-            let (hwid, rest) = take_cstring(rest)?;
+                // Clients predating version negotiation end the packet here.
+                let (version, capabilities, credential, cookie) = if rest.is_empty() {
+                    (0, 0, "", None)
+                } else {
+                    let (version, rest) = take_u16(packet_type, rest)?;
+                    let (capabilities, rest) = take_u32(packet_type, rest)?;
+                    let (credential, rest) = take_cstring(rest)?;
+                    let cookie = if rest.is_empty() {
+                        None
+                    } else {
+                        let (cookie, rest) = take_array(packet_type, rest)?;
+                        expect_empty(packet_type, rest)?;
+                        Some(cookie)
+                    };
+                    (version, capabilities, credential, cookie)
+                };
 
-            Ok(PacketType::Join {
-                name: name.to_string(),
-                hwid: hwid.to_string(),
-                room_id: u16::from_be_bytes(rest[..2].try_into()?),
-            })
+                Ok(PacketType::Join {
+                    name: name.into(),
+                    hwid: hwid.into(),
+                    room_id,
+                    version,
+                    capabilities,
+                    credential: credential.into(),
+                    cookie,
+                })
+            }
+            TALK => {
+                let (seq, rest) = take_u16(packet_type, rest)?;
+                let (timestamp, rest) = take_u32(packet_type, rest)?;
+                let (payload_type, rest) = take_u8(packet_type, rest)?;
+                Ok(PacketType::Talk {
+                    seq,
+                    timestamp,
+                    payload_type,
+                    audio_data: Cow::Borrowed(rest),
+                })
+            }
+            ROOMS => {
+                let (offset, rest) = take_u16(packet_type, rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Rooms { offset })
+            }
+            SWITCH => {
+                let (room_id, rest) = take_u16(packet_type, rest)?;
+                let (token, rest) = take_u64(packet_type, rest)?;
+                let (request_id, rest) = take_u32(packet_type, rest)?;
+                let (credential, rest) = take_cstring(rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Switch {
+                    room_id,
+                    token,
+                    request_id,
+                    credential: credential.into(),
+                })
+            }
+            ALIVE => {
+                let (token, mut rest) = take_u64(packet_type, rest)?;
+                let mut seqs = vec![];
+                while !rest.is_empty() {
+                    let (room_id, tail) = take_u16(packet_type, rest)?;
+                    let (seq, tail) = take_u64(packet_type, tail)?;
+                    seqs.push((room_id, seq));
+                    rest = tail;
+                }
+                Ok(PacketType::Alive { token, seqs })
+            }
+            SUBSCRIBE => {
+                let (token, mut rest) = take_u64(packet_type, rest)?;
+                let mut rooms = vec![];
+                while !rest.is_empty() {
+                    let (room_id, tail) = take_u16(packet_type, rest)?;
+                    rooms.push(room_id);
+                    rest = tail;
+                }
+                Ok(PacketType::Subscribe { token, rooms })
+            }
+            LEAVE => {
+                let (token, rest) = take_u64(packet_type, rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Leave { token })
+            }
+
+            PONG => {
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Pong)
+            }
+            ROOMSLIST => {
+                let (remaining, mut rest) = take_u8(packet_type, rest)?;
+                let mut list = vec![];
+                while !rest.is_empty() {
+                    let (room_id, tail) = take_u16(packet_type, rest)?;
+                    let (name, tail) = take_cstring(tail)?;
+                    list.push((room_id, name.into()));
+                    rest = tail;
+                }
+                Ok(PacketType::RoomsList {
+                    remaining: remaining != 0,
+                    list,
+                })
+            }
+            JOINED => {
+                let (room_id, rest) = take_u16(packet_type, rest)?;
+                let (seq, rest) = take_u64(packet_type, rest)?;
+                let (payload_type, rest) = take_u8(packet_type, rest)?;
+                let (sample_rate, rest) = take_u32(packet_type, rest)?;
+                let (channels, rest) = take_u8(packet_type, rest)?;
+                let (max_frame_len, mut rest) = take_u16(packet_type, rest)?;
+                let codec = CodecPolicy {
+                    payload_type,
+                    sample_rate,
+                    channels,
+                    max_frame_len,
+                };
+                let mut users = vec![];
+                while !rest.is_empty() {
+                    let (user_id, tail) = take_u64(packet_type, rest)?;
+                    let (name, tail) = take_cstring(tail)?;
+                    users.push((user_id, name.into()));
+                    rest = tail;
+                }
+                Ok(PacketType::Joined {
+                    room_id,
+                    seq,
+                    codec,
+                    users,
+                })
+            }
+            TALKED => {
+                let (kind, rest) = take_u8(packet_type, rest)?;
+                let (talker, rest) = take_u64(packet_type, rest)?;
+                let (seq, rest) = take_u16(packet_type, rest)?;
+                match kind {
+                    TALKED_AUDIO => {
+                        let (timestamp, rest) = take_u32(packet_type, rest)?;
+                        let (payload_type, rest) = take_u8(packet_type, rest)?;
+                        Ok(PacketType::Talked {
+                            talker,
+                            seq,
+                            timestamp,
+                            payload_type,
+                            audio_data: Cow::Borrowed(rest),
+                        })
+                    }
+                    TALKED_PARITY => {
+                        let (count, rest) = take_u8(packet_type, rest)?;
+                        let (timestamp, rest) = take_u32(packet_type, rest)?;
+                        Ok(PacketType::TalkedParity {
+                            talker,
+                            seq,
+                            count,
+                            timestamp,
+                            parity: Cow::Borrowed(rest),
+                        })
+                    }
+                    _ => Err(DecodeError::InvalidValue(packet_type)),
+                }
+            }
+            PARITY => {
+                let (seq, rest) = take_u16(packet_type, rest)?;
+                let (count, rest) = take_u8(packet_type, rest)?;
+                let (timestamp, rest) = take_u32(packet_type, rest)?;
+                Ok(PacketType::Parity {
+                    seq,
+                    count,
+                    timestamp,
+                    parity: Cow::Borrowed(rest),
+                })
+            }
+            ALIVED => {
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Alived)
+            }
+            ACCEPTED => {
+                let (user_id, rest) = take_u64(packet_type, rest)?;
+                let (version, rest) = take_u16(packet_type, rest)?;
+                let (capabilities, rest) = take_u32(packet_type, rest)?;
+                let (token, rest) = take_u64(packet_type, rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Accepted {
+                    user_id,
+                    version,
+                    capabilities,
+                    token,
+                })
+            }
+            EVENT => {
+                let (seq, rest) = take_u64(packet_type, rest)?;
+                let (room_id, rest) = take_u16(packet_type, rest)?;
+                let (user_id, rest) = take_u64(packet_type, rest)?;
+                let (name, rest) = take_cstring(rest)?;
+                let (kind, rest) = take_u8(packet_type, rest)?;
+                let kind =
+                    EventKind::from_u8(kind).ok_or(DecodeError::InvalidValue(packet_type))?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Event {
+                    seq,
+                    kind,
+                    room_id,
+                    user_id,
+                    name: name.into(),
+                })
+            }
+            DISCONNECT => {
+                let (reason, rest) = take_u16(packet_type, rest)?;
+                let reason = DisconnectReason::from_u16(reason)
+                    .ok_or(DecodeError::InvalidValue(packet_type))?;
+                let (detail, rest) = take_cstring(rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Disconnect {
+                    reason,
+                    detail: detail.into(),
+                })
+            }
+            HANDSHAKE => {
+                let (public_key, rest) = take_array(packet_type, rest)?;
+                let cookie = if rest.is_empty() {
+                    None
+                } else {
+                    let (cookie, rest) = take_array(packet_type, rest)?;
+                    expect_empty(packet_type, rest)?;
+                    Some(cookie)
+                };
+                Ok(PacketType::Handshake { public_key, cookie })
+            }
+            HANDSHAKED => {
+                let (session_id, rest) = take_u64(packet_type, rest)?;
+                let (public_key, rest) = take_array(packet_type, rest)?;
+                let (identity, rest) = take_array(packet_type, rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Handshaked {
+                    session_id,
+                    public_key,
+                    identity,
+                })
+            }
+            SEALED => {
+                let (session_id, rest) = take_u64(packet_type, rest)?;
+                let (counter, rest) = take_u64(packet_type, rest)?;
+                Ok(PacketType::Sealed {
+                    session_id,
+                    counter,
+                    ciphertext: Cow::Borrowed(rest),
+                })
+            }
+            COOKIE => {
+                let (cookie, rest) = take_array(packet_type, rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Cookie { cookie })
+            }
+            SNAPSHOT => {
+                let (id, rest) = take_u32(packet_type, rest)?;
+                let (part, rest) = take_u16(packet_type, rest)?;
+                let (parts, mut rest) = take_u16(packet_type, rest)?;
+                if part >= parts {
+                    return Err(DecodeError::InvalidValue(packet_type));
+                }
+                let mut rooms = vec![];
+                while !rest.is_empty() {
+                    let (room_id, tail) = take_u16(packet_type, rest)?;
+                    let (seq, tail) = take_u64(packet_type, tail)?;
+                    let (count, mut tail) = take_u16(packet_type, tail)?;
+                    let mut users = vec![];
+                    for _ in 0..count {
+                        let (user_id, next) = take_u64(packet_type, tail)?;
+                        let (name, next) = take_cstring(next)?;
+                        users.push((user_id, name.into()));
+                        tail = next;
+                    }
+                    rooms.push((room_id, seq, users));
+                    rest = tail;
+                }
+                Ok(PacketType::Snapshot {
+                    id,
+                    part,
+                    parts,
+                    rooms,
+                })
+            }
+            FRAGMENT => {
+                let (frag_id, rest) = take_u32(packet_type, rest)?;
+                let (index, rest) = take_u16(packet_type, rest)?;
+                let (count, rest) = take_u16(packet_type, rest)?;
+                if index >= count {
+                    return Err(DecodeError::InvalidValue(packet_type));
+                }
+                Ok(PacketType::Fragment {
+                    frag_id,
+                    index,
+                    count,
+                    chunk: Cow::Borrowed(rest),
+                })
+            }
+            RELIABLE => {
+                let (seq, rest) = take_u32(packet_type, rest)?;
+                Ok(PacketType::Reliable {
+                    seq,
+                    payload: Cow::Borrowed(rest),
+                })
+            }
+            ACK => {
+                let (token, rest) = take_u64(packet_type, rest)?;
+                let (cumulative, rest) = take_u32(packet_type, rest)?;
+                let (sack, rest) = take_u64(packet_type, rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Ack {
+                    token,
+                    cumulative,
+                    sack,
+                })
+            }
+            ERROR => {
+                let (code, rest) = take_u16(packet_type, rest)?;
+                let code =
+                    ErrorCode::from_u16(code).ok_or(DecodeError::InvalidValue(packet_type))?;
+                let (request_type, rest) = take_u32(packet_type, rest)?;
+                let (request_id, rest) = take_u32(packet_type, rest)?;
+                let (detail, rest) = take_cstring(rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Error {
+                    code,
+                    request_type,
+                    request_id,
+                    detail: detail.into(),
+                })
+            }
+            SWITCHED => {
+                let (request_id, rest) = take_u32(packet_type, rest)?;
+                let (room_id, rest) = take_u16(packet_type, rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Switched {
+                    request_id,
+                    room_id,
+                })
+            }
+            QUEUED => {
+                let (request_id, rest) = take_u32(packet_type, rest)?;
+                let (room_id, rest) = take_u16(packet_type, rest)?;
+                let (position, rest) = take_u16(packet_type, rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::Queued {
+                    request_id,
+                    room_id,
+                    position,
+                })
+            }
+            ROOM_CHANGED => {
+                let (change, rest) = take_u8(packet_type, rest)?;
+                let change =
+                    RoomChange::from_u8(change).ok_or(DecodeError::InvalidValue(packet_type))?;
+                let (room_id, rest) = take_u16(packet_type, rest)?;
+                let (name, rest) = take_cstring(rest)?;
+                expect_empty(packet_type, rest)?;
+                Ok(PacketType::RoomChanged {
+                    change,
+                    room_id,
+                    name: name.into(),
+                })
+            }
+            _ => Err(DecodeError::UnknownType(packet_type)),
         }
-        TALK => Ok(PacketType::Talk {
-            audio_data: rest.to_vec(),
-        }),
-        ROOMS if rest.len() == 2 => Ok(PacketType::Rooms {
-            offset: u16::from_be_bytes(rest[..2].try_into()?),
-        }),
-        SWITCH if rest.len() == 2 => Ok(PacketType::Switch {
-            room_id: u16::from_be_bytes(rest[..2].try_into()?),
-        }),
-        ALIVE if rest.len() == 8 => Ok(PacketType::Alive {
-            seq: u64::from_be_bytes(rest[..8].try_into()?),
-        }),
-        LEAVE => Ok(PacketType::Leave),
-        _ => Err(anyhow::format_err!("invalid packet type")),
     }
 }
 
-pub fn parse_from_server_packet(buf: &[u8]) -> Result<PacketType> {
-    if buf.len() < 8 {
-        return Err(anyhow::format_err!("invalid packet: too small"));
+/// Checks the magic and returns the type of a client packet without
+/// decoding its body.
+pub fn peek_client_packet_type(buf: &[u8]) -> Result<u32, DecodeError> {
+    let (packet_type, _) = take_header(buf)?;
+    if !is_client_packet(packet_type) {
+        return Err(DecodeError::UnknownType(packet_type));
     }
+    Ok(packet_type)
+}
 
-    if &buf[..4] != MAGIC {
-        return Err(anyhow::format_err!("invalid magic"));
-    }
+/// Reads the session token, or the secure session id of a SEALED packet,
+/// without decoding the rest.
+pub fn peek_session_key(buf: &[u8]) -> Option<u64> {
+    let (packet_type, rest) = take_header(buf).ok()?;
+    let rest = match packet_type {
+        SWITCH => rest.get(2..)?,
+        ALIVE | SUBSCRIBE | LEAVE | ACK | SEALED => rest,
+        _ => return None,
+    };
+    rest.first_chunk::<8>().map(|key| u64::from_be_bytes(*key))
+}
 
-    let packet_type = u32::from_be_bytes(buf[4..8].try_into()?);
-    let rest = &buf[8..];
+pub fn parse_from_client_packet(buf: &[u8]) -> Result<PacketType<'_>, DecodeError> {
+    peek_client_packet_type(buf)?;
+    PacketType::decode(buf)
+}
 
-    match packet_type {
-        PONG => {
-            if rest.len() != 0 {
-                return Err(anyhow::format_err!("invalid pong payload"));
-            }
-            Ok(PacketType::Pong)
-        }
+pub fn parse_from_server_packet(buf: &[u8]) -> Result<PacketType<'_>, DecodeError> {
+    let (packet_type, _) = take_header(buf)?;
+    if !is_server_packet(packet_type) {
+        return Err(DecodeError::UnknownType(packet_type));
+    }
+    PacketType::decode(buf)
+}
 
-        JOINED => {
-            let mut users = vec![];
-            let mut start = 0;
+fn take_header(buf: &[u8]) -> Result<(u32, &[u8]), DecodeError> {
+    let Some((magic, rest)) = buf.split_first_chunk::<4>() else {
+        return Err(DecodeError::TooShort);
+    };
+    let Some((packet_type, rest)) = rest.split_first_chunk::<4>() else {
+        return Err(DecodeError::TooShort);
+    };
+    if *magic != MAGIC {
+        return Err(DecodeError::BadMagic);
+    }
+    Ok((u32::from_be_bytes(*packet_type), rest))
+}
 
-            for i in 0..rest.len() {
-                if rest[i] == 0 {
-                    let name = String::from_utf8(rest[start..i].to_vec())?;
-                    users.push(name);
-                    start = i + 1;
-                }
-            }
+fn take_u8(packet_type: u32, input: &[u8]) -> Result<(u8, &[u8]), DecodeError> {
+    match input.split_first() {
+        Some((&v, rest)) => Ok((v, rest)),
+        None => Err(DecodeError::InvalidLength(packet_type)),
+    }
+}
 
-            Ok(PacketType::Joined { users })
-        }
+fn take_u16(packet_type: u32, input: &[u8]) -> Result<(u16, &[u8]), DecodeError> {
+    match input.split_first_chunk::<2>() {
+        Some((v, rest)) => Ok((u16::from_be_bytes(*v), rest)),
+        None => Err(DecodeError::InvalidLength(packet_type)),
+    }
+}
 
-        TALKED => {
-            let audio_data = rest.to_vec();
-            Ok(PacketType::Talked { audio_data })
-        }
+fn take_u32(packet_type: u32, input: &[u8]) -> Result<(u32, &[u8]), DecodeError> {
+    match input.split_first_chunk::<4>() {
+        Some((v, rest)) => Ok((u32::from_be_bytes(*v), rest)),
+        None => Err(DecodeError::InvalidLength(packet_type)),
+    }
+}
 
-        ALIVED => {
-            if rest.len() != 0 {
-                return Err(anyhow::format_err!("invalid alived payload"));
-            }
-            Ok(PacketType::Alived)
-        }
+fn take_u64(packet_type: u32, input: &[u8]) -> Result<(u64, &[u8]), DecodeError> {
+    match input.split_first_chunk::<8>() {
+        Some((v, rest)) => Ok((u64::from_be_bytes(*v), rest)),
+        None => Err(DecodeError::InvalidLength(packet_type)),
+    }
+}
 
-        _ => Err(anyhow::format_err!("unknown packet type")),
+fn take_array<const N: usize>(
+    packet_type: u32,
+    input: &[u8],
+) -> Result<([u8; N], &[u8]), DecodeError> {
+    match input.split_first_chunk::<N>() {
+        Some((v, rest)) => Ok((*v, rest)),
+        None => Err(DecodeError::InvalidLength(packet_type)),
     }
 }
 
-fn take_cstring(input: &[u8]) -> Result<(&str, &[u8])> {
-    if let Some(pos) = input.iter().position(|&c| c == 0) {
-        let (left, rest) = input.split_at(pos);
-        let rest = &rest[1..]; // skip the null byte
-        let s = std::str::from_utf8(left)?;
-        Ok((s, rest))
+fn expect_empty(packet_type: u32, input: &[u8]) -> Result<(), DecodeError> {
+    if input.is_empty() {
+        Ok(())
     } else {
-        Err(anyhow::anyhow!("invalid packet: missing null terminator"))
+        Err(DecodeError::InvalidLength(packet_type))
     }
 }
+
+fn take_cstring(input: &[u8]) -> Result<(&str, &[u8]), DecodeError> {
+    let pos = input
+        .iter()
+        .position(|&c| c == 0)
+        .ok_or(DecodeError::MissingNul)?;
+    let (left, rest) = input.split_at(pos);
+    let s = std::str::from_utf8(left).map_err(|_| DecodeError::InvalidUtf8)?;
+    Ok((s, &rest[1..]))
+}
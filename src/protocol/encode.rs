@@ -1,102 +1,591 @@
 // src/protocol/encode.rs
+use std::borrow::Cow;
+
+use bytes::BufMut;
+
 use crate::protocol::constants::*;
+use crate::protocol::packet::{
+    CodecPolicy, DisconnectReason, ErrorCode, EventKind, PacketType, RoomChange, SnapshotRoom,
+};
+
+pub trait Encode {
+    fn encode_into(&self, buf: &mut impl BufMut);
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf);
+        buf
+    }
+}
+
+impl Encode for PacketType<'_> {
+    fn encode_into(&self, buf: &mut impl BufMut) {
+        buf.put_slice(&MAGIC);
+        buf.put_u32(self.code());
+
+        match self {
+            PacketType::Ping | PacketType::Pong | PacketType::Alived => {}
+            PacketType::Rooms { offset } => buf.put_u16(*offset),
+            PacketType::RoomsList { remaining, list } => {
+                buf.put_u8((*remaining).into());
+                for (room_id, name) in list.iter() {
+                    buf.put_u16(*room_id);
+                    put_cstring(buf, name);
+                }
+            }
+            PacketType::Join {
+                name,
+                hwid,
+                room_id,
+                version,
+                capabilities,
+                credential,
+                cookie,
+            } => {
+                put_cstring(buf, name);
+                put_cstring(buf, hwid);
+                buf.put_u16(*room_id);
+                buf.put_u16(*version);
+                buf.put_u32(*capabilities);
+                put_cstring(buf, credential);
+                if let Some(cookie) = cookie {
+                    buf.put_slice(cookie);
+                }
+            }
+            PacketType::Joined {
+                room_id,
+                seq,
+                codec,
+                users,
+            } => {
+                buf.put_u16(*room_id);
+                buf.put_u64(*seq);
+                buf.put_u8(codec.payload_type);
+                buf.put_u32(codec.sample_rate);
+                buf.put_u8(codec.channels);
+                buf.put_u16(codec.max_frame_len);
+                for (user_id, name) in users.iter() {
+                    buf.put_u64(*user_id);
+                    put_cstring(buf, name);
+                }
+            }
+            PacketType::Talk {
+                seq,
+                timestamp,
+                payload_type,
+                audio_data,
+            } => {
+                buf.put_u16(*seq);
+                buf.put_u32(*timestamp);
+                buf.put_u8(*payload_type);
+                buf.put_slice(audio_data);
+            }
+            PacketType::Talked {
+                talker,
+                seq,
+                timestamp,
+                payload_type,
+                audio_data,
+            } => {
+                buf.put_u8(TALKED_AUDIO);
+                buf.put_u64(*talker);
+                buf.put_u16(*seq);
+                buf.put_u32(*timestamp);
+                buf.put_u8(*payload_type);
+                buf.put_slice(audio_data);
+            }
+            PacketType::Event {
+                seq,
+                kind,
+                room_id,
+                user_id,
+                name,
+            } => {
+                buf.put_u64(*seq);
+                buf.put_u16(*room_id);
+                buf.put_u64(*user_id);
+                put_cstring(buf, name);
+                buf.put_u8(*kind as u8);
+            }
+            PacketType::Switch {
+                room_id,
+                token,
+                request_id,
+                credential,
+            } => {
+                buf.put_u16(*room_id);
+                buf.put_u64(*token);
+                buf.put_u32(*request_id);
+                put_cstring(buf, credential);
+            }
+            PacketType::Alive { token, seqs } => {
+                buf.put_u64(*token);
+                for (room_id, seq) in seqs.iter() {
+                    buf.put_u16(*room_id);
+                    buf.put_u64(*seq);
+                }
+            }
+            PacketType::Leave { token } => buf.put_u64(*token),
+            PacketType::Accepted {
+                user_id,
+                version,
+                capabilities,
+                token,
+            } => {
+                buf.put_u64(*user_id);
+                buf.put_u16(*version);
+                buf.put_u32(*capabilities);
+                buf.put_u64(*token);
+            }
+            PacketType::Disconnect { reason, detail } => {
+                buf.put_u16(*reason as u16);
+                put_cstring(buf, detail);
+            }
+            PacketType::Handshake { public_key, cookie } => {
+                buf.put_slice(public_key);
+                if let Some(cookie) = cookie {
+                    buf.put_slice(cookie);
+                }
+            }
+            PacketType::Handshaked {
+                session_id,
+                public_key,
+                identity,
+            } => {
+                buf.put_u64(*session_id);
+                buf.put_slice(public_key);
+                buf.put_slice(identity);
+            }
+            PacketType::Sealed {
+                session_id,
+                counter,
+                ciphertext,
+            } => {
+                buf.put_u64(*session_id);
+                buf.put_u64(*counter);
+                buf.put_slice(ciphertext);
+            }
+            PacketType::Cookie { cookie } => buf.put_slice(cookie),
+            PacketType::Reliable { seq, payload } => {
+                buf.put_u32(*seq);
+                buf.put_slice(payload);
+            }
+            PacketType::Ack {
+                token,
+                cumulative,
+                sack,
+            } => {
+                buf.put_u64(*token);
+                buf.put_u32(*cumulative);
+                buf.put_u64(*sack);
+            }
+            PacketType::Snapshot {
+                id,
+                part,
+                parts,
+                rooms,
+            } => {
+                buf.put_u32(*id);
+                buf.put_u16(*part);
+                buf.put_u16(*parts);
+                for (room_id, seq, users) in rooms.iter() {
+                    buf.put_u16(*room_id);
+                    buf.put_u64(*seq);
+                    buf.put_u16(users.len() as u16);
+                    for (user_id, name) in users.iter() {
+                        buf.put_u64(*user_id);
+                        put_cstring(buf, name);
+                    }
+                }
+            }
+            PacketType::Subscribe { token, rooms } => {
+                buf.put_u64(*token);
+                for room_id in rooms.iter() {
+                    buf.put_u16(*room_id);
+                }
+            }
+            PacketType::Fragment {
+                frag_id,
+                index,
+                count,
+                chunk,
+            } => {
+                buf.put_u32(*frag_id);
+                buf.put_u16(*index);
+                buf.put_u16(*count);
+                buf.put_slice(chunk);
+            }
+            PacketType::Parity {
+                seq,
+                count,
+                timestamp,
+                parity,
+            } => {
+                buf.put_u16(*seq);
+                buf.put_u8(*count);
+                buf.put_u32(*timestamp);
+                buf.put_slice(parity);
+            }
+            PacketType::TalkedParity {
+                talker,
+                seq,
+                count,
+                timestamp,
+                parity,
+            } => {
+                buf.put_u8(TALKED_PARITY);
+                buf.put_u64(*talker);
+                buf.put_u16(*seq);
+                buf.put_u8(*count);
+                buf.put_u32(*timestamp);
+                buf.put_slice(parity);
+            }
+            PacketType::Error {
+                code,
+                request_type,
+                request_id,
+                detail,
+            } => {
+                buf.put_u16(*code as u16);
+                buf.put_u32(*request_type);
+                buf.put_u32(*request_id);
+                put_cstring(buf, detail);
+            }
+            PacketType::Switched {
+                request_id,
+                room_id,
+            } => {
+                buf.put_u32(*request_id);
+                buf.put_u16(*room_id);
+            }
+            PacketType::Queued {
+                request_id,
+                room_id,
+                position,
+            } => {
+                buf.put_u32(*request_id);
+                buf.put_u16(*room_id);
+                buf.put_u16(*position);
+            }
+            PacketType::RoomChanged {
+                change,
+                room_id,
+                name,
+            } => {
+                buf.put_u8(*change as u8);
+                buf.put_u16(*room_id);
+                put_cstring(buf, name);
+            }
+        }
+    }
+}
 
-pub fn new_accepted(seq: u64, user_id: u64) -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&ACCEPTED.to_be_bytes());
-    packet.extend_from_slice(&seq.to_be_bytes());
-    packet.extend_from_slice(&user_id.to_be_bytes());
-    packet
+fn put_cstring(buf: &mut impl BufMut, s: &str) {
+    buf.put_slice(s.as_bytes());
+    buf.put_u8(0);
+}
+
+pub fn new_accepted(user_id: u64, version: u16, capabilities: u32, token: u64) -> Vec<u8> {
+    PacketType::Accepted {
+        user_id,
+        version,
+        capabilities,
+        token,
+    }
+    .encode()
 }
 
 pub fn new_ping() -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&PING.to_be_bytes());
-    packet
+    PacketType::Ping.encode()
+}
+
+pub fn new_rooms(offset: u16) -> Vec<u8> {
+    PacketType::Rooms { offset }.encode()
 }
 
 pub fn new_pong() -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&PONG.to_be_bytes());
-    packet
+    PacketType::Pong.encode()
 }
 
 pub fn new_rooms_list(remaining: bool, list: Vec<(u16, String)>) -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&ROOMSLIST.to_be_bytes());
-    packet.push(remaining.into());
-    for room in list.iter() {
-        packet.extend_from_slice(&room.0.to_be_bytes());
-        packet.extend_from_slice(room.1.as_bytes());
-        packet.push(0);
+    PacketType::RoomsList {
+        remaining,
+        list: list
+            .into_iter()
+            .map(|(id, name)| (id, Cow::Owned(name)))
+            .collect(),
     }
-    packet
+    .encode()
 }
 
-pub fn new_event(seq: u64, joined: bool, room_id: u16, user_id: u64, name: &str) -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&EVENT.to_be_bytes());
-    packet.extend_from_slice(&seq.to_be_bytes());
-    packet.extend_from_slice(&room_id.to_be_bytes());
-    packet.extend_from_slice(&user_id.to_be_bytes());
-    packet.extend_from_slice(name.as_bytes());
-    packet.push(0);
-    packet.push(joined.into());
-    packet
+pub fn new_event(seq: u64, kind: EventKind, room_id: u16, user_id: u64, name: &str) -> Vec<u8> {
+    PacketType::Event {
+        seq,
+        kind,
+        room_id,
+        user_id,
+        name: Cow::Borrowed(name),
+    }
+    .encode()
 }
 
-pub fn new_join(name: &str) -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&JOIN.to_be_bytes());
-    packet.extend_from_slice(name.as_bytes());
-    packet.push(0);
-    packet
+pub fn new_join(
+    name: &str,
+    hwid: &str,
+    room_id: u16,
+    version: u16,
+    capabilities: u32,
+    credential: &str,
+    cookie: Option<[u8; COOKIE_LEN]>,
+) -> Vec<u8> {
+    PacketType::Join {
+        name: Cow::Borrowed(name),
+        hwid: Cow::Borrowed(hwid),
+        room_id,
+        version,
+        capabilities,
+        credential: Cow::Borrowed(credential),
+        cookie,
+    }
+    .encode()
 }
 
-pub fn new_joined(room_id: u16, users: Vec<(u64, String)>) -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&JOINED.to_be_bytes());
-    packet.extend_from_slice(&room_id.to_be_bytes());
-    for user in users {
-        packet.extend_from_slice(&user.0.to_be_bytes());
-        packet.extend_from_slice(user.1.as_bytes());
-        packet.push(0);
+pub fn new_joined(
+    room_id: u16,
+    seq: u64,
+    codec: CodecPolicy,
+    users: Vec<(u64, String)>,
+) -> Vec<u8> {
+    PacketType::Joined {
+        room_id,
+        seq,
+        codec,
+        users: users
+            .into_iter()
+            .map(|(id, name)| (id, Cow::Owned(name)))
+            .collect(),
     }
-    packet
+    .encode()
 }
 
-pub fn new_talk(audio_data: &[u8]) -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&TALK.to_be_bytes());
-    packet.extend_from_slice(audio_data);
-    packet
+pub fn new_talk(seq: u16, timestamp: u32, payload_type: u8, audio_data: &[u8]) -> Vec<u8> {
+    PacketType::Talk {
+        seq,
+        timestamp,
+        payload_type,
+        audio_data: Cow::Borrowed(audio_data),
+    }
+    .encode()
 }
 
-pub fn new_talked_audio(talker: u64, audio_data: &[u8]) -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&TALKED.to_be_bytes());
-    packet.push(0);
-    packet.extend_from_slice(&talker.to_be_bytes());
-    packet.extend_from_slice(audio_data);
-    packet
+pub fn new_talked_audio(
+    talker: u64,
+    seq: u16,
+    timestamp: u32,
+    payload_type: u8,
+    audio_data: &[u8],
+) -> Vec<u8> {
+    PacketType::Talked {
+        talker,
+        seq,
+        timestamp,
+        payload_type,
+        audio_data: Cow::Borrowed(audio_data),
+    }
+    .encode()
 }
 
-pub fn new_alive() -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&ALIVE.to_be_bytes());
-    packet
+pub fn new_alive(token: u64, seqs: &[(u16, u64)]) -> Vec<u8> {
+    PacketType::Alive {
+        token,
+        seqs: seqs.to_vec(),
+    }
+    .encode()
+}
+
+pub fn new_subscribe(token: u64, rooms: &[u16]) -> Vec<u8> {
+    PacketType::Subscribe {
+        token,
+        rooms: rooms.to_vec(),
+    }
+    .encode()
+}
+
+pub fn new_switch(room_id: u16, token: u64, request_id: u32, credential: &str) -> Vec<u8> {
+    PacketType::Switch {
+        room_id,
+        token,
+        request_id,
+        credential: Cow::Borrowed(credential),
+    }
+    .encode()
+}
+
+pub fn new_leave(token: u64) -> Vec<u8> {
+    PacketType::Leave { token }.encode()
 }
 
 pub fn new_alived() -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&ALIVED.to_be_bytes());
-    packet
+    PacketType::Alived.encode()
+}
+
+pub fn new_handshake(public_key: [u8; 32], cookie: Option<[u8; COOKIE_LEN]>) -> Vec<u8> {
+    PacketType::Handshake { public_key, cookie }.encode()
+}
+
+pub fn new_handshaked(session_id: u64, public_key: [u8; 32], identity: [u8; 32]) -> Vec<u8> {
+    PacketType::Handshaked {
+        session_id,
+        public_key,
+        identity,
+    }
+    .encode()
+}
+
+pub fn new_cookie(cookie: [u8; COOKIE_LEN]) -> Vec<u8> {
+    PacketType::Cookie { cookie }.encode()
 }
 
-pub fn new_disconnect(reason: &str) -> Vec<u8> {
-    let mut packet = MAGIC.to_vec();
-    packet.extend_from_slice(&DISCONNECT.to_be_bytes());
-    packet.extend_from_slice(reason.as_bytes());
-    packet.push(0);
-    packet
+pub fn new_reliable(seq: u32, payload: &[u8]) -> Vec<u8> {
+    PacketType::Reliable {
+        seq,
+        payload: Cow::Borrowed(payload),
+    }
+    .encode()
+}
+
+pub fn new_ack(token: u64, cumulative: u32, sack: u64) -> Vec<u8> {
+    PacketType::Ack {
+        token,
+        cumulative,
+        sack,
+    }
+    .encode()
+}
+
+/// Splits the room state into as many SNAPSHOT parts as needed to keep each
+/// one under `SNAPSHOT_PART_LEN`. Large rooms continue in the next part.
+pub fn new_snapshot(id: u32, rooms: &[SnapshotRoom]) -> Vec<Vec<u8>> {
+    const ROOM_HEADER_LEN: usize = 12;
+
+    let mut parts: Vec<Vec<SnapshotRoom>> = vec![Vec::new()];
+    let mut part_len = 0;
+    for (room_id, seq, users) in rooms.iter() {
+        if part_len + ROOM_HEADER_LEN > SNAPSHOT_PART_LEN {
+            parts.push(Vec::new());
+            part_len = 0;
+        }
+        parts.last_mut().unwrap().push((*room_id, *seq, Vec::new()));
+        part_len += ROOM_HEADER_LEN;
+
+        for (user_id, name) in users.iter() {
+            let user_len = 8 + name.len() + 1;
+            if part_len + user_len > SNAPSHOT_PART_LEN {
+                parts.push(vec![(*room_id, *seq, Vec::new())]);
+                part_len = ROOM_HEADER_LEN;
+            }
+            let part = parts.last_mut().unwrap();
+            part.last_mut()
+                .unwrap()
+                .2
+                .push((*user_id, Cow::Borrowed(name.as_ref())));
+            part_len += user_len;
+        }
+    }
+
+    let count = parts.len() as u16;
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(part, rooms)| {
+            PacketType::Snapshot {
+                id,
+                part: part as u16,
+                parts: count,
+                rooms,
+            }
+            .encode()
+        })
+        .collect()
+}
+
+pub fn new_fragment(frag_id: u32, index: u16, count: u16, chunk: &[u8]) -> Vec<u8> {
+    PacketType::Fragment {
+        frag_id,
+        index,
+        count,
+        chunk: Cow::Borrowed(chunk),
+    }
+    .encode()
+}
+
+pub fn new_parity(seq: u16, count: u8, timestamp: u32, parity: &[u8]) -> Vec<u8> {
+    PacketType::Parity {
+        seq,
+        count,
+        timestamp,
+        parity: Cow::Borrowed(parity),
+    }
+    .encode()
+}
+
+pub fn new_talked_parity(
+    talker: u64,
+    seq: u16,
+    count: u8,
+    timestamp: u32,
+    parity: &[u8],
+) -> Vec<u8> {
+    PacketType::TalkedParity {
+        talker,
+        seq,
+        count,
+        timestamp,
+        parity: Cow::Borrowed(parity),
+    }
+    .encode()
+}
+
+pub fn new_error(code: ErrorCode, request_type: u32, request_id: u32, detail: &str) -> Vec<u8> {
+    PacketType::Error {
+        code,
+        request_type,
+        request_id,
+        detail: Cow::Borrowed(detail),
+    }
+    .encode()
+}
+
+pub fn new_switched(request_id: u32, room_id: u16) -> Vec<u8> {
+    PacketType::Switched {
+        request_id,
+        room_id,
+    }
+    .encode()
+}
+
+pub fn new_queued(request_id: u32, room_id: u16, position: u16) -> Vec<u8> {
+    PacketType::Queued {
+        request_id,
+        room_id,
+        position,
+    }
+    .encode()
+}
+
+pub fn new_room_changed(change: RoomChange, room_id: u16, name: &str) -> Vec<u8> {
+    PacketType::RoomChanged {
+        change,
+        room_id,
+        name: Cow::Borrowed(name),
+    }
+    .encode()
+}
+
+pub fn new_disconnect(reason: DisconnectReason, detail: &str) -> Vec<u8> {
+    PacketType::Disconnect {
+        reason,
+        detail: Cow::Borrowed(detail),
+    }
+    .encode()
 }
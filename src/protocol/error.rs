@@ -0,0 +1,37 @@
+// src/protocol/error.rs
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    TooShort,
+    BadMagic,
+    UnknownType(u32),
+    InvalidLength(u32),
+    InvalidValue(u32),
+    MissingNul,
+    InvalidUtf8,
+    Unauthenticated,
+    Replayed,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooShort => write!(f, "invalid packet: too short"),
+            DecodeError::BadMagic => write!(f, "invalid packet: bad magic"),
+            DecodeError::UnknownType(t) => write!(f, "invalid packet: unknown type {t}"),
+            DecodeError::InvalidLength(t) => {
+                write!(f, "invalid packet: bad payload length for type {t}")
+            }
+            DecodeError::InvalidValue(t) => {
+                write!(f, "invalid packet: bad field value for type {t}")
+            }
+            DecodeError::MissingNul => write!(f, "invalid packet: missing null terminator"),
+            DecodeError::InvalidUtf8 => write!(f, "invalid packet: string is not utf-8"),
+            DecodeError::Unauthenticated => write!(f, "invalid packet: authentication failed"),
+            DecodeError::Replayed => write!(f, "invalid packet: replayed counter"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
@@ -0,0 +1,211 @@
+// src/protocol/fec.rs
+use std::collections::VecDeque;
+
+use crate::protocol::encode::new_parity;
+
+pub const FEC_GROUP_SIZE: u8 = 4;
+pub const MAX_FEC_GROUP_SIZE: u8 = 16;
+/// Frames and parity packets remembered per talker while waiting for a
+/// group to become recoverable.
+pub const FEC_HISTORY: usize = 64;
+
+/// XORs `data`, prefixed with its length, into `parity` so that frames of
+/// different sizes can be recovered.
+fn xor_into(parity: &mut Vec<u8>, data: &[u8]) {
+    if parity.len() < data.len() + 2 {
+        parity.resize(data.len() + 2, 0);
+    }
+    let len = (data.len() as u16).to_be_bytes();
+    for (p, b) in parity.iter_mut().zip(len.iter().chain(data)) {
+        *p ^= b;
+    }
+}
+
+/// Collects consecutive voice frames into groups and produces one PARITY
+/// packet per completed group.
+pub struct FecEncoder {
+    group_size: u8,
+    seq: u16,
+    count: u8,
+    timestamp: u32,
+    parity: Vec<u8>,
+}
+
+impl Default for FecEncoder {
+    fn default() -> Self {
+        Self::new(FEC_GROUP_SIZE)
+    }
+}
+
+impl FecEncoder {
+    pub fn new(group_size: u8) -> Self {
+        Self {
+            group_size: group_size.clamp(2, MAX_FEC_GROUP_SIZE),
+            seq: 0,
+            count: 0,
+            timestamp: 0,
+            parity: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, seq: u16, timestamp: u32, data: &[u8]) -> Option<Vec<u8>> {
+        // A gap in our own sequence starts a new group.
+        if self.count == 0 || seq != self.seq.wrapping_add(self.count as u16) {
+            self.seq = seq;
+            self.count = 0;
+            self.timestamp = 0;
+            self.parity.clear();
+        }
+        xor_into(&mut self.parity, data);
+        self.timestamp ^= timestamp;
+        self.count += 1;
+
+        if self.count < self.group_size {
+            return None;
+        }
+        self.count = 0;
+        Some(new_parity(
+            self.seq,
+            self.group_size,
+            self.timestamp,
+            &self.parity,
+        ))
+    }
+}
+
+struct Group {
+    seq: u16,
+    count: u8,
+    timestamp: u32,
+    parity: Vec<u8>,
+}
+
+impl Group {
+    fn contains(&self, seq: u16) -> bool {
+        seq.wrapping_sub(self.seq) < self.count as u16
+    }
+
+    /// XORs the `present` frames out of the parity to get the missing one.
+    fn rebuild(&self, present: &[&(u16, u32, Vec<u8>)]) -> Option<(u16, u32, Vec<u8>)> {
+        let mut parity = self.parity.clone();
+        let mut timestamp = self.timestamp;
+        let mut seqs: Vec<u16> = Vec::with_capacity(present.len());
+        for (seq, frame_timestamp, data) in present {
+            if data.len() + 2 > parity.len() {
+                return None;
+            }
+            xor_into(&mut parity, data);
+            timestamp ^= frame_timestamp;
+            seqs.push(*seq);
+        }
+        let seq = (0..self.count as u16)
+            .map(|offset| self.seq.wrapping_add(offset))
+            .find(|seq| !seqs.contains(seq))?;
+
+        let len = u16::from_be_bytes([*parity.first()?, *parity.get(1)?]) as usize;
+        let data = parity.get(2..2 + len)?.to_vec();
+        Some((seq, timestamp, data))
+    }
+}
+
+/// Recovers a single lost frame per parity group from one talker's stream.
+#[derive(Default)]
+pub struct FecDecoder {
+    frames: VecDeque<(u16, u32, Vec<u8>)>,
+    groups: VecDeque<Group>,
+    recovered: VecDeque<u16>,
+}
+
+impl FecDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a received frame and returns a frame it made recoverable.
+    pub fn push_frame(
+        &mut self,
+        seq: u16,
+        timestamp: u32,
+        data: &[u8],
+    ) -> Option<(u16, u32, Vec<u8>)> {
+        if self.frames.iter().any(|(s, _, _)| *s == seq) {
+            return None;
+        }
+        self.remember((seq, timestamp, data.to_vec()));
+        self.recover()
+    }
+
+    /// Whether `seq` was already recovered from parity, so the original
+    /// arriving late is a duplicate.
+    pub fn is_recovered(&self, seq: u16) -> bool {
+        self.recovered.contains(&seq)
+    }
+
+    /// Records a parity packet and returns the frame it recovered, if the
+    /// group is missing exactly one.
+    pub fn push_parity(
+        &mut self,
+        seq: u16,
+        count: u8,
+        timestamp: u32,
+        parity: &[u8],
+    ) -> Option<(u16, u32, Vec<u8>)> {
+        if count == 0 || count > MAX_FEC_GROUP_SIZE {
+            return None;
+        }
+        self.groups.push_back(Group {
+            seq,
+            count,
+            timestamp,
+            parity: parity.to_vec(),
+        });
+        if self.groups.len() > FEC_HISTORY / 2 {
+            self.groups.pop_front();
+        }
+        self.recover()
+    }
+
+    fn remember(&mut self, frame: (u16, u32, Vec<u8>)) {
+        self.frames.push_back(frame);
+        if self.frames.len() > FEC_HISTORY {
+            self.frames.pop_front();
+        }
+    }
+
+    fn recover(&mut self) -> Option<(u16, u32, Vec<u8>)> {
+        let mut i = 0;
+        while i < self.groups.len() {
+            let group = &self.groups[i];
+            let present: Vec<&(u16, u32, Vec<u8>)> = self
+                .frames
+                .iter()
+                .filter(|(seq, _, _)| group.contains(*seq))
+                .collect();
+            let missing = (group.count as usize).saturating_sub(present.len());
+            if missing == 0 {
+                self.groups.remove(i);
+                continue;
+            }
+            // Groups stay put until they are used, since the frames they
+            // still lack may yet arrive.
+            let rebuilt = if missing == 1 {
+                group.rebuild(&present)
+            } else {
+                None
+            };
+            let Some((seq, timestamp, data)) = rebuilt else {
+                i += 1;
+                continue;
+            };
+
+            self.groups.remove(i);
+            self.remember((seq, timestamp, data.clone()));
+            self.recovered.push_back(seq);
+            if self.recovered.len() > FEC_HISTORY {
+                self.recovered.pop_front();
+            }
+            return Some((seq, timestamp, data));
+        }
+        None
+    }
+}
@@ -0,0 +1,134 @@
+// src/protocol/fragment.rs
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+use crate::protocol::encode::new_fragment;
+
+pub const FRAGMENT_HEADER_LEN: usize = 16;
+pub const MAX_FRAGMENTS: u16 = 64;
+pub const FRAGMENT_TIMEOUT_MS: u64 = 2000;
+pub const MAX_REASSEMBLY_BYTES: usize = 256 * 1024;
+
+/// Splits `packet` into FRAGMENT packets of at most `mtu` bytes each, or
+/// returns `None` if that takes more than `MAX_FRAGMENTS`, which no receiver
+/// would reassemble.
+pub fn fragment(packet: &[u8], frag_id: u32, mtu: usize) -> Option<Vec<Vec<u8>>> {
+    let chunk_len = mtu.saturating_sub(FRAGMENT_HEADER_LEN).max(1);
+    let count = packet.len().div_ceil(chunk_len);
+    if count > MAX_FRAGMENTS as usize {
+        return None;
+    }
+    let fragments = packet
+        .chunks(chunk_len)
+        .enumerate()
+        .map(|(index, chunk)| new_fragment(frag_id, index as u16, count as u16, chunk))
+        .collect();
+    Some(fragments)
+}
+
+struct Partial {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: u16,
+    bytes: usize,
+    started: Instant,
+}
+
+pub struct Reassembler<K> {
+    partial: HashMap<K, Partial>,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl<K: Hash + Eq + Clone> Default for Reassembler<K> {
+    fn default() -> Self {
+        Self::with_limit(MAX_REASSEMBLY_BYTES)
+    }
+}
+
+impl<K: Hash + Eq + Clone> Reassembler<K> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limit(max_bytes: usize) -> Self {
+        Self {
+            partial: HashMap::new(),
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    /// Stores one fragment and returns the whole packet once every fragment
+    /// has arrived. When the memory limit is hit the oldest partial packets
+    /// are dropped first.
+    pub fn push(
+        &mut self,
+        key: K,
+        index: u16,
+        count: u16,
+        chunk: &[u8],
+        now: Instant,
+    ) -> Option<Vec<u8>> {
+        if count == 0 || count > MAX_FRAGMENTS || index >= count || chunk.len() > self.max_bytes {
+            return None;
+        }
+
+        self.purge(now);
+        while self.bytes + chunk.len() > self.max_bytes {
+            let oldest = self
+                .partial
+                .iter()
+                .min_by_key(|(_, partial)| partial.started)
+                .map(|(key, _)| key.clone())?;
+            self.remove(&oldest);
+        }
+
+        let partial = self.partial.entry(key.clone()).or_insert_with(|| Partial {
+            chunks: vec![None; count as usize],
+            received: 0,
+            bytes: 0,
+            started: now,
+        });
+        if partial.chunks.len() != count as usize {
+            return None;
+        }
+        let slot = &mut partial.chunks[index as usize];
+        if slot.is_some() {
+            return None;
+        }
+        *slot = Some(chunk.to_vec());
+        partial.received += 1;
+        partial.bytes += chunk.len();
+        self.bytes += chunk.len();
+
+        if partial.received < count {
+            return None;
+        }
+        let partial = self.remove(&key)?;
+        Some(partial.chunks.into_iter().flatten().flatten().collect())
+    }
+
+    pub fn purge(&mut self, now: Instant) {
+        let timeout = Duration::from_millis(FRAGMENT_TIMEOUT_MS);
+        let expired: Vec<K> = self
+            .partial
+            .iter()
+            .filter(|(_, partial)| now.duration_since(partial.started) >= timeout)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+
+    pub fn pending_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn remove(&mut self, key: &K) -> Option<Partial> {
+        let partial = self.partial.remove(key)?;
+        self.bytes -= partial.bytes;
+        Some(partial)
+    }
+}
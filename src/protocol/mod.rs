@@ -1,13 +1,31 @@
 // src/protocol/mod.rs
 mod constants;
+mod crypto;
 mod decode;
 mod encode;
+mod error;
+mod fec;
+mod fragment;
 mod packet;
+mod reliable;
 
 pub use constants::*;
-pub use decode::{parse_from_client_packet, parse_from_server_packet};
+pub use crypto::{CryptoSession, Identity, KeyExchange, ReplayWindow, Role};
+pub use decode::{
+    Decode, parse_from_client_packet, parse_from_server_packet, peek_client_packet_type,
+    peek_session_key,
+};
 pub use encode::{
-    new_accepted, new_alive, new_alived, new_disconnect, new_event, new_join, new_joined, new_ping,
-    new_pong, new_rooms_list, new_talk, new_talked_audio,
+    Encode, new_accepted, new_ack, new_alive, new_alived, new_cookie, new_disconnect, new_error,
+    new_event, new_fragment, new_handshake, new_handshaked, new_join, new_joined, new_leave,
+    new_parity, new_ping, new_pong, new_queued, new_reliable, new_room_changed, new_rooms,
+    new_rooms_list, new_snapshot, new_subscribe, new_switch, new_switched, new_talk,
+    new_talked_audio, new_talked_parity,
+};
+pub use error::DecodeError;
+pub use fec::{FecDecoder, FecEncoder};
+pub use fragment::{FRAGMENT_HEADER_LEN, MAX_FRAGMENTS, Reassembler, fragment};
+pub use packet::{
+    CodecPolicy, DisconnectReason, ErrorCode, EventKind, PacketType, RoomChange, SnapshotRoom,
 };
-pub use packet::PacketType;
+pub use reliable::{ReliableReceiver, ReliableSender};
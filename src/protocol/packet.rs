@@ -1,5 +1,189 @@
 // src/protocol/packet.rs
-pub enum PacketType {
+use std::borrow::Cow;
+
+use crate::protocol::constants::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Left = 0,
+    Joined = 1,
+    ConnectionLost = 2,
+    Reconnected = 3,
+}
+
+impl EventKind {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(EventKind::Left),
+            1 => Some(EventKind::Joined),
+            2 => Some(EventKind::ConnectionLost),
+            3 => Some(EventKind::Reconnected),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoomChange {
+    Created = 0,
+    Renamed = 1,
+    Deleted = 2,
+}
+
+impl RoomChange {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(RoomChange::Created),
+            1 => Some(RoomChange::Renamed),
+            2 => Some(RoomChange::Deleted),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    PayloadType = 1,
+    FrameTooLarge = 2,
+    RateLimited = 3,
+    UnknownRoom = 4,
+    NotJoined = 5,
+    RoomFull = 6,
+    PermissionDenied = 7,
+    /// The room needs a password or invite code, and none or a wrong one
+    /// was given.
+    Unauthorized = 8,
+}
+
+impl ErrorCode {
+    pub fn from_u16(value: u16) -> Option<Self> {
+        match value {
+            1 => Some(ErrorCode::PayloadType),
+            2 => Some(ErrorCode::FrameTooLarge),
+            3 => Some(ErrorCode::RateLimited),
+            4 => Some(ErrorCode::UnknownRoom),
+            5 => Some(ErrorCode::NotJoined),
+            6 => Some(ErrorCode::RoomFull),
+            7 => Some(ErrorCode::PermissionDenied),
+            8 => Some(ErrorCode::Unauthorized),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = match self {
+            ErrorCode::PayloadType => "wrong payload type",
+            ErrorCode::FrameTooLarge => "frame too large",
+            ErrorCode::RateLimited => "rate limited",
+            ErrorCode::UnknownRoom => "unknown room",
+            ErrorCode::NotJoined => "not joined",
+            ErrorCode::RoomFull => "room full",
+            ErrorCode::PermissionDenied => "permission denied",
+            ErrorCode::Unauthorized => "unauthorized",
+        };
+        f.write_str(code)
+    }
+}
+
+impl std::error::Error for ErrorCode {}
+
+/// Why the server ended a session. Join hooks may return one of these as
+/// their error to pick the code clients see.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    Banned = 1,
+    DuplicateHwid = 2,
+    Timeout = 3,
+    SyncFailure = 4,
+    Kicked = 5,
+    Shutdown = 6,
+    UnsupportedVersion = 7,
+    Flooding = 8,
+    /// A join hook refused the user without saying why.
+    Rejected = 9,
+}
+
+impl DisconnectReason {
+    pub fn from_u16(value: u16) -> Option<Self> {
+        match value {
+            1 => Some(DisconnectReason::Banned),
+            2 => Some(DisconnectReason::DuplicateHwid),
+            3 => Some(DisconnectReason::Timeout),
+            4 => Some(DisconnectReason::SyncFailure),
+            5 => Some(DisconnectReason::Kicked),
+            6 => Some(DisconnectReason::Shutdown),
+            7 => Some(DisconnectReason::UnsupportedVersion),
+            8 => Some(DisconnectReason::Flooding),
+            9 => Some(DisconnectReason::Rejected),
+            _ => None,
+        }
+    }
+
+    /// Whether joining again later can succeed without user action.
+    pub fn is_retryable(self) -> bool {
+        matches!(
+            self,
+            DisconnectReason::Timeout | DisconnectReason::SyncFailure | DisconnectReason::Shutdown
+        )
+    }
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            DisconnectReason::Banned => "banned",
+            DisconnectReason::DuplicateHwid => "already connected from another client",
+            DisconnectReason::Timeout => "timed out",
+            DisconnectReason::SyncFailure => "lost synchronization",
+            DisconnectReason::Kicked => "kicked",
+            DisconnectReason::Shutdown => "server shutting down",
+            DisconnectReason::UnsupportedVersion => "unsupported protocol version",
+            DisconnectReason::Flooding => "too much traffic",
+            DisconnectReason::Rejected => "rejected",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for DisconnectReason {}
+
+/// The audio format a room accepts, announced in JOINED.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CodecPolicy {
+    pub payload_type: u8,
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub max_frame_len: u16,
+}
+
+impl CodecPolicy {
+    pub const OPUS_48K_MONO: Self = Self {
+        payload_type: PAYLOAD_OPUS,
+        sample_rate: 48000,
+        channels: 1,
+        max_frame_len: 1275,
+    };
+    /// 20 ms of uncompressed 16 kHz audio; meant for testing.
+    pub const PCM16_16K_MONO: Self = Self {
+        payload_type: PAYLOAD_PCM16,
+        sample_rate: 16000,
+        channels: 1,
+        max_frame_len: 640,
+    };
+}
+
+impl Default for CodecPolicy {
+    fn default() -> Self {
+        Self::OPUS_48K_MONO
+    }
+}
+
+pub type SnapshotRoom<'a> = (u16, u64, Vec<(u64, Cow<'a, str>)>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketType<'a> {
     Ping,
     Pong,
     Rooms {
@@ -7,34 +191,485 @@ pub enum PacketType {
     },
     RoomsList {
         remaining: bool,
-        list: Vec<(u16, String)>,
+        list: Vec<(u16, Cow<'a, str>)>,
     },
     Join {
-        name: String,
-        hwid: String,
+        name: Cow<'a, str>,
+        hwid: Cow<'a, str>,
         room_id: u16,
+        version: u16,
+        capabilities: u32,
+        /// Room password or invite code; empty when there is none.
+        credential: Cow<'a, str>,
+        cookie: Option<[u8; COOKIE_LEN]>,
     },
     Joined {
-        users: Vec<String>,
+        room_id: u16,
+        seq: u64,
+        codec: CodecPolicy,
+        users: Vec<(u64, Cow<'a, str>)>,
     },
     Talk {
-        audio_data: Vec<u8>,
+        seq: u16,
+        timestamp: u32,
+        payload_type: u8,
+        audio_data: Cow<'a, [u8]>,
     },
     Talked {
-        audio_data: Vec<u8>,
+        talker: u64,
+        seq: u16,
+        timestamp: u32,
+        payload_type: u8,
+        audio_data: Cow<'a, [u8]>,
     },
     Event {
-        joined: bool,
+        seq: u64,
+        kind: EventKind,
         room_id: u16,
         user_id: u64,
-        name: String,
+        name: Cow<'a, str>,
     },
     Switch {
         room_id: u16,
+        token: u64,
+        request_id: u32,
+        credential: Cow<'a, str>,
     },
     Alive {
-        seq: u64,
+        token: u64,
+        seqs: Vec<(u16, u64)>,
     },
     Alived,
-    Leave,
+    Accepted {
+        user_id: u64,
+        version: u16,
+        capabilities: u32,
+        token: u64,
+    },
+    Leave {
+        token: u64,
+    },
+    Disconnect {
+        reason: DisconnectReason,
+        detail: Cow<'a, str>,
+    },
+    Handshake {
+        public_key: [u8; 32],
+        /// Echo of the server's COOKIE, the same as in JOIN.
+        cookie: Option<[u8; COOKIE_LEN]>,
+    },
+    Handshaked {
+        session_id: u64,
+        public_key: [u8; 32],
+        /// The server's long-term public key.
+        identity: [u8; 32],
+    },
+    Sealed {
+        session_id: u64,
+        counter: u64,
+        ciphertext: Cow<'a, [u8]>,
+    },
+    Cookie {
+        cookie: [u8; COOKIE_LEN],
+    },
+    Reliable {
+        seq: u32,
+        payload: Cow<'a, [u8]>,
+    },
+    Ack {
+        token: u64,
+        cumulative: u32,
+        sack: u64,
+    },
+    Snapshot {
+        id: u32,
+        part: u16,
+        parts: u16,
+        rooms: Vec<SnapshotRoom<'a>>,
+    },
+    Subscribe {
+        token: u64,
+        rooms: Vec<u16>,
+    },
+    Fragment {
+        frag_id: u32,
+        index: u16,
+        count: u16,
+        chunk: Cow<'a, [u8]>,
+    },
+    /// XOR of the `count` voice frames starting at `seq`.
+    Parity {
+        seq: u16,
+        count: u8,
+        timestamp: u32,
+        parity: Cow<'a, [u8]>,
+    },
+    /// A forwarded PARITY, sent as a TALKED of kind `TALKED_PARITY`.
+    TalkedParity {
+        talker: u64,
+        seq: u16,
+        count: u8,
+        timestamp: u32,
+        parity: Cow<'a, [u8]>,
+    },
+    /// Answers the client packet of type `request_type` identified by
+    /// `request_id`: the SWITCH id, or the TALK sequence number.
+    Error {
+        code: ErrorCode,
+        request_type: u32,
+        request_id: u32,
+        detail: Cow<'a, str>,
+    },
+    /// Confirms the SWITCH `request_id`; the user is now in `room_id`.
+    Switched {
+        request_id: u32,
+        room_id: u16,
+    },
+    /// The SWITCH `request_id` waits for a free spot in `room_id`; 1 is
+    /// the front of the queue. Sent again whenever the position changes.
+    Queued {
+        request_id: u32,
+        room_id: u16,
+        position: u16,
+    },
+    /// A room was created, renamed or deleted. Sent to every user so room
+    /// lists stay current; `name` is the room's new or last name.
+    RoomChanged {
+        change: RoomChange,
+        room_id: u16,
+        name: Cow<'a, str>,
+    },
+}
+
+impl PacketType<'_> {
+    pub fn code(&self) -> u32 {
+        match self {
+            PacketType::Ping => PING,
+            PacketType::Pong => PONG,
+            PacketType::Rooms { .. } => ROOMS,
+            PacketType::RoomsList { .. } => ROOMSLIST,
+            PacketType::Join { .. } => JOIN,
+            PacketType::Joined { .. } => JOINED,
+            PacketType::Talk { .. } => TALK,
+            PacketType::Talked { .. } => TALKED,
+            PacketType::Event { .. } => EVENT,
+            PacketType::Switch { .. } => SWITCH,
+            PacketType::Alive { .. } => ALIVE,
+            PacketType::Alived => ALIVED,
+            PacketType::Accepted { .. } => ACCEPTED,
+            PacketType::Leave { .. } => LEAVE,
+            PacketType::Disconnect { .. } => DISCONNECT,
+            PacketType::Handshake { .. } => HANDSHAKE,
+            PacketType::Handshaked { .. } => HANDSHAKED,
+            PacketType::Sealed { .. } => SEALED,
+            PacketType::Cookie { .. } => COOKIE,
+            PacketType::Reliable { .. } => RELIABLE,
+            PacketType::Ack { .. } => ACK,
+            PacketType::Snapshot { .. } => SNAPSHOT,
+            PacketType::Subscribe { .. } => SUBSCRIBE,
+            PacketType::Fragment { .. } => FRAGMENT,
+            PacketType::Parity { .. } => PARITY,
+            PacketType::TalkedParity { .. } => TALKED,
+            PacketType::Error { .. } => ERROR,
+            PacketType::Switched { .. } => SWITCHED,
+            PacketType::Queued { .. } => QUEUED,
+            PacketType::RoomChanged { .. } => ROOM_CHANGED,
+        }
+    }
+
+    /// The session token a client packet carries, if its type has one.
+    pub fn session_token(&self) -> Option<u64> {
+        match self {
+            PacketType::Switch { token, .. }
+            | PacketType::Alive { token, .. }
+            | PacketType::Leave { token }
+            | PacketType::Ack { token, .. }
+            | PacketType::Subscribe { token, .. } => Some(*token),
+            _ => None,
+        }
+    }
+
+    pub fn into_owned(self) -> PacketType<'static> {
+        fn own(s: Cow<'_, str>) -> Cow<'static, str> {
+            Cow::Owned(s.into_owned())
+        }
+
+        match self {
+            PacketType::Ping => PacketType::Ping,
+            PacketType::Pong => PacketType::Pong,
+            PacketType::Rooms { offset } => PacketType::Rooms { offset },
+            PacketType::RoomsList { remaining, list } => PacketType::RoomsList {
+                remaining,
+                list: list.into_iter().map(|(id, name)| (id, own(name))).collect(),
+            },
+            PacketType::Join {
+                name,
+                hwid,
+                room_id,
+                version,
+                capabilities,
+                credential,
+                cookie,
+            } => PacketType::Join {
+                name: own(name),
+                hwid: own(hwid),
+                room_id,
+                version,
+                capabilities,
+                credential: own(credential),
+                cookie,
+            },
+            PacketType::Joined {
+                room_id,
+                seq,
+                codec,
+                users,
+            } => PacketType::Joined {
+                room_id,
+                seq,
+                codec,
+                users: users
+                    .into_iter()
+                    .map(|(id, name)| (id, own(name)))
+                    .collect(),
+            },
+            PacketType::Talk {
+                seq,
+                timestamp,
+                payload_type,
+                audio_data,
+            } => PacketType::Talk {
+                seq,
+                timestamp,
+                payload_type,
+                audio_data: Cow::Owned(audio_data.into_owned()),
+            },
+            PacketType::Talked {
+                talker,
+                seq,
+                timestamp,
+                payload_type,
+                audio_data,
+            } => PacketType::Talked {
+                talker,
+                seq,
+                timestamp,
+                payload_type,
+                audio_data: Cow::Owned(audio_data.into_owned()),
+            },
+            PacketType::Event {
+                seq,
+                kind,
+                room_id,
+                user_id,
+                name,
+            } => PacketType::Event {
+                seq,
+                kind,
+                room_id,
+                user_id,
+                name: own(name),
+            },
+            PacketType::Switch {
+                room_id,
+                token,
+                request_id,
+                credential,
+            } => PacketType::Switch {
+                room_id,
+                token,
+                request_id,
+                credential: own(credential),
+            },
+            PacketType::Alive { token, seqs } => PacketType::Alive { token, seqs },
+            PacketType::Alived => PacketType::Alived,
+            PacketType::Accepted {
+                user_id,
+                version,
+                capabilities,
+                token,
+            } => PacketType::Accepted {
+                user_id,
+                version,
+                capabilities,
+                token,
+            },
+            PacketType::Leave { token } => PacketType::Leave { token },
+            PacketType::Disconnect { reason, detail } => PacketType::Disconnect {
+                reason,
+                detail: own(detail),
+            },
+            PacketType::Handshake { public_key, cookie } => {
+                PacketType::Handshake { public_key, cookie }
+            }
+            PacketType::Handshaked {
+                session_id,
+                public_key,
+                identity,
+            } => PacketType::Handshaked {
+                session_id,
+                public_key,
+                identity,
+            },
+            PacketType::Sealed {
+                session_id,
+                counter,
+                ciphertext,
+            } => PacketType::Sealed {
+                session_id,
+                counter,
+                ciphertext: Cow::Owned(ciphertext.into_owned()),
+            },
+            PacketType::Cookie { cookie } => PacketType::Cookie { cookie },
+            PacketType::Reliable { seq, payload } => PacketType::Reliable {
+                seq,
+                payload: Cow::Owned(payload.into_owned()),
+            },
+            PacketType::Ack {
+                token,
+                cumulative,
+                sack,
+            } => PacketType::Ack {
+                token,
+                cumulative,
+                sack,
+            },
+            PacketType::Snapshot {
+                id,
+                part,
+                parts,
+                rooms,
+            } => PacketType::Snapshot {
+                id,
+                part,
+                parts,
+                rooms: rooms
+                    .into_iter()
+                    .map(|(room_id, seq, users)| {
+                        (
+                            room_id,
+                            seq,
+                            users
+                                .into_iter()
+                                .map(|(id, name)| (id, own(name)))
+                                .collect(),
+                        )
+                    })
+                    .collect(),
+            },
+            PacketType::Subscribe { token, rooms } => PacketType::Subscribe { token, rooms },
+            PacketType::Fragment {
+                frag_id,
+                index,
+                count,
+                chunk,
+            } => PacketType::Fragment {
+                frag_id,
+                index,
+                count,
+                chunk: Cow::Owned(chunk.into_owned()),
+            },
+            PacketType::Parity {
+                seq,
+                count,
+                timestamp,
+                parity,
+            } => PacketType::Parity {
+                seq,
+                count,
+                timestamp,
+                parity: Cow::Owned(parity.into_owned()),
+            },
+            PacketType::TalkedParity {
+                talker,
+                seq,
+                count,
+                timestamp,
+                parity,
+            } => PacketType::TalkedParity {
+                talker,
+                seq,
+                count,
+                timestamp,
+                parity: Cow::Owned(parity.into_owned()),
+            },
+            PacketType::Error {
+                code,
+                request_type,
+                request_id,
+                detail,
+            } => PacketType::Error {
+                code,
+                request_type,
+                request_id,
+                detail: own(detail),
+            },
+            PacketType::Switched {
+                request_id,
+                room_id,
+            } => PacketType::Switched {
+                request_id,
+                room_id,
+            },
+            PacketType::Queued {
+                request_id,
+                room_id,
+                position,
+            } => PacketType::Queued {
+                request_id,
+                room_id,
+                position,
+            },
+            PacketType::RoomChanged {
+                change,
+                room_id,
+                name,
+            } => PacketType::RoomChanged {
+                change,
+                room_id,
+                name: own(name),
+            },
+        }
+    }
+}
+
+pub fn is_client_packet(packet_type: u32) -> bool {
+    matches!(
+        packet_type,
+        PING | JOIN
+            | TALK
+            | ROOMS
+            | SWITCH
+            | ALIVE
+            | LEAVE
+            | HANDSHAKE
+            | SEALED
+            | ACK
+            | SUBSCRIBE
+            | FRAGMENT
+            | PARITY
+    )
+}
+
+pub fn is_server_packet(packet_type: u32) -> bool {
+    matches!(
+        packet_type,
+        PONG | ROOMSLIST
+            | JOINED
+            | TALKED
+            | ALIVED
+            | EVENT
+            | DISCONNECT
+            | ACCEPTED
+            | HANDSHAKED
+            | SEALED
+            | COOKIE
+            | RELIABLE
+            | SNAPSHOT
+            | FRAGMENT
+            | ERROR
+            | SWITCHED
+            | QUEUED
+            | ROOM_CHANGED
+    )
 }
@@ -0,0 +1,144 @@
+// src/protocol/reliable.rs
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
+
+use crate::protocol::encode::new_reliable;
+
+pub const RETRANSMIT_TIMEOUT_MS: u64 = 250;
+pub const MAX_RETRANSMIT_TIMEOUT_MS: u64 = 2000;
+pub const MAX_RETRANSMITS: u8 = 5;
+pub const SACK_BITS: u32 = 64;
+pub const MAX_RECEIVE_WINDOW: u32 = 1024;
+
+struct Pending {
+    packet: Vec<u8>,
+    sent_at: Instant,
+    retries: u8,
+}
+
+#[derive(Default)]
+pub struct ReliableSender {
+    next_seq: u32,
+    unacked: BTreeMap<u32, Pending>,
+}
+
+impl ReliableSender {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Numbers packets from `seq + 1` on, e.g. to exercise wrap-around.
+    pub fn starting_after(seq: u32) -> Self {
+        Self {
+            next_seq: seq,
+            ..Self::default()
+        }
+    }
+
+    pub fn wrap(&mut self, inner: &[u8], now: Instant) -> Vec<u8> {
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let seq = self.next_seq;
+
+        let packet = new_reliable(seq, inner);
+
+        self.unacked.insert(
+            seq,
+            Pending {
+                packet: packet.clone(),
+                sent_at: now,
+                retries: 0,
+            },
+        );
+        packet
+    }
+
+    pub fn on_ack(&mut self, cumulative: u32, sack: u64) {
+        // Seqs wrap, so anything not just ahead of `cumulative` is acked.
+        self.unacked.retain(|&seq, _| {
+            let offset = seq.wrapping_sub(cumulative).wrapping_sub(1);
+            if offset >= MAX_RECEIVE_WINDOW {
+                return false;
+            }
+            offset >= SACK_BITS || sack & (1 << offset) == 0
+        });
+    }
+
+    /// Returns the packets whose retransmit timer expired. Packets that ran
+    /// out of retries are dropped; the keepalive resync picks those up.
+    pub fn due(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut due = Vec::new();
+        self.unacked.retain(|_, pending| {
+            let rto = Duration::from_millis(
+                (RETRANSMIT_TIMEOUT_MS << pending.retries).min(MAX_RETRANSMIT_TIMEOUT_MS),
+            );
+            if now.duration_since(pending.sent_at) < rto {
+                return true;
+            }
+            if pending.retries >= MAX_RETRANSMITS {
+                return false;
+            }
+            pending.retries += 1;
+            pending.sent_at = now;
+            due.push(pending.packet.clone());
+            true
+        });
+        due
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// The wrapped packets still waiting for an ACK.
+    pub fn in_flight_packets(&self) -> impl Iterator<Item = &[u8]> {
+        self.unacked
+            .values()
+            .map(|pending| pending.packet.as_slice())
+    }
+}
+
+#[derive(Default)]
+pub struct ReliableReceiver {
+    cumulative: u32,
+    received: BTreeSet<u32>,
+}
+
+impl ReliableReceiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Expects `seq + 1` next, matching `ReliableSender::starting_after`.
+    pub fn starting_after(seq: u32) -> Self {
+        Self {
+            cumulative: seq,
+            ..Self::default()
+        }
+    }
+
+    /// Records `seq` and returns whether it is seen for the first time.
+    pub fn accept(&mut self, seq: u32) -> bool {
+        let offset = seq.wrapping_sub(self.cumulative);
+        if offset == 0 || offset > MAX_RECEIVE_WINDOW {
+            return false;
+        }
+        if !self.received.insert(seq) {
+            return false;
+        }
+        while self.received.remove(&self.cumulative.wrapping_add(1)) {
+            self.cumulative = self.cumulative.wrapping_add(1);
+        }
+        true
+    }
+
+    pub fn ack(&self) -> (u32, u64) {
+        let mut sack = 0u64;
+        for &seq in self.received.iter() {
+            let offset = seq.wrapping_sub(self.cumulative).wrapping_sub(1);
+            if offset < SACK_BITS {
+                sack |= 1 << offset;
+            }
+        }
+        (self.cumulative, sack)
+    }
+}
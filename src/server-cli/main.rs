@@ -1,13 +1,132 @@
+mod store;
+
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use anyhow::Context;
 use dashmap::DashMap;
-use pigeonvc2::server::Server;
+use pigeonvc2::protocol::{DisconnectReason, Identity};
+use pigeonvc2::server::{RoomConfig, Server, ServerConfig, hash_password, new_invite_code};
 use sqlx::SqlitePool;
 use sqlx::sqlite::SqliteConnectOptions;
+use tokio::io::{AsyncBufReadExt, BufReader};
+
+use store::SqliteRoomStore;
+
+/// How often invites created or revoked from the command line reach a
+/// running server.
+const INVITE_SYNC_SECS: u64 = 5;
+
+const USAGE: &str = "usage: pigeonvc2-server [password <room id> [password] | \
+                     invite <room id> <minutes> | revoke <code>]";
+
+const CONSOLE_USAGE: &str = "commands: create <name> | rename <room id> <name> | \
+                             limits <room id> <max users|none> <max queue> | delete <room id>";
+
+/// id, name, max_users, max_queue, password_hash, invite_only
+type RoomRow = (i64, String, Option<i64>, i64, Option<String>, bool);
+
+fn unix_now() -> anyhow::Result<i64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64)
+}
+
+/// Runs a command typed into the server's terminal.
+async fn console(srv: &Server, line: &str) -> anyhow::Result<()> {
+    let line = line.trim();
+    let (command, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let rest = rest.trim();
+    match command {
+        "" => {}
+        "create" => {
+            srv.create_room(rest, RoomConfig::default()).await?;
+        }
+        "rename" => {
+            let (room_id, name) = rest.split_once(' ').context(CONSOLE_USAGE)?;
+            let room_id = room_id.parse().context("invalid room id")?;
+            srv.rename_room(room_id, name.trim()).await?;
+        }
+        "limits" => {
+            let args: Vec<&str> = rest.split_whitespace().collect();
+            let [room_id, max_users, max_queue] = args[..] else {
+                anyhow::bail!(CONSOLE_USAGE);
+            };
+            let room_id = room_id.parse().context("invalid room id")?;
+            let max_users = match max_users {
+                "none" => None,
+                max_users => Some(max_users.parse().context("invalid max users")?),
+            };
+            let max_queue = max_queue.parse().context("invalid max queue")?;
+            srv.set_room_limits(room_id, max_users, max_queue).await?;
+        }
+        "delete" => {
+            let room_id = rest.parse().context("invalid room id")?;
+            srv.delete_room(room_id).await?;
+        }
+        _ => anyhow::bail!(CONSOLE_USAGE),
+    }
+    Ok(())
+}
+
+/// Runs an admin command against the database instead of starting the
+/// server.
+async fn admin(db: &SqlitePool, args: &[String]) -> anyhow::Result<()> {
+    let room_id = |arg: Option<&String>| -> anyhow::Result<i64> {
+        arg.context(USAGE)?
+            .parse()
+            .with_context(|| format!("invalid room id\n{USAGE}"))
+    };
+    match args.first().map(String::as_str) {
+        Some("password") => {
+            let room_id = room_id(args.get(1))?;
+            let hash = args.get(2).map(|p| hash_password(p)).transpose()?;
+            let updated = sqlx::query("UPDATE rooms SET password_hash = ? WHERE id = ?")
+                .bind(&hash)
+                .bind(room_id)
+                .execute(db)
+                .await
+                .context("failed to set room password")?;
+            anyhow::ensure!(
+                updated.rows_affected() == 1,
+                "room {room_id} does not exist"
+            );
+            let change = if hash.is_some() { "set" } else { "cleared" };
+            println!("Password {change} for room {room_id}; restart the server to apply it");
+        }
+        Some("invite") => {
+            let room_id = room_id(args.get(1))?;
+            let minutes: i64 = args
+                .get(2)
+                .context(USAGE)?
+                .parse()
+                .with_context(|| format!("invalid minutes\n{USAGE}"))?;
+            let code = new_invite_code();
+            sqlx::query("INSERT INTO invites (code, room_id, expires_at) VALUES (?, ?, ?)")
+                .bind(&code)
+                .bind(room_id)
+                .bind(unix_now()? + minutes * 60)
+                .execute(db)
+                .await
+                .context("failed to create invite")?;
+            println!("Invite for room {room_id}, valid for {minutes} minutes: {code}");
+        }
+        Some("revoke") => {
+            let code = args.get(1).context(USAGE)?.to_ascii_uppercase();
+            let revoked = sqlx::query("DELETE FROM invites WHERE code = ?")
+                .bind(&code)
+                .execute(db)
+                .await
+                .context("failed to revoke invite")?;
+            anyhow::ensure!(revoked.rows_affected() > 0, "invite {code} does not exist");
+            println!("Revoked invite {code}");
+        }
+        _ => anyhow::bail!(USAGE),
+    }
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
     let state = Arc::new(DashMap::new());
 
     let db = SqlitePool::connect_with(
@@ -38,7 +157,11 @@ async fn main() -> anyhow::Result<()> {
         CREATE TABLE IF NOT EXISTS rooms (
             id          INTEGER PRIMARY KEY,
             name        TEXT NOT NULL UNIQUE,
-            description TEXT
+            description TEXT,
+            max_users   INTEGER,
+            max_queue   INTEGER NOT NULL DEFAULT 0,
+            password_hash TEXT,
+            invite_only INTEGER NOT NULL DEFAULT 0
         );
         "#,
     )
@@ -46,6 +169,76 @@ async fn main() -> anyhow::Result<()> {
     .await
     .context("failed to create rooms table")?;
 
+    // Databases created before room limits and private rooms existed lack
+    // these columns.
+    let room_columns: Vec<(String,)> =
+        sqlx::query_as("SELECT name FROM pragma_table_info('rooms')")
+            .fetch_all(&db)
+            .await
+            .context("failed to inspect rooms table")?;
+    for (column, definition) in [
+        ("max_users", "INTEGER"),
+        ("max_queue", "INTEGER NOT NULL DEFAULT 0"),
+        ("password_hash", "TEXT"),
+        ("invite_only", "INTEGER NOT NULL DEFAULT 0"),
+    ] {
+        if !room_columns.iter().any(|(name,)| name == column) {
+            sqlx::query(&format!(
+                "ALTER TABLE rooms ADD COLUMN {column} {definition}"
+            ))
+            .execute(&db)
+            .await
+            .with_context(|| format!("failed to add rooms.{column}"))?;
+        }
+    }
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS invites (
+            code        TEXT PRIMARY KEY,
+            room_id     INTEGER NOT NULL REFERENCES rooms(id) ON DELETE CASCADE,
+            expires_at  INTEGER NOT NULL
+        );
+        "#,
+    )
+    .execute(&db)
+    .await
+    .context("failed to create invites table")?;
+
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS identity (
+            id      INTEGER PRIMARY KEY CHECK (id = 1),
+            secret  BLOB NOT NULL
+        );
+        "#,
+    )
+    .execute(&db)
+    .await
+    .context("failed to create identity table")?;
+
+    // Clients may pin the identity key, so it has to survive restarts.
+    let identity = match sqlx::query_as::<_, (Vec<u8>,)>("SELECT secret FROM identity WHERE id = 1")
+        .fetch_optional(&db)
+        .await
+        .context("failed to load server identity")?
+    {
+        Some((secret,)) => Identity::from_secret(
+            secret
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("stored server identity is corrupt"))?,
+        ),
+        None => {
+            let identity = Identity::generate();
+            sqlx::query("INSERT INTO identity (id, secret) VALUES (1, ?)")
+                .bind(identity.secret().to_vec())
+                .execute(&db)
+                .await
+                .context("failed to store server identity")?;
+            identity
+        }
+    };
+
     let (room_count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM rooms")
         .fetch_one(&db)
         .await
@@ -65,6 +258,10 @@ async fn main() -> anyhow::Result<()> {
         .context("failed to insert default rooms")?;
     }
 
+    if !args.is_empty() {
+        return admin(&db, &args).await;
+    }
+
     let join_fn = {
         let db = db.clone();
         let state = state.clone();
@@ -72,8 +269,9 @@ async fn main() -> anyhow::Result<()> {
             let db = db.clone();
             let state = state.clone();
             async move {
-                if let Some(_) = state.get(&hwid) {
-                    anyhow::bail!("user with hwid `{hwid}` is already joined");
+                if state.contains_key(&hwid) {
+                    return Err(anyhow::Error::new(DisconnectReason::DuplicateHwid)
+                        .context(format!("user with hwid `{hwid}` is already joined")));
                 }
                 if let Some((banned,)) =
                     sqlx::query_as::<_, (i64,)>("SELECT banned FROM users WHERE hwid = ?")
@@ -83,7 +281,8 @@ async fn main() -> anyhow::Result<()> {
                         .context("failed to query user by hwid")?
                 {
                     if banned != 0 {
-                        anyhow::bail!("user with hwid `{hwid}` is banned");
+                        return Err(anyhow::Error::new(DisconnectReason::Banned)
+                            .context(format!("user with hwid `{hwid}` is banned")));
                     }
 
                     sqlx::query("UPDATE users SET last_seen = CURRENT_TIMESTAMP WHERE hwid = ?")
@@ -117,20 +316,37 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
+    let db_rooms: Vec<RoomRow> = sqlx::query_as(
+        "SELECT id, name, max_users, max_queue, password_hash, invite_only FROM rooms ORDER BY id",
+    )
+    .fetch_all(&db)
+    .await
+    .context("failed to load rooms from database")?;
+
+    // Occupants of deleted rooms go to the first room, the lobby by default.
+    let config = ServerConfig {
+        fallback_room: db_rooms.first().map_or(1, |room| room.0 as u16),
+        ..ServerConfig::default()
+    };
     let srv = Arc::new(
         Server::new("0.0.0.0:8897".to_string(), join_fn, disconnect_fn)
             .await
-            .context("failed to start UDP server")?,
+            .context("failed to start UDP server")?
+            .with_config(config)
+            .with_identity(identity)
+            .with_room_store(SqliteRoomStore { db: db.clone() }),
     );
 
-    let db_rooms: Vec<(i64, String)> = sqlx::query_as("SELECT id, name FROM rooms ORDER BY id")
-        .fetch_all(&db)
-        .await
-        .context("failed to load rooms from database")?;
-
-    for (id, name) in db_rooms {
+    for (id, name, max_users, max_queue, password_hash, invite_only) in db_rooms {
         let id_u16 = id as u16; // assuming your IDs are in 0..65535
-        srv.add_room_with_id(id_u16, &name);
+        let config = RoomConfig {
+            max_users: max_users.map(|n| n.clamp(0, u16::MAX as i64) as u16),
+            max_queue: max_queue.clamp(0, u16::MAX as i64) as u16,
+            password_hash,
+            invite_only,
+            ..RoomConfig::default()
+        };
+        srv.add_room_with_config(id_u16, &name, config);
         println!("Loaded room {id_u16}: {name}");
     }
 
@@ -148,10 +364,69 @@ async fn main() -> anyhow::Result<()> {
         });
     }
 
+    {
+        let srv_clone = srv.clone();
+        tokio::spawn(async move {
+            srv_clone.retransmit_routine().await;
+        });
+    }
+
+    {
+        let srv_clone = srv.clone();
+        let db = db.clone();
+        tokio::spawn(async move {
+            loop {
+                let invites: anyhow::Result<Vec<(String, i64, i64)>> = async {
+                    sqlx::query_as(
+                        "SELECT code, room_id, expires_at FROM invites WHERE expires_at > ?",
+                    )
+                    .bind(unix_now()?)
+                    .fetch_all(&db)
+                    .await
+                    .context("failed to load invites")
+                }
+                .await;
+                match invites {
+                    Ok(invites) => {
+                        let invites: Vec<(String, u16, u64)> = invites
+                            .into_iter()
+                            .map(|(code, room_id, expires_at)| {
+                                (code, room_id as u16, expires_at as u64)
+                            })
+                            .collect();
+                        srv_clone.sync_invites(&invites);
+                    }
+                    Err(e) => eprintln!("{e:#}"),
+                }
+                tokio::time::sleep(Duration::from_secs(INVITE_SYNC_SECS)).await;
+            }
+        });
+    }
+
+    {
+        let srv_clone = srv.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(tokio::io::stdin()).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if let Err(e) = console(&srv_clone, &line).await {
+                    eprintln!("{e:#}");
+                }
+            }
+        });
+    }
+
     println!("Server running on 0.0.0.0:8897 (press Ctrl+C to exit)");
+    let key: String = srv
+        .identity_key()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    println!("Server identity key: {key}");
+    println!("{CONSOLE_USAGE}");
 
     tokio::signal::ctrl_c().await?;
     println!("Shutting down...");
+    srv.shutdown().await;
 
     Ok(())
 }
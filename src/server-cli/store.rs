@@ -0,0 +1,78 @@
+// src/server-cli/store.rs
+use anyhow::Context;
+use pigeonvc2::server::{RoomConfig, RoomStore, StoreFuture};
+use sqlx::SqlitePool;
+
+pub struct SqliteRoomStore {
+    pub db: SqlitePool,
+}
+
+impl RoomStore for SqliteRoomStore {
+    fn create_room<'a>(
+        &'a self,
+        room_id: u16,
+        name: &'a str,
+        config: &'a RoomConfig,
+    ) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            sqlx::query(
+                "INSERT INTO rooms (id, name, max_users, max_queue, password_hash, invite_only) \
+                 VALUES (?, ?, ?, ?, ?, ?)",
+            )
+            .bind(room_id)
+            .bind(name)
+            .bind(config.max_users)
+            .bind(config.max_queue)
+            .bind(&config.password_hash)
+            .bind(config.invite_only)
+            .execute(&self.db)
+            .await
+            .context("failed to insert room")?;
+            Ok(())
+        })
+    }
+
+    fn rename_room<'a>(&'a self, room_id: u16, name: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let result = sqlx::query("UPDATE rooms SET name = ? WHERE id = ?")
+                .bind(name)
+                .bind(room_id)
+                .execute(&self.db)
+                .await
+                .context("failed to rename room")?;
+            anyhow::ensure!(result.rows_affected() > 0, "room {room_id} does not exist");
+            Ok(())
+        })
+    }
+
+    fn set_room_limits(
+        &self,
+        room_id: u16,
+        max_users: Option<u16>,
+        max_queue: u16,
+    ) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            let result = sqlx::query("UPDATE rooms SET max_users = ?, max_queue = ? WHERE id = ?")
+                .bind(max_users)
+                .bind(max_queue)
+                .bind(room_id)
+                .execute(&self.db)
+                .await
+                .context("failed to set room limits")?;
+            anyhow::ensure!(result.rows_affected() > 0, "room {room_id} does not exist");
+            Ok(())
+        })
+    }
+
+    fn delete_room(&self, room_id: u16) -> StoreFuture<'_, ()> {
+        Box::pin(async move {
+            let result = sqlx::query("DELETE FROM rooms WHERE id = ?")
+                .bind(room_id)
+                .execute(&self.db)
+                .await
+                .context("failed to delete room")?;
+            anyhow::ensure!(result.rows_affected() > 0, "room {room_id} does not exist");
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,280 @@
+// src/server/access.rs
+use std::collections::HashSet;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use argon2::Argon2;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use rand_core::{OsRng, RngCore};
+
+use crate::protocol::{self, ErrorCode};
+use crate::server::Server;
+
+use super::model::{CREDENTIAL_LOCKOUT_SECS, INVITE_CODE_LEN, MAX_CREDENTIAL_FAILURES, Room};
+
+/// Unambiguous characters only, so codes survive being read out loud.
+const INVITE_ALPHABET: &[u8; 32] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+#[derive(Debug, Clone, Copy)]
+pub struct Invite {
+    pub room_id: u16,
+    /// Unix seconds.
+    pub expires_at: u64,
+}
+
+pub(crate) struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+pub(crate) enum CredentialCheck {
+    Pending,
+    Done { accepted: bool },
+}
+
+/// A password check that finished off the packet loop, along with the
+/// request that asked for it.
+pub(crate) struct VerifiedCredential {
+    addr: SocketAddr,
+    room_id: u16,
+    credential: String,
+    accepted: bool,
+    packet: Vec<u8>,
+}
+
+/// Hashes a room password into the PHC string stored in
+/// `RoomConfig::password_hash`.
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow::anyhow!("failed to hash password: {e}"))?;
+    Ok(hash.to_string())
+}
+
+pub fn new_invite_code() -> String {
+    let mut bytes = [0u8; INVITE_CODE_LEN];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+        .iter()
+        .map(|b| INVITE_ALPHABET[(b % 32) as usize] as char)
+        .collect()
+}
+
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|parsed| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok()
+    })
+}
+
+impl Server {
+    /// Creates an invite code for `room_id` that works until `valid_for` has
+    /// passed, however many times it is used.
+    pub fn create_invite(&self, room_id: u16, valid_for: Duration) -> String {
+        let code = new_invite_code();
+        let expires_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .saturating_add(valid_for)
+            .as_secs();
+        self.add_invite(&code, room_id, expires_at);
+        code
+    }
+
+    /// Registers an invite issued elsewhere, e.g. one loaded from storage.
+    pub fn add_invite(&self, code: &str, room_id: u16, expires_at: u64) {
+        self.invites.insert(
+            code.to_ascii_uppercase(),
+            Invite {
+                room_id,
+                expires_at,
+            },
+        );
+    }
+
+    pub fn revoke_invite(&self, code: &str) -> bool {
+        self.invites.remove(&code.to_ascii_uppercase()).is_some()
+    }
+
+    /// Makes `invites` (code, room id, expiry) the complete set, revoking
+    /// every code that is not among them, e.g. after reloading storage.
+    pub fn sync_invites(&self, invites: &[(String, u16, u64)]) {
+        let codes: HashSet<String> = invites
+            .iter()
+            .map(|(code, _, _)| code.to_ascii_uppercase())
+            .collect();
+        let revoked: Vec<String> = self
+            .invites
+            .iter()
+            .filter(|invite| !codes.contains(invite.key()))
+            .map(|invite| invite.key().clone())
+            .collect();
+        for code in revoked {
+            self.revoke_invite(&code);
+        }
+        for (code, room_id, expires_at) in invites {
+            self.add_invite(code, *room_id, *expires_at);
+        }
+    }
+
+    /// Checks the password or invite code `credential` against a private
+    /// room. Repeated failures lock the source address out of the room for
+    /// a while.
+    ///
+    /// Passwords are verified on the blocking pool so the packet loop keeps
+    /// going meanwhile; that gives `Ok(false)`, and once the verdict is in,
+    /// `packet` is handled again to send the answer.
+    pub(crate) fn check_credential(
+        &self,
+        addr: SocketAddr,
+        room_id: u16,
+        room: &Room,
+        credential: &str,
+        packet: &[u8],
+    ) -> Result<bool, (ErrorCode, String)> {
+        let config = room.config();
+        if config.password_hash.is_none() && !config.invite_only {
+            return Ok(true);
+        }
+        if credential.is_empty() {
+            let needs = if config.invite_only {
+                "an invite code"
+            } else {
+                "a password or invite code"
+            };
+            return Err((
+                ErrorCode::Unauthorized,
+                format!("{} requires {needs}", room.name()),
+            ));
+        }
+        let wrong = || {
+            Err((
+                ErrorCode::Unauthorized,
+                format!("wrong password or invite code for {}", room.name()),
+            ))
+        };
+
+        let key = (addr, room_id, credential.to_string());
+        if let Some((_, CredentialCheck::Done { accepted })) =
+            self.credential_checks.remove_if(&key, |_, check| {
+                matches!(check, CredentialCheck::Done { .. })
+            })
+        {
+            return if accepted { Ok(true) } else { wrong() };
+        }
+        if self.credential_checks.contains_key(&key) {
+            // A retry of a request that is still being verified.
+            return Ok(false);
+        }
+
+        // Checks still running count too, or a burst of guesses would all
+        // get through before the first one fails.
+        let ip = addr.ip();
+        let lockout = Duration::from_secs(CREDENTIAL_LOCKOUT_SECS);
+        let failed = self
+            .credential_failures
+            .get(&(ip, room_id))
+            .filter(|failures| failures.last.elapsed() < lockout)
+            .map_or(0, |failures| failures.count);
+        let pending = self
+            .credential_checks
+            .iter()
+            .filter(|check| {
+                check.key().0.ip() == ip
+                    && check.key().1 == room_id
+                    && matches!(check.value(), CredentialCheck::Pending)
+            })
+            .count() as u32;
+        if failed + pending >= MAX_CREDENTIAL_FAILURES {
+            return Err((
+                ErrorCode::Unauthorized,
+                "too many wrong credentials, try again later".to_string(),
+            ));
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let invited = self
+            .invites
+            .get(&credential.to_ascii_uppercase())
+            .is_some_and(|invite| invite.room_id == room_id && invite.expires_at > now);
+        if invited {
+            self.credential_failures.remove(&(ip, room_id));
+            return Ok(true);
+        }
+        let hash = match (&config.password_hash, config.invite_only) {
+            (Some(hash), false) => hash.clone(),
+            _ => {
+                self.credential_failed(ip, room_id);
+                return wrong();
+            }
+        };
+
+        self.credential_checks.insert(key, CredentialCheck::Pending);
+        let verified_tx = self.verified_tx.clone();
+        let credential = credential.to_string();
+        let packet = packet.to_vec();
+        tokio::task::spawn_blocking(move || {
+            let accepted = verify_password(&credential, &hash);
+            let _ = verified_tx.send(VerifiedCredential {
+                addr,
+                room_id,
+                credential,
+                accepted,
+                packet,
+            });
+        });
+        Ok(false)
+    }
+
+    /// Records the verdict of a password check and handles the request that
+    /// asked for it again, which now gets its answer.
+    pub(crate) async fn finish_credential_check(&self, verified: VerifiedCredential) {
+        let ip = verified.addr.ip();
+        if verified.accepted {
+            self.credential_failures.remove(&(ip, verified.room_id));
+        } else {
+            self.credential_failed(ip, verified.room_id);
+        }
+
+        let key = (verified.addr, verified.room_id, verified.credential);
+        self.credential_checks.insert(
+            key.clone(),
+            CredentialCheck::Done {
+                accepted: verified.accepted,
+            },
+        );
+        if let Ok(packet) = protocol::parse_from_client_packet(&verified.packet) {
+            let _ = self.handle(verified.addr, &verified.packet, packet).await;
+        }
+        // The request may have been turned away before it got this far.
+        self.credential_checks.remove(&key);
+    }
+
+    fn credential_failed(&self, ip: IpAddr, room_id: u16) {
+        let lockout = Duration::from_secs(CREDENTIAL_LOCKOUT_SECS);
+        let mut failures = self
+            .credential_failures
+            .entry((ip, room_id))
+            .or_insert(Failures {
+                count: 0,
+                last: Instant::now(),
+            });
+        if failures.last.elapsed() >= lockout {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = Instant::now();
+    }
+
+    pub(crate) fn purge_credentials(&self, now: u64) {
+        self.invites.retain(|_, invite| invite.expires_at > now);
+        let lockout = Duration::from_secs(CREDENTIAL_LOCKOUT_SECS);
+        self.credential_failures
+            .retain(|_, failures| failures.last.elapsed() < lockout);
+    }
+}
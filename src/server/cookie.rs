@@ -0,0 +1,56 @@
+// src/server/cookie.rs
+use std::net::SocketAddr;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::protocol::COOKIE_LEN;
+use crate::server::Server;
+
+use super::model::COOKIE_LIFETIME_SECS;
+
+type HmacSha256 = Hmac<Sha256>;
+
+impl Server {
+    pub(crate) fn make_cookie(&self, addr: SocketAddr, now: u64) -> [u8; COOKIE_LEN] {
+        let mut cookie = [0u8; COOKIE_LEN];
+        cookie[..8].copy_from_slice(&now.to_be_bytes());
+        cookie[8..].copy_from_slice(&self.cookie_mac(addr, now)[..COOKIE_LEN - 8]);
+        cookie
+    }
+
+    pub(crate) fn verify_cookie(
+        &self,
+        addr: SocketAddr,
+        cookie: &[u8; COOKIE_LEN],
+        now: u64,
+    ) -> bool {
+        let issued_at = u64::from_be_bytes(cookie[..8].try_into().unwrap());
+        if issued_at > now || now - issued_at > COOKIE_LIFETIME_SECS {
+            return false;
+        }
+
+        self.cookie_hmac(addr, issued_at)
+            .verify_truncated_left(&cookie[8..])
+            .is_ok()
+    }
+
+    fn cookie_mac(&self, addr: SocketAddr, issued_at: u64) -> [u8; 32] {
+        self.cookie_hmac(addr, issued_at)
+            .finalize()
+            .into_bytes()
+            .into()
+    }
+
+    fn cookie_hmac(&self, addr: SocketAddr, issued_at: u64) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.cookie_secret)
+            .expect("hmac accepts keys of any length");
+        match addr.ip() {
+            std::net::IpAddr::V4(ip) => mac.update(&ip.octets()),
+            std::net::IpAddr::V6(ip) => mac.update(&ip.octets()),
+        }
+        mac.update(&addr.port().to_be_bytes());
+        mac.update(&issued_at.to_be_bytes());
+        mac
+    }
+}
@@ -0,0 +1,152 @@
+// src/server/crypto.rs
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand_core::{OsRng, RngCore};
+
+use crate::protocol::{self, COOKIE_LEN, KeyExchange};
+use crate::server::Server;
+
+use super::model::{SecureSession, USER_TIMEOUT_SECS};
+
+impl Server {
+    pub(crate) async fn handle_handshake(
+        &self,
+        addr: SocketAddr,
+        client_public: [u8; 32],
+        cookie: Option<[u8; COOKIE_LEN]>,
+    ) -> anyhow::Result<()> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        // Same stateless round trip as JOIN: no key agreement and no session
+        // until the client proves it receives packets at `addr`.
+        match cookie {
+            Some(cookie) if self.verify_cookie(addr, &cookie, now) => {}
+            _ => {
+                let cookie = self.make_cookie(addr, now);
+                self.listener
+                    .send_to(&protocol::new_cookie(cookie), addr)
+                    .await?;
+                return Ok(());
+            }
+        }
+
+        let kx = KeyExchange::new();
+        let server_public = kx.public_key();
+        let session_id = OsRng.next_u64();
+
+        // Anyone can send a HANDSHAKE in someone else's name, so the new
+        // session stays unused until a packet sealed with it arrives; the
+        // address keeps its current session until then.
+        let session = Arc::new(SecureSession {
+            crypto: kx.finish_server(client_public, &self.identity, session_id),
+            addr: std::sync::Mutex::new(addr),
+            last_seen: AtomicU64::new(now + USER_TIMEOUT_SECS),
+        });
+        self.secure_sessions.insert(session_id, session);
+
+        let pkt = protocol::new_handshaked(session_id, server_public, self.identity_key());
+        self.listener.send_to(&pkt, addr).await?;
+        Ok(())
+    }
+
+    pub fn identity_key(&self) -> [u8; 32] {
+        self.identity.public_key()
+    }
+
+    pub(crate) fn open_sealed(
+        &self,
+        addr: SocketAddr,
+        buf: &[u8],
+        session_id: u64,
+        counter: u64,
+        ciphertext: &[u8],
+    ) -> anyhow::Result<Vec<u8>> {
+        let Some(session) = self
+            .secure_sessions
+            .get(&session_id)
+            .map(|s| s.value().clone())
+        else {
+            anyhow::bail!("sealed packet from {addr} for unknown session");
+        };
+        let plaintext = session
+            .crypto
+            .open_parsed(buf, session_id, counter, ciphertext)?;
+
+        let old_addr = std::mem::replace(&mut *session.addr.lock().unwrap(), addr);
+        if self.secure_addrs.get(&addr).map(|id| *id) != Some(session_id) {
+            // The session's first packet, or its client moved.
+            if self
+                .secure_addrs
+                .remove_if(&old_addr, |_, id| *id == session_id)
+                .is_some()
+            {
+                self.release_ip(old_addr);
+            }
+            match self.secure_addrs.insert(addr, session_id) {
+                Some(replaced) => {
+                    self.secure_sessions.remove(&replaced);
+                }
+                None => self.hold_ip(addr),
+            }
+            self.lingering.remove(&addr);
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        session
+            .last_seen
+            .store(now + USER_TIMEOUT_SECS, Ordering::Relaxed);
+        Ok(plaintext)
+    }
+
+    pub(crate) fn seal_for<'a>(&self, addr: SocketAddr, buf: &'a [u8]) -> Cow<'a, [u8]> {
+        let Some(session_id) = self.secure_addrs.get(&addr).map(|id| *id) else {
+            return Cow::Borrowed(buf);
+        };
+        match self.secure_sessions.get(&session_id) {
+            Some(session) => Cow::Owned(session.crypto.seal(buf)),
+            None => Cow::Borrowed(buf),
+        }
+    }
+
+    pub(crate) fn move_secure_session(&self, old_addr: SocketAddr, new_addr: SocketAddr) {
+        let Some((_, session_id)) = self.secure_addrs.remove(&old_addr) else {
+            return;
+        };
+        self.release_ip(old_addr);
+        if let Some(session) = self.secure_sessions.get(&session_id) {
+            *session.addr.lock().unwrap() = new_addr;
+        }
+        if self.secure_addrs.insert(new_addr, session_id).is_none() {
+            self.hold_ip(new_addr);
+        }
+    }
+
+    pub(crate) fn remove_secure_session(&self, addr: SocketAddr) {
+        if let Some((_, session_id)) = self.secure_addrs.remove(&addr) {
+            self.release_ip(addr);
+            self.secure_sessions.remove(&session_id);
+        }
+    }
+
+    pub(crate) fn purge_secure_sessions(&self, now: u64) {
+        self.secure_sessions.retain(|id, session| {
+            let addr = *session.addr.lock().unwrap();
+            // Unconfirmed sessions never outlive their timeout.
+            let in_use = self.secure_addrs.get(&addr).is_some_and(|a| *a == *id)
+                && self.users.contains_key(&addr);
+            let keep = session.last_seen.load(Ordering::Relaxed) > now || in_use;
+            if !keep
+                && self
+                    .secure_addrs
+                    .remove_if(&addr, |_, id| *id == session.crypto.id())
+                    .is_some()
+            {
+                self.release_ip(addr);
+            }
+            keep
+        });
+    }
+}
@@ -1,114 +1,216 @@
 // src/server/events.rs
+use std::borrow::Cow;
+use std::collections::BTreeSet;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::sync::atomic::Ordering;
 
+use crate::protocol::{self, PacketType};
 use crate::server::Server;
-use crate::server::model::{StoredEvent, User};
+use crate::server::model::{Room, StoredEvent, User};
 
 use super::model::{MAX_CONSECUTIVE_BEHIND, MAX_EVENT_HISTORY};
 
 impl Server {
-    pub async fn broadcast_event(
-        &self,
-        pkt_builder: impl Fn(u64) -> Vec<u8>,
-        recipients: &[SocketAddr],
-    ) {
-        if recipients.is_empty() {
+    pub async fn broadcast_event(&self, room_id: u16, pkt_builder: impl Fn(u64) -> Vec<u8>) {
+        let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone()) else {
             return;
-        }
+        };
 
-        let mut event_system = self.event_system.write().await;
+        let mut events = room_arc.events.write().await;
 
-        let seq = event_system.next_seq;
-        event_system.next_seq += 1;
+        let seq = events.next_seq;
+        events.next_seq += 1;
 
         let pkt = pkt_builder(seq);
 
-        if event_system.history.len() == MAX_EVENT_HISTORY {
-            event_system.history.pop_front();
+        if events.history.len() == MAX_EVENT_HISTORY {
+            events.history.pop_front();
         }
-        event_system.history.push_back(StoredEvent {
+        events.history.push_back(StoredEvent {
             seq,
             data: pkt.clone(),
         });
 
-        drop(event_system);
+        drop(events);
+
+        let subscribers: Vec<Arc<User>> = room_arc
+            .subscribers
+            .iter()
+            .map(|u| u.value().clone())
+            .collect();
+        for user_arc in subscribers {
+            self.send_reliable(&user_arc, &pkt).await;
+        }
+    }
+
+    pub(crate) async fn subscribe(&self, user_arc: &Arc<User>, room_id: u16) {
+        let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone()) else {
+            return;
+        };
+        user_arc.subscriptions.lock().unwrap().insert(room_id);
+        room_arc.subscribers.insert(user_arc.id, user_arc.clone());
+        self.send_room_state(user_arc, room_id, &room_arc).await;
+    }
+
+    pub(crate) fn unsubscribe(&self, user_arc: &User, room_id: u16) {
+        user_arc.subscriptions.lock().unwrap().remove(&room_id);
+        if let Some(room_arc) = self.rooms.get(&room_id) {
+            room_arc.subscribers.remove(&user_arc.id);
+        }
+    }
+
+    pub(crate) fn unsubscribe_all(&self, user_arc: &User) {
+        let rooms = std::mem::take(&mut *user_arc.subscriptions.lock().unwrap());
+        for room_id in rooms {
+            if let Some(room_arc) = self.rooms.get(&room_id) {
+                room_arc.subscribers.remove(&user_arc.id);
+            }
+        }
+    }
 
-        self.batch_send(&pkt, recipients).await;
+    /// Replaces the user's subscriptions with `rooms`. The user's own room is
+    /// always kept, and newly added rooms get their current state.
+    pub(crate) async fn update_subscriptions(&self, user_arc: &Arc<User>, rooms: &[u16]) {
+        let own_room = user_arc.room_id.load(Ordering::Relaxed);
+        let wanted: BTreeSet<u16> = rooms
+            .iter()
+            .copied()
+            .filter(|room_id| self.rooms.contains_key(room_id))
+            .chain([own_room])
+            .collect();
+        let current = user_arc.subscriptions.lock().unwrap().clone();
+
+        for room_id in current.difference(&wanted) {
+            self.unsubscribe(user_arc, *room_id);
+        }
+        for room_id in wanted.difference(&current) {
+            self.subscribe(user_arc, *room_id).await;
+        }
+    }
+
+    pub(crate) async fn send_room_state(&self, user_arc: &User, room_id: u16, room_arc: &Room) {
+        let pkt = {
+            let events = room_arc.events.read().await;
+            let users = room_arc.joined_snapshot.read().await.clone();
+            protocol::new_joined(room_id, events.next_seq - 1, room_arc.config().codec, users)
+        };
+        self.send_reliable(user_arc, &pkt).await;
     }
 
-    pub async fn handle_alive_sync(&self, addr: SocketAddr, user_arc: Arc<User>, client_seq: u64) {
-        enum SyncAction {
-            UpToDate,
-            Resend(Vec<Vec<u8>>),
-            Disconnect(String),
+    pub(crate) async fn send_snapshot(&self, user_arc: &User, room_ids: &[u16]) {
+        let mut rooms = Vec::with_capacity(room_ids.len());
+        for room_id in room_ids.iter() {
+            let Some(room_arc) = self.rooms.get(room_id).map(|r| r.value().clone()) else {
+                continue;
+            };
+            let events = room_arc.events.read().await;
+            let users = room_arc
+                .joined_snapshot
+                .read()
+                .await
+                .iter()
+                .map(|(id, name)| (*id, Cow::Owned(name.clone())))
+                .collect();
+            rooms.push((*room_id, events.next_seq - 1, users));
         }
 
-        let action = {
-            let event_system = self.event_system.read().await;
-            let server_last_seq = event_system.next_seq.saturating_sub(1);
+        let id = self.next_snapshot_id.fetch_add(1, Ordering::Relaxed);
+        for pkt in protocol::new_snapshot(id, &rooms) {
+            self.send_reliable(user_arc, &pkt).await;
+        }
+    }
+
+    pub async fn handle_alive_sync(
+        &self,
+        addr: SocketAddr,
+        user_arc: Arc<User>,
+        client_seqs: &[(u16, u64)],
+    ) {
+        let in_flight = in_flight_room_seqs(&user_arc);
+
+        let mut behind = Vec::new();
+        let mut stale = Vec::new();
+        let mut resend = Vec::new();
+        for (room_id, client_seq) in client_seqs.iter().copied() {
+            if !user_arc.subscriptions.lock().unwrap().contains(&room_id) {
+                continue;
+            }
+            let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone()) else {
+                continue;
+            };
 
+            let events = room_arc.events.read().await;
+            let server_last_seq = events.next_seq - 1;
             if client_seq >= server_last_seq {
-                SyncAction::UpToDate
-            } else {
-                let behind_by = server_last_seq - client_seq;
-
-                if behind_by > MAX_EVENT_HISTORY as u64 {
-                    SyncAction::Disconnect(format!(
-                        "Sync failure: Too far behind ({} events)",
-                        behind_by
-                    ))
-                } else {
-                    let failures = user_arc
-                        .consecutive_behind
-                        .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
-                        + 1;
-                    if failures >= MAX_CONSECUTIVE_BEHIND {
-                        SyncAction::Disconnect(format!(
-                            "Sync failure: Behind {} consecutive times",
-                            failures
-                        ))
-                    } else {
-                        let mut packets_to_resend = Vec::with_capacity(behind_by as usize);
-                        for event in event_system.history.iter() {
-                            if event.seq > client_seq {
-                                packets_to_resend.push(event.data.clone());
-                            }
-                        }
-                        if packets_to_resend.len() as u64 != behind_by {
-                            SyncAction::Disconnect(
-                                "Internal server error: Event history inconsistency".to_string(),
-                            )
-                        } else {
-                            SyncAction::Resend(packets_to_resend)
-                        }
-                    }
-                }
+                continue;
             }
-        };
+            // The retransmit timer is still working on this room's gap.
+            if in_flight
+                .iter()
+                .any(|&(pending_room, seq)| pending_room == room_id && seq > client_seq)
+            {
+                continue;
+            }
+            behind.push(room_id);
 
-        match action {
-            SyncAction::UpToDate => {
-                if user_arc
-                    .consecutive_behind
-                    .load(std::sync::atomic::Ordering::Relaxed)
-                    > 0
-                {
-                    user_arc
-                        .consecutive_behind
-                        .store(0, std::sync::atomic::Ordering::Relaxed);
-                }
+            let behind_by = server_last_seq - client_seq;
+            let missed: Vec<Vec<u8>> = events
+                .history
+                .iter()
+                .filter(|event| event.seq > client_seq)
+                .map(|event| event.data.clone())
+                .collect();
+            if behind_by > MAX_EVENT_HISTORY as u64 || missed.len() as u64 != behind_by {
+                stale.push(room_id);
+            } else {
+                resend.extend(missed);
             }
-            SyncAction::Resend(packets) => {
-                println!("User {addr} is behind. Resending {} events.", packets.len());
-                for pkt in packets {
-                    let _ = self.listener.send_to(&pkt, addr).await;
-                }
+        }
+
+        if behind.is_empty() {
+            user_arc.consecutive_behind.store(0, Ordering::Relaxed);
+            return;
+        }
+
+        let failures = user_arc.consecutive_behind.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= MAX_CONSECUTIVE_BEHIND {
+            stale = behind;
+            resend.clear();
+        }
+
+        if !resend.is_empty() {
+            println!("User {addr} is behind. Resending {} events.", resend.len());
+            for pkt in resend {
+                self.send_reliable(&user_arc, &pkt).await;
             }
-            SyncAction::Disconnect(reason) => {
-                println!("Disconnecting user {addr}: {}", reason);
-                self.disconnect_user(addr, Some(&reason)).await;
+        }
+        if !stale.is_empty() {
+            println!("Sending snapshot of {} rooms to user {addr}", stale.len());
+            user_arc.consecutive_behind.store(0, Ordering::Relaxed);
+            self.send_snapshot(&user_arc, &stale).await;
+        }
+    }
+}
+
+/// The room and event seq of every room update still waiting for an ACK.
+fn in_flight_room_seqs(user_arc: &User) -> Vec<(u16, u64)> {
+    let sender = user_arc.reliable.lock().unwrap();
+    let mut seqs = Vec::new();
+    for packet in sender.in_flight_packets() {
+        let Ok(PacketType::Reliable { payload, .. }) = protocol::parse_from_server_packet(packet)
+        else {
+            continue;
+        };
+        match protocol::parse_from_server_packet(&payload) {
+            Ok(
+                PacketType::Event { room_id, seq, .. } | PacketType::Joined { room_id, seq, .. },
+            ) => seqs.push((room_id, seq)),
+            Ok(PacketType::Snapshot { rooms, .. }) => {
+                seqs.extend(rooms.iter().map(|(room_id, seq, _)| (*room_id, *seq)))
             }
+            _ => {}
         }
     }
+    seqs
 }
@@ -0,0 +1,94 @@
+// src/server/filter.rs
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::protocol::{self, ErrorCode, PacketType};
+use crate::server::Server;
+
+/// Packets dropped before reaching a handler, by reason.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DropStats {
+    /// Over a per-address or per-subnet budget, or from a blocked address.
+    pub rate_limited: u64,
+    /// A valid header, but a type the source address may not send yet.
+    pub unknown_source: u64,
+    /// Bad magic, unknown type, or a body that failed to decode.
+    pub malformed: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct DropCounters {
+    pub rate_limited: AtomicU64,
+    pub unknown_source: AtomicU64,
+    pub malformed: AtomicU64,
+}
+
+impl Server {
+    pub fn drop_stats(&self) -> DropStats {
+        DropStats {
+            rate_limited: self.drops.rate_limited.load(Ordering::Relaxed),
+            unknown_source: self.drops.unknown_source.load(Ordering::Relaxed),
+            malformed: self.drops.malformed.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn is_known(&self, addr: SocketAddr) -> bool {
+        self.users.contains_key(&addr) || self.lingering.contains_key(&addr)
+    }
+
+    /// Decides from the header and session key alone whether a packet is
+    /// worth parsing. Addresses without a user may only look around and
+    /// join, or prove they own a live session after their address changed.
+    pub(crate) fn admit_type(&self, addr: SocketAddr, packet_type: u32, key: Option<u64>) -> bool {
+        let admitted = match packet_type {
+            protocol::PING | protocol::ROOMS | protocol::JOIN | protocol::HANDSHAKE => true,
+            _ if self.is_known(addr) => true,
+            protocol::SEALED => key.is_some_and(|id| self.secure_sessions.contains_key(&id)),
+            protocol::ALIVE
+            | protocol::SUBSCRIBE
+            | protocol::SWITCH
+            | protocol::LEAVE
+            | protocol::ACK => key.is_some_and(|token| self.sessions.contains_key(&token)),
+            _ => false,
+        };
+        if !admitted {
+            self.drops.unknown_source.fetch_add(1, Ordering::Relaxed);
+        }
+        admitted
+    }
+
+    /// Checks a reassembled packet from an address without a user the way
+    /// `admit_type` checks a datagram.
+    pub(crate) fn admit(&self, addr: SocketAddr, packet: &PacketType) -> bool {
+        let admitted = self.is_known(addr)
+            || match packet {
+                PacketType::Ping
+                | PacketType::Rooms { .. }
+                | PacketType::Join { .. }
+                | PacketType::Handshake { .. } => true,
+                PacketType::Sealed { session_id, .. } => {
+                    self.secure_sessions.contains_key(session_id)
+                }
+                packet => packet
+                    .session_token()
+                    .is_some_and(|token| self.sessions.contains_key(&token)),
+            };
+        if !admitted {
+            self.drops.unknown_source.fetch_add(1, Ordering::Relaxed);
+        }
+        admitted
+    }
+
+    /// Tells a client that its control packet went nowhere because it has
+    /// no session. Voice is dropped silently; its next ALIVE gets the answer.
+    pub(crate) async fn reject_unjoined(&self, addr: SocketAddr, packet: &PacketType<'_>) {
+        let (request_type, request_id) = match packet {
+            PacketType::Alive { .. } => (protocol::ALIVE, 0),
+            PacketType::Subscribe { .. } => (protocol::SUBSCRIBE, 0),
+            PacketType::Switch { request_id, .. } => (protocol::SWITCH, *request_id),
+            _ => return,
+        };
+        self.send_error(addr, ErrorCode::NotJoined, request_type, request_id, "")
+            .await;
+    }
+}
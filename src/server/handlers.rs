@@ -1,62 +1,161 @@
 // src/server/handlers.rs
+use std::collections::BTreeSet;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use crate::protocol;
-use crate::protocol::PacketType;
+use rand_core::{OsRng, RngCore};
+use tokio::sync::RwLock;
+
+use crate::protocol::{self, DisconnectReason, ErrorCode, EventKind};
+use crate::protocol::{PacketType, ReliableSender};
 use crate::server::Server;
 
-use super::model::{USER_TIMEOUT_SECS, User};
+use super::model::{
+    MEDIA_ERROR_INTERVAL, Room, RoomConfig, SERVER_CAPABILITIES, USER_TIMEOUT_SECS, User,
+};
+use super::ratelimit::{MediaLimiter, MediaVerdict, TokenBucket};
 
 impl Server {
-    pub async fn handle(&self, addr: SocketAddr, buf: &[u8]) -> anyhow::Result<()> {
-        let packet_type = protocol::parse_from_client_packet(buf)?;
+    /// Handles a client packet already parsed from `buf`.
+    pub async fn handle(
+        &self,
+        addr: SocketAddr,
+        buf: &[u8],
+        packet_type: PacketType<'_>,
+    ) -> anyhow::Result<()> {
         let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
 
         match packet_type {
             PacketType::Ping => {
-                self.listener.send_to(&protocol::new_pong(), addr).await?;
+                self.send_to(&protocol::new_pong(), addr).await?;
             }
-            PacketType::Rooms { mut offset } => {
-                let mut remaining = false;
-                let mut list = Vec::new();
-                if offset == 0 {
-                    offset = 1;
-                }
-
-                for i in offset..offset + 10 {
-                    let room = match self.rooms.get(&i) {
-                        Some(r) => r.value().clone(),
-                        None => break,
-                    };
-                    list.push((i, room.name.clone()));
-                }
-                if self.rooms.len() as u16 >= offset + 10 {
-                    remaining = true;
-                }
+            PacketType::Rooms { offset } => {
+                // Deleted rooms leave gaps, so `offset` is the first room id
+                // to list rather than a count.
+                let mut ids: Vec<u16> = self
+                    .rooms
+                    .iter()
+                    .map(|r| *r.key())
+                    .filter(|id| *id >= offset)
+                    .collect();
+                ids.sort_unstable();
+                let remaining = ids.len() > 10;
+                let list = ids
+                    .into_iter()
+                    .take(10)
+                    .filter_map(|id| Some((id, self.rooms.get(&id)?.name())))
+                    .collect();
 
-                self.listener
-                    .send_to(&protocol::new_rooms_list(remaining, list), addr)
+                self.send_to(&protocol::new_rooms_list(remaining, list), addr)
                     .await?;
             }
-            PacketType::Alive { seq: client_seq } => {
-                if let Some(user_arc) = self.keepalive_user_arc(addr).await {
-                    self.listener.send_to(&protocol::new_alived(), addr).await?;
-                    if client_seq > 0 {
-                        self.handle_alive_sync(addr, user_arc, client_seq).await;
-                    }
+            PacketType::Alive { token, seqs } => {
+                if let Some(user_arc) = self.keepalive_session(addr, token).await {
+                    self.send_to(&protocol::new_alived(), addr).await?;
+                    self.handle_alive_sync(addr, user_arc, &seqs).await;
+                } else {
+                    self.send_error(addr, ErrorCode::NotJoined, protocol::ALIVE, 0, "")
+                        .await;
                 }
             }
-            PacketType::Talk { audio_data } => {
+            PacketType::Subscribe { token, rooms } => {
+                if let Some(user_arc) = self.keepalive_session(addr, token).await {
+                    self.update_subscriptions(&user_arc, &rooms).await;
+                } else {
+                    self.send_error(addr, ErrorCode::NotJoined, protocol::SUBSCRIBE, 0, "")
+                        .await;
+                }
+            }
+            PacketType::Talk {
+                seq,
+                timestamp,
+                payload_type,
+                audio_data,
+            } => {
                 if let Some(user_arc) = self.keepalive_user_arc(addr).await {
                     let user_id = user_arc.id;
                     let room_id = user_arc.room_id.load(std::sync::atomic::Ordering::Relaxed);
-                    let pkt = protocol::new_talked_audio(user_id, &audio_data);
+                    let Some(config) = self.rooms.get(&room_id).map(|r| r.config()) else {
+                        return Ok(());
+                    };
+                    let codec = config.codec;
+                    if payload_type != codec.payload_type {
+                        let detail = format!(
+                            "room {room_id} expects payload type {}, got {payload_type}",
+                            codec.payload_type
+                        );
+                        self.reject_frame(
+                            addr,
+                            &user_arc,
+                            (protocol::TALK, seq),
+                            ErrorCode::PayloadType,
+                            &detail,
+                        )
+                        .await;
+                        return Ok(());
+                    }
+                    if !self
+                        .check_media(
+                            addr,
+                            &user_arc,
+                            (protocol::TALK, seq),
+                            buf.len(),
+                            audio_data.len(),
+                            &config,
+                        )
+                        .await
+                    {
+                        return Ok(());
+                    }
+                    let pkt = protocol::new_talked_audio(
+                        user_id,
+                        seq,
+                        timestamp,
+                        payload_type,
+                        &audio_data,
+                    );
                     self.batch_send_room(&pkt, room_id, Some(addr)).await;
                 }
             }
-            PacketType::Leave => {
+            PacketType::Parity {
+                seq,
+                count,
+                timestamp,
+                parity,
+            } => {
+                if let Some(user_arc) = self.keepalive_user_arc(addr).await
+                    && user_arc.capabilities & protocol::CAP_FEC != 0
+                {
+                    let room_id = user_arc.room_id.load(std::sync::atomic::Ordering::Relaxed);
+                    let Some(config) = self.rooms.get(&room_id).map(|r| r.config()) else {
+                        return Ok(());
+                    };
+                    // Parity is as big as the largest frame it covers plus a
+                    // length prefix, and counts against the same budget.
+                    if !self
+                        .check_media(
+                            addr,
+                            &user_arc,
+                            (protocol::PARITY, seq),
+                            buf.len(),
+                            parity.len().saturating_sub(2),
+                            &config,
+                        )
+                        .await
+                    {
+                        return Ok(());
+                    }
+                    let pkt =
+                        protocol::new_talked_parity(user_arc.id, seq, count, timestamp, &parity);
+                    self.batch_send_room_capable(&pkt, room_id, addr, protocol::CAP_FEC)
+                        .await;
+                }
+            }
+            PacketType::Leave { token } => {
+                if self.keepalive_session(addr, token).await.is_none() {
+                    return Ok(());
+                }
                 println!("User {addr} is leaving voluntarily.");
                 self.disconnect_user(addr, None).await;
                 return Ok(());
@@ -65,13 +164,78 @@ impl Server {
                 name,
                 hwid,
                 room_id,
+                version,
+                capabilities,
+                credential,
+                cookie,
             } => {
-                if self.users.contains_key(&addr) {
+                if let Some(user_arc) = self.users.get(&addr).map(|u| u.value().clone())
+                    && !user_arc
+                        .reconnecting
+                        .load(std::sync::atomic::Ordering::Relaxed)
+                {
+                    // A repeated JOIN means our ACCEPTED got lost.
+                    if user_arc.hwid == hwid {
+                        self.welcome_user(addr, &user_arc).await;
+                    }
+                    return Ok(());
+                }
+
+                // Legacy JOINs cannot carry a cookie, so they are turned away
+                // first. The answer is smaller than the request and touches
+                // no state, so the address needs no proof yet.
+                if version < protocol::MIN_PROTOCOL_VERSION {
+                    let reason = format!(
+                        "Unsupported protocol version {version}, server requires at least {}",
+                        protocol::MIN_PROTOCOL_VERSION
+                    );
+                    let pkt =
+                        protocol::new_disconnect(DisconnectReason::UnsupportedVersion, &reason);
+                    self.send_to(&pkt, addr).await?;
+                    return Ok(());
+                }
+
+                match cookie {
+                    Some(cookie) if self.verify_cookie(addr, &cookie, now) => {}
+                    _ => {
+                        let cookie = self.make_cookie(addr, now);
+                        self.send_to(&protocol::new_cookie(cookie), addr).await?;
+                        return Ok(());
+                    }
+                }
+
+                // Someone else lost its connection at this address and is
+                // not coming back to it.
+                if self.users.get(&addr).is_some_and(|u| u.hwid != hwid) {
+                    self.disconnect_user(addr, None).await;
+                }
+                if self.resume_lost_user(addr, &hwid).await {
                     return Ok(());
                 }
 
-                if let Err(e) = (self.on_join)(hwid.clone()).await {
-                    self.disconnect_user(addr, Some(&e.to_string())).await;
+                // Keeps the room from being deleted between the check and
+                // the user landing in it.
+                let _changing = self.room_changes.lock().await;
+                match self
+                    .check_room_entry((addr, &hwid), room_id, &credential, buf)
+                    .await
+                {
+                    Ok(Some(_)) => {}
+                    Ok(None) => return Ok(()),
+                    Err((code, detail)) => {
+                        self.send_error(addr, code, protocol::JOIN, 0, &detail)
+                            .await;
+                        return Ok(());
+                    }
+                }
+
+                if let Err(e) = (self.on_join)(hwid.to_string()).await {
+                    let reason = e
+                        .downcast_ref::<DisconnectReason>()
+                        .copied()
+                        .unwrap_or(DisconnectReason::Rejected);
+                    self.disconnect_user(addr, Some((reason, &e.to_string())))
+                        .await;
                     return Err(e);
                 };
 
@@ -79,22 +243,39 @@ impl Server {
                     id: self
                         .next_user_id
                         .fetch_add(1, std::sync::atomic::Ordering::Relaxed),
-                    name: name.clone(),
-                    hwid: hwid,
+                    name: name.to_string(),
+                    hwid: hwid.into_owned(),
+                    token: OsRng.next_u64(),
+                    addr: RwLock::new(addr),
+                    version: version.min(protocol::PROTOCOL_VERSION),
+                    capabilities: capabilities & SERVER_CAPABILITIES,
                     room_id: std::sync::atomic::AtomicU16::new(room_id),
                     last_seen: std::sync::atomic::AtomicU64::new(now + USER_TIMEOUT_SECS),
                     flags: 0,
                     consecutive_behind: std::sync::atomic::AtomicU8::new(0),
+                    reconnecting: std::sync::atomic::AtomicBool::new(false),
+                    reliable: std::sync::Mutex::new(ReliableSender::new()),
+                    subscriptions: std::sync::Mutex::new(BTreeSet::new()),
+                    rejected_frames: std::sync::atomic::AtomicU64::new(0),
+                    media: std::sync::Mutex::new(MediaLimiter::new(Instant::now())),
+                    control: std::sync::Mutex::new(TokenBucket::new(
+                        self.config.flood.session_control.per_sec,
+                        self.config.flood.session_control.burst,
+                        Instant::now(),
+                    )),
                 });
 
-                self.users.insert(addr, user.clone());
-                {
-                    let mut addrs = self.connected_addrs.write().await;
-                    addrs.push(addr);
+                self.lingering.remove(&addr);
+
+                if self.users.insert(addr, user.clone()).is_none() {
+                    self.hold_ip(addr);
                 }
+                self.sessions.insert(user.token, user.clone());
 
                 if let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone()) {
                     room_arc.users.insert(addr, user.clone());
+                    room_arc.subscribers.insert(user.id, user.clone());
+                    user.subscriptions.lock().unwrap().insert(room_id);
                     {
                         let mut snap = room_arc.joined_snapshot.write().await;
                         snap.push((user.id, user.name.clone()));
@@ -105,107 +286,260 @@ impl Server {
                     }
                 }
 
-                for room_ref in self.rooms.iter() {
-                    let room_arc = room_ref.value().clone();
-                    let users_snapshot = room_arc.joined_snapshot.read().await.clone();
-                    let pkt = protocol::new_joined(*room_ref.key(), users_snapshot);
-                    self.listener.send_to(&pkt, addr).await?;
-                }
-
-                let recipients: Vec<SocketAddr> = {
-                    let addrs = self.connected_addrs.read().await;
-                    addrs.iter().copied().collect()
-                };
-
-                self.broadcast_event(
-                    |seq| protocol::new_event(seq, true, room_id, user.id, &name),
-                    &recipients,
-                )
+                self.broadcast_event(room_id, |seq| {
+                    protocol::new_event(seq, EventKind::Joined, room_id, user.id, &name)
+                })
                 .await;
 
-                let _ = self
-                    .listener
-                    .send_to(
-                        &protocol::new_accepted(
-                            self.event_system.read().await.next_seq - 1,
-                            user.id,
-                        ),
-                        addr,
-                    )
-                    .await;
+                self.welcome_user(addr, &user).await;
             }
-            PacketType::Switch { room_id } => {
-                if let Some(user_arc) = self.keepalive_user_arc(addr).await {
-                    use std::sync::atomic::Ordering;
-                    let user_id = user_arc.id;
-                    let old_room_id = user_arc.room_id.load(Ordering::Relaxed);
-
-                    if old_room_id == room_id {
-                        return Ok(());
-                    }
+            PacketType::Switch {
+                room_id,
+                token,
+                request_id,
+                credential,
+            } => {
+                use std::sync::atomic::Ordering;
+                let Some(user_arc) = self.keepalive_session(addr, token).await else {
+                    self.send_error(addr, ErrorCode::NotJoined, protocol::SWITCH, request_id, "")
+                        .await;
+                    return Ok(());
+                };
+                let old_room_id = user_arc.room_id.load(Ordering::Relaxed);
 
-                    let Some(new_room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone())
-                    else {
-                        return Ok(());
-                    };
+                // A retried SWITCH whose answer got lost.
+                if old_room_id == room_id {
+                    self.send_reliable(&user_arc, &protocol::new_switched(request_id, room_id))
+                        .await;
+                    return Ok(());
+                }
 
-                    user_arc.room_id.store(room_id, Ordering::Relaxed);
-                    let user_name = user_arc.name.clone();
+                // Keeps the room from being deleted until the user is in it
+                // or in its line, where a delete finds them.
+                let _changing = self.room_changes.lock().await;
+                if let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone())
+                    && self.is_queued(user_arc.id, &room_arc)
+                {
+                    self.enqueue(&user_arc, room_id, &room_arc, request_id)
+                        .await;
+                    return Ok(());
+                }
+                // Asking for another room gives up any place in line.
+                self.dequeue(user_arc.id).await;
 
-                    if let Some(old_room_arc) =
-                        self.rooms.get(&old_room_id).map(|r| r.value().clone())
-                    {
-                        old_room_arc.users.remove(&addr);
-                        {
-                            let mut snap = old_room_arc.joined_snapshot.write().await;
-                            if let Some(pos) = snap.iter().position(|(id, _)| *id == user_id) {
-                                snap.swap_remove(pos);
-                            }
-                        }
+                let new_room_arc = match self
+                    .check_room_entry((addr, &user_arc.hwid), room_id, &credential, buf)
+                    .await
+                {
+                    Ok(Some(room_arc)) => room_arc,
+                    Ok(None) => return Ok(()),
+                    Err((ErrorCode::RoomFull, detail)) => {
+                        let room_arc = self.rooms.get(&room_id).map(|r| r.value().clone());
+                        if let Some(room_arc) = room_arc
+                            && self
+                                .enqueue(&user_arc, room_id, &room_arc, request_id)
+                                .await
                         {
-                            let mut addrs = old_room_arc.addr_list.write().await;
-                            if let Some(pos) = addrs.iter().position(|a| *a == addr) {
-                                addrs.swap_remove(pos);
-                            }
+                            return Ok(());
                         }
+                        let pkt = protocol::new_error(
+                            ErrorCode::RoomFull,
+                            protocol::SWITCH,
+                            request_id,
+                            &detail,
+                        );
+                        self.send_reliable(&user_arc, &pkt).await;
+                        return Ok(());
                     }
-
-                    new_room_arc.users.insert(addr, user_arc.clone());
-                    {
-                        let mut snap = new_room_arc.joined_snapshot.write().await;
-                        snap.push((user_id, user_name.clone()));
-                    }
-                    {
-                        let mut addrs = new_room_arc.addr_list.write().await;
-                        addrs.push(addr);
+                    Err((code, detail)) => {
+                        let pkt = protocol::new_error(code, protocol::SWITCH, request_id, &detail);
+                        self.send_reliable(&user_arc, &pkt).await;
+                        return Ok(());
                     }
+                };
 
-                    let joined_users = new_room_arc.joined_snapshot.read().await.clone();
-                    self.listener
-                        .send_to(&protocol::new_joined(room_id, joined_users), addr)
-                        .await?;
+                self.move_user(addr, &user_arc, room_id, &new_room_arc)
+                    .await;
+                self.send_reliable(&user_arc, &protocol::new_switched(request_id, room_id))
+                    .await;
+                self.admit_waiting(old_room_id).await;
+            }
+            PacketType::Ack {
+                token,
+                cumulative,
+                sack,
+            } => {
+                self.handle_ack(addr, token, cumulative, sack).await;
+            }
+            _ => { /* ignore others for now */ }
+        }
 
-                    let recipients: Vec<SocketAddr> = {
-                        let addrs = self.connected_addrs.read().await;
-                        addrs.iter().copied().collect()
-                    };
+        Ok(())
+    }
 
-                    self.broadcast_event(
-                        |seq| protocol::new_event(seq, false, old_room_id, user_id, &user_name),
-                        &recipients,
-                    )
+    /// Runs a TALK or PARITY packet past the user's media limits and returns
+    /// whether to forward it.
+    async fn check_media(
+        &self,
+        addr: SocketAddr,
+        user_arc: &User,
+        (request_type, seq): (u32, u16),
+        packet_len: usize,
+        frame_len: usize,
+        config: &RoomConfig,
+    ) -> bool {
+        let verdict =
+            user_arc
+                .media
+                .lock()
+                .unwrap()
+                .check(packet_len, frame_len, config, Instant::now());
+        match verdict {
+            MediaVerdict::Forward => true,
+            MediaVerdict::Drop(code) => {
+                let detail = match code {
+                    ErrorCode::FrameTooLarge => format!(
+                        "frame of {frame_len} bytes exceeds the room limit of {}",
+                        config.codec.max_frame_len
+                    ),
+                    _ => format!("exceeding the room bitrate of {} bit/s", config.max_bitrate),
+                };
+                self.reject_frame(addr, user_arc, (request_type, seq), code, &detail)
                     .await;
+                false
+            }
+            MediaVerdict::Disconnect => {
+                println!("User {addr} keeps exceeding the media limits.");
+                self.disconnect_user(
+                    addr,
+                    Some((
+                        DisconnectReason::Flooding,
+                        "Too many oversized or over-rate voice frames",
+                    )),
+                )
+                .await;
+                false
+            }
+        }
+    }
 
-                    self.broadcast_event(
-                        |seq| protocol::new_event(seq, true, room_id, user_id, &user_name),
-                        &recipients,
-                    )
-                    .await;
+    /// Drops a voice frame and tells the sender why, at most once every
+    /// `MEDIA_ERROR_INTERVAL` rejections.
+    async fn reject_frame(
+        &self,
+        addr: SocketAddr,
+        user_arc: &User,
+        (request_type, seq): (u32, u16),
+        code: ErrorCode,
+        detail: &str,
+    ) {
+        let rejected = user_arc
+            .rejected_frames
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        if rejected.is_multiple_of(MEDIA_ERROR_INTERVAL) {
+            self.send_error(addr, code, request_type, seq as u32, detail)
+                .await;
+        }
+    }
+
+    /// Moves a joined user into `room_id` and returns the room they left.
+    pub(crate) async fn move_user(
+        &self,
+        addr: SocketAddr,
+        user_arc: &Arc<User>,
+        room_id: u16,
+        new_room_arc: &Room,
+    ) -> u16 {
+        use std::sync::atomic::Ordering;
+        let user_id = user_arc.id;
+        let old_room_id = user_arc.room_id.swap(room_id, Ordering::Relaxed);
+        let user_name = user_arc.name.clone();
+
+        if let Some(old_room_arc) = self.rooms.get(&old_room_id).map(|r| r.value().clone()) {
+            old_room_arc.users.remove(&addr);
+            {
+                let mut snap = old_room_arc.joined_snapshot.write().await;
+                if let Some(pos) = snap.iter().position(|(id, _)| *id == user_id) {
+                    snap.swap_remove(pos);
+                }
+            }
+            {
+                let mut addrs = old_room_arc.addr_list.write().await;
+                if let Some(pos) = addrs.iter().position(|a| *a == addr) {
+                    addrs.swap_remove(pos);
                 }
             }
-            _ => { /* ignore others for now */ }
         }
 
-        Ok(())
+        new_room_arc.users.insert(addr, user_arc.clone());
+        {
+            let mut snap = new_room_arc.joined_snapshot.write().await;
+            snap.push((user_id, user_name.clone()));
+        }
+        {
+            let mut addrs = new_room_arc.addr_list.write().await;
+            addrs.push(addr);
+        }
+
+        self.subscribe(user_arc, room_id).await;
+
+        self.broadcast_event(old_room_id, |seq| {
+            protocol::new_event(seq, EventKind::Left, old_room_id, user_id, &user_name)
+        })
+        .await;
+
+        self.broadcast_event(room_id, |seq| {
+            protocol::new_event(seq, EventKind::Joined, room_id, user_id, &user_name)
+        })
+        .await;
+
+        old_room_id
+    }
+
+    /// Checks that the user `hwid` at `addr` may enter `room_id` right now.
+    /// `Ok(None)` means the credential is still being verified, and `packet`
+    /// will be handled again once it is.
+    pub(crate) async fn check_room_entry(
+        &self,
+        (addr, hwid): (SocketAddr, &str),
+        room_id: u16,
+        credential: &str,
+        packet: &[u8],
+    ) -> Result<Option<Arc<Room>>, (ErrorCode, String)> {
+        let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone()) else {
+            return Err((
+                ErrorCode::UnknownRoom,
+                format!("room {room_id} does not exist"),
+            ));
+        };
+        if let Err(e) = (self.on_enter_room)(hwid.to_string(), room_id).await {
+            return Err((ErrorCode::PermissionDenied, e.to_string()));
+        }
+        if !self.check_credential(addr, room_id, &room_arc, credential, packet)? {
+            return Ok(None);
+        }
+        // Nobody skips the line, even if a spot just opened up.
+        if let Some(max_users) = room_arc.config().max_users
+            && (room_arc.users.len() >= max_users as usize
+                || !room_arc.queue.lock().unwrap().is_empty())
+        {
+            return Err((
+                ErrorCode::RoomFull,
+                format!("{} is full ({max_users} users)", room_arc.name()),
+            ));
+        }
+        Ok(Some(room_arc))
+    }
+
+    pub(crate) async fn send_error(
+        &self,
+        addr: SocketAddr,
+        code: ErrorCode,
+        request_type: u32,
+        request_id: u32,
+        detail: &str,
+    ) {
+        let pkt = protocol::new_error(code, request_type, request_id, detail);
+        let _ = self.send_to(&pkt, addr).await;
     }
 }
@@ -1,8 +1,21 @@
 // src/server/mod.rs
+mod access;
+mod cookie;
+mod crypto;
 mod events;
+mod filter;
 mod handlers;
 mod model;
 mod net;
+mod queue;
+mod ratelimit;
+mod reliable;
+mod rooms;
 mod routine;
+mod store;
 
-pub use model::{Room, Server, User};
+pub use access::{Invite, hash_password, new_invite_code};
+pub use filter::DropStats;
+pub use model::{Budget, FloodConfig, Room, RoomConfig, Server, ServerConfig, User, Waiting};
+pub use ratelimit::{FloodGuard, MediaLimiter, MediaVerdict, TokenBucket, Traffic};
+pub use store::{MemoryRoomStore, RoomStore, StoreFuture};
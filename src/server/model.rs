@@ -1,37 +1,128 @@
 // src/server/model.rs
 use dashmap::DashMap;
-use std::collections::VecDeque;
+use rand_core::{OsRng, RngCore};
+use std::collections::{BTreeSet, VecDeque};
 use std::pin::Pin;
 use std::{
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
     sync::{
         Arc,
-        atomic::{AtomicU8, AtomicU16, AtomicU64},
+        atomic::{AtomicBool, AtomicU8, AtomicU16, AtomicU32, AtomicU64},
     },
 };
 use tokio::net::UdpSocket;
-use tokio::sync::RwLock;
+use tokio::sync::{RwLock, mpsc};
+
+use crate::protocol::{self, CodecPolicy, CryptoSession, Identity, Reassembler, ReliableSender};
+
+use super::access::{CredentialCheck, Failures, Invite, VerifiedCredential};
+use super::filter::DropCounters;
+use super::ratelimit::{FloodGuard, MediaLimiter, TokenBucket};
+use super::store::{MemoryRoomStore, RoomStore};
 
 pub const USER_TIMEOUT_SECS: u64 = 5;
 pub const ROUTINE_SLEEP_MS: u64 = 500;
+pub const RETRANSMIT_TICK_MS: u64 = 50;
 pub const MAX_EVENT_HISTORY: usize = 100;
 pub const MAX_CONSECUTIVE_BEHIND: u8 = 3;
+pub const COOKIE_LIFETIME_SECS: u64 = 10;
+pub const DEFAULT_RECONNECT_GRACE_SECS: u64 = 15;
+pub const DEFAULT_MTU: usize = 1200;
+pub const DEFAULT_MAX_BITRATE: u32 = 128_000;
+pub const DEFAULT_BLOCK_SECS: u64 = 60;
+pub const DEFAULT_FALLBACK_ROOM: u16 = 1;
+/// Per-address limiter state is forgotten after this long without traffic.
+pub const FLOOD_IDLE_SECS: u64 = 60;
+/// How much of the bitrate budget a user may spend at once.
+pub const MEDIA_BURST_MS: u64 = 250;
+pub const MAX_MEDIA_STRIKES: f64 = 100.0;
+pub const MEDIA_STRIKE_REFILL_PER_SEC: f64 = 10.0;
+/// Rejected frames between two ERROR replies to the same user.
+pub const MEDIA_ERROR_INTERVAL: u64 = 50;
+pub const INVITE_CODE_LEN: usize = 10;
+/// Wrong passwords or invite codes one address may try on a room before
+/// being locked out of it for `CREDENTIAL_LOCKOUT_SECS`.
+pub const MAX_CREDENTIAL_FAILURES: u32 = 5;
+pub const CREDENTIAL_LOCKOUT_SECS: u64 = 30;
+pub const SERVER_CAPABILITIES: u32 = protocol::CAP_ENCRYPTION | protocol::CAP_FEC;
 
 pub struct User {
     pub id: u64,
     pub name: String,
     pub hwid: String,
+    pub token: u64,
+    pub addr: RwLock<SocketAddr>,
+    pub version: u16,
+    pub capabilities: u32,
     pub last_seen: AtomicU64,
     pub room_id: AtomicU16,
     pub flags: u8,
     pub consecutive_behind: AtomicU8,
+    pub reconnecting: AtomicBool,
+    pub reliable: std::sync::Mutex<ReliableSender>,
+    pub subscriptions: std::sync::Mutex<BTreeSet<u16>>,
+    pub rejected_frames: AtomicU64,
+    pub media: std::sync::Mutex<MediaLimiter>,
+    /// Budget for the ACKs and ALIVEs this session sends.
+    pub control: std::sync::Mutex<TokenBucket>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RoomConfig {
+    pub codec: CodecPolicy,
+    /// Per-user limit in bits per second, TALK headers included. Zero
+    /// disables it.
+    pub max_bitrate: u32,
+    pub max_users: Option<u16>,
+    /// How many users may wait for a spot once the room is full. Only
+    /// SWITCH waits; a JOIN into a full room is refused. Zero disables
+    /// queueing.
+    pub max_queue: u16,
+    /// Argon2 hash of the room password, from `hash_password`. Invite codes
+    /// for the room are accepted as well.
+    pub password_hash: Option<String>,
+    /// Only invite codes let users in; the password is ignored.
+    pub invite_only: bool,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            codec: CodecPolicy::default(),
+            max_bitrate: DEFAULT_MAX_BITRATE,
+            max_users: None,
+            max_queue: 0,
+            password_hash: None,
+            invite_only: false,
+        }
+    }
 }
 
 pub struct Room {
-    pub name: String,
+    pub name: std::sync::RwLock<String>,
+    pub config: std::sync::RwLock<RoomConfig>,
     pub users: DashMap<SocketAddr, Arc<User>>,
     pub joined_snapshot: RwLock<Vec<(u64, String)>>,
     pub addr_list: RwLock<Vec<SocketAddr>>,
+    pub subscribers: DashMap<u64, Arc<User>>,
+    pub events: RwLock<EventSystem>,
+    pub queue: std::sync::Mutex<VecDeque<Waiting>>,
+}
+
+impl Room {
+    pub fn name(&self) -> String {
+        self.name.read().unwrap().clone()
+    }
+
+    pub fn config(&self) -> RoomConfig {
+        self.config.read().unwrap().clone()
+    }
+}
+
+/// A user waiting for a spot in a full room, with the SWITCH that asked.
+pub struct Waiting {
+    pub user: Arc<User>,
+    pub request_id: u32,
 }
 
 #[derive(Clone)]
@@ -45,6 +136,104 @@ pub struct EventSystem {
     pub history: VecDeque<StoredEvent>,
 }
 
+pub struct SecureSession {
+    pub crypto: CryptoSession,
+    pub addr: std::sync::Mutex<SocketAddr>,
+    pub last_seen: AtomicU64,
+}
+
+pub struct Lingering {
+    pub token: u64,
+    pub sender: ReliableSender,
+}
+
+/// A packets-per-second budget with room for short bursts.
+#[derive(Debug, Clone, Copy)]
+pub struct Budget {
+    pub per_sec: f64,
+    pub burst: f64,
+}
+
+/// Limits applied per source IP and per subnet (/24 for IPv4, /64 for IPv6)
+/// before any packet is parsed.
+#[derive(Debug, Clone)]
+pub struct FloodConfig {
+    pub ip_control: Budget,
+    pub ip_media: Budget,
+    pub ip_join: Budget,
+    pub subnet_control: Budget,
+    pub subnet_media: Budget,
+    pub subnet_join: Budget,
+    /// Malformed packets an address may send before it is blocked; the
+    /// allowance refills at `malformed.per_sec`.
+    pub malformed: Budget,
+    pub block_secs: u64,
+    /// ACKs and ALIVEs from a live session are charged to the session rather
+    /// than its address, so busy rooms and shared NATs stay within budget.
+    pub session_control: Budget,
+}
+
+impl Default for FloodConfig {
+    fn default() -> Self {
+        Self {
+            ip_control: Budget {
+                per_sec: 20.0,
+                burst: 40.0,
+            },
+            ip_media: Budget {
+                per_sec: 150.0,
+                burst: 300.0,
+            },
+            ip_join: Budget {
+                per_sec: 2.0,
+                burst: 10.0,
+            },
+            subnet_control: Budget {
+                per_sec: 200.0,
+                burst: 400.0,
+            },
+            subnet_media: Budget {
+                per_sec: 2000.0,
+                burst: 4000.0,
+            },
+            subnet_join: Budget {
+                per_sec: 10.0,
+                burst: 40.0,
+            },
+            malformed: Budget {
+                per_sec: 1.0,
+                burst: 20.0,
+            },
+            block_secs: DEFAULT_BLOCK_SECS,
+            session_control: Budget {
+                per_sec: 100.0,
+                burst: 200.0,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub reconnect_grace_secs: u64,
+    /// Largest packet sent before sealing; bigger ones are fragmented.
+    pub mtu: usize,
+    pub flood: FloodConfig,
+    /// Where the occupants of a deleted room are moved.
+    pub fallback_room: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            reconnect_grace_secs: DEFAULT_RECONNECT_GRACE_SECS,
+            mtu: DEFAULT_MTU,
+            flood: FloodConfig::default(),
+            fallback_room: DEFAULT_FALLBACK_ROOM,
+        }
+    }
+}
+
 type OnJoinFn = Arc<
     dyn Fn(String) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'static>>
         + Send
@@ -54,15 +243,42 @@ type OnJoinFn = Arc<
 type OnDisconnectFn =
     Arc<dyn Fn(String) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>> + Send + Sync>;
 
+type OnEnterRoomFn = Arc<
+    dyn Fn(String, u16) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'static>>
+        + Send
+        + Sync,
+>;
+
 pub struct Server {
     pub(crate) listener: Arc<UdpSocket>,
+    pub(crate) config: ServerConfig,
     pub(crate) rooms: DashMap<u16, Arc<Room>>,
     pub(crate) users: DashMap<SocketAddr, Arc<User>>,
-    pub(crate) connected_addrs: RwLock<Vec<SocketAddr>>,
+    pub(crate) sessions: DashMap<u64, Arc<User>>,
+    pub(crate) secure_sessions: DashMap<u64, Arc<SecureSession>>,
+    pub(crate) secure_addrs: DashMap<SocketAddr, u64>,
+    /// Entries in `users` and `secure_addrs` per source IP.
+    pub(crate) ip_refs: DashMap<IpAddr, usize>,
+    pub(crate) lingering: DashMap<SocketAddr, Lingering>,
     pub(crate) next_user_id: AtomicU64,
-    pub(crate) event_system: RwLock<EventSystem>,
+    pub(crate) cookie_secret: [u8; 32],
+    pub(crate) identity: Identity,
+    pub(crate) next_snapshot_id: AtomicU32,
+    pub(crate) next_frag_id: AtomicU32,
+    pub(crate) fragments: std::sync::Mutex<Reassembler<(SocketAddr, u32)>>,
+    pub(crate) flood: FloodGuard,
+    pub(crate) drops: DropCounters,
+    pub(crate) invites: DashMap<String, Invite>,
+    pub(crate) credential_failures: DashMap<(IpAddr, u16), Failures>,
+    pub(crate) credential_checks: DashMap<(SocketAddr, u16, String), CredentialCheck>,
+    pub(crate) verified_tx: mpsc::UnboundedSender<VerifiedCredential>,
+    pub(crate) verified_rx: tokio::sync::Mutex<mpsc::UnboundedReceiver<VerifiedCredential>>,
     pub(crate) on_join: OnJoinFn,
     pub(crate) on_disconnect: OnDisconnectFn,
+    pub(crate) on_enter_room: OnEnterRoomFn,
+    pub(crate) room_store: Arc<dyn RoomStore>,
+    /// Serializes room creation, renaming and deletion.
+    pub(crate) room_changes: tokio::sync::Mutex<()>,
 }
 
 impl Server {
@@ -83,33 +299,96 @@ impl Server {
         let on_disconnect: OnDisconnectFn =
             Arc::new(move |hwid: String| Box::pin(on_disconnect(hwid)));
 
+        let mut cookie_secret = [0u8; 32];
+        OsRng.fill_bytes(&mut cookie_secret);
+        let (verified_tx, verified_rx) = mpsc::unbounded_channel();
+
         let server = Self {
             listener,
+            config: ServerConfig::default(),
             rooms: DashMap::new(),
             users: DashMap::new(),
-            connected_addrs: RwLock::new(Vec::new()),
+            sessions: DashMap::new(),
+            secure_sessions: DashMap::new(),
+            secure_addrs: DashMap::new(),
+            ip_refs: DashMap::new(),
+            lingering: DashMap::new(),
             next_user_id: AtomicU64::new(1),
-            event_system: RwLock::new(EventSystem {
-                next_seq: 1,
-                history: VecDeque::with_capacity(MAX_EVENT_HISTORY),
-            }),
+            cookie_secret,
+            identity: Identity::generate(),
+            next_snapshot_id: AtomicU32::new(1),
+            next_frag_id: AtomicU32::new(1),
+            fragments: std::sync::Mutex::new(Reassembler::new()),
+            flood: FloodGuard::new(),
+            drops: DropCounters::default(),
+            invites: DashMap::new(),
+            credential_failures: DashMap::new(),
+            credential_checks: DashMap::new(),
+            verified_tx,
+            verified_rx: tokio::sync::Mutex::new(verified_rx),
             on_join,
             on_disconnect,
+            on_enter_room: Arc::new(|_, _| Box::pin(async { Ok(()) })),
+            room_store: Arc::new(MemoryRoomStore),
+            room_changes: tokio::sync::Mutex::new(()),
         };
 
         Ok(server)
     }
 
+    pub fn with_config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Replaces the identity generated at startup. Keep it across restarts
+    /// so clients can pin its public key.
+    pub fn with_identity(mut self, identity: Identity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Sets a check run with the user's hwid and the room id whenever a user
+    /// joins or switches rooms. An error refuses entry, and its message is
+    /// sent to the client.
+    pub fn with_room_permission<F, FR>(mut self, on_enter_room: F) -> Self
+    where
+        F: Fn(String, u16) -> FR + Send + Sync + 'static,
+        FR: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        self.on_enter_room =
+            Arc::new(move |hwid: String, room_id: u16| Box::pin(on_enter_room(hwid, room_id)));
+        self
+    }
+
+    /// Sets where `create_room`, `rename_room`, `set_room_limits` and
+    /// `delete_room` persist their changes.
+    pub fn with_room_store(mut self, store: impl RoomStore + 'static) -> Self {
+        self.room_store = Arc::new(store);
+        self
+    }
+
     pub fn add_room_with_id(&self, id: u16, name: &str) {
-        self.rooms.insert(id, Self::make_room(name));
+        self.add_room_with_config(id, name, RoomConfig::default());
+    }
+
+    pub fn add_room_with_config(&self, id: u16, name: &str, config: RoomConfig) {
+        self.rooms.insert(id, Self::make_room(name, config));
     }
 
-    fn make_room(name: &str) -> Arc<Room> {
+    fn make_room(name: &str, config: RoomConfig) -> Arc<Room> {
         Arc::new(Room {
-            name: name.to_string(),
+            name: std::sync::RwLock::new(name.to_string()),
+            config: std::sync::RwLock::new(config),
             users: DashMap::new(),
             joined_snapshot: RwLock::new(Vec::new()),
             addr_list: RwLock::new(Vec::new()),
+            subscribers: DashMap::new(),
+            events: RwLock::new(EventSystem {
+                next_seq: 1,
+                history: VecDeque::with_capacity(MAX_EVENT_HISTORY),
+            }),
+            queue: std::sync::Mutex::new(VecDeque::new()),
         })
     }
 }
@@ -1,22 +1,192 @@
 // src/server/net.rs
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::time::Instant;
 
+use dashmap::Entry;
+
+use crate::protocol::{self, DecodeError, PacketType};
 use crate::server::Server;
 
 impl Server {
     pub async fn listen(&self) {
+        let mut verified = self.verified_rx.lock().await;
         loop {
             let mut buf = [0u8; 1500];
-            let Ok((n, addr)) = self.listener.recv_from(&mut buf).await else {
+            let (n, addr) = tokio::select! {
+                received = self.listener.recv_from(&mut buf) => match received {
+                    Ok(received) => received,
+                    Err(_) => continue,
+                },
+                Some(verified) = verified.recv() => {
+                    self.finish_credential_check(verified).await;
+                    continue;
+                }
+            };
+            let now = Instant::now();
+            let allowed = match self.allow_session(addr, &buf[..n], now) {
+                Some(allowed) => allowed,
+                None => self
+                    .flood
+                    .allow(addr.ip(), &buf[..n], &self.config.flood, now),
+            };
+            if !allowed {
+                self.drops.rate_limited.fetch_add(1, Ordering::Relaxed);
+                continue;
+            }
+            let Err(e) = self.receive(addr, &buf[..n]).await else {
                 continue;
             };
-            let _ = self.handle(addr, &buf[..n]).await;
+            match e.downcast_ref::<DecodeError>() {
+                // Duplicates are normal on a lossy network.
+                Some(DecodeError::Replayed) | None => {}
+                Some(_) => {
+                    self.drops.malformed.fetch_add(1, Ordering::Relaxed);
+                    // Source addresses can be forged, so garbage must not get
+                    // the address of someone already connected blocked.
+                    if !self.ip_in_use(addr.ip())
+                        && self.flood.malformed(addr.ip(), &self.config.flood, now)
+                    {
+                        println!(
+                            "Blocking {} for {}s after repeated malformed packets",
+                            addr.ip(),
+                            self.config.flood.block_secs
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Charges an ACK or ALIVE to the session living at `addr`, or returns
+    /// `None` for anything the address budgets cover.
+    fn allow_session(&self, addr: SocketAddr, buf: &[u8], now: Instant) -> Option<bool> {
+        let packet_type = protocol::peek_client_packet_type(buf).ok()?;
+        if !matches!(packet_type, protocol::ACK | protocol::ALIVE) {
+            return None;
         }
+        let token = protocol::peek_session_key(buf)?;
+        let user_arc = self
+            .users
+            .get(&addr)
+            .filter(|u| u.token == token)?
+            .value()
+            .clone();
+        let allowed = user_arc.control.lock().unwrap().try_take(1.0, now);
+        Some(allowed)
+    }
+
+    fn ip_in_use(&self, ip: IpAddr) -> bool {
+        self.ip_refs.contains_key(&ip)
+    }
+
+    /// Counts a new entry for `addr` in `users` or `secure_addrs`.
+    pub(crate) fn hold_ip(&self, addr: SocketAddr) {
+        *self.ip_refs.entry(addr.ip()).or_insert(0) += 1;
+    }
+
+    /// Undoes `hold_ip` once the entry for `addr` is gone.
+    pub(crate) fn release_ip(&self, addr: SocketAddr) {
+        if let Entry::Occupied(mut refs) = self.ip_refs.entry(addr.ip()) {
+            *refs.get_mut() -= 1;
+            if *refs.get() == 0 {
+                refs.remove();
+            }
+        }
+    }
+
+    pub async fn receive(&self, addr: SocketAddr, buf: &[u8]) -> anyhow::Result<()> {
+        let packet_type = protocol::peek_client_packet_type(buf)?;
+        if !self.admit_type(addr, packet_type, protocol::peek_session_key(buf)) {
+            // A dead session still learns that it has to join again.
+            if matches!(
+                packet_type,
+                protocol::ALIVE | protocol::SUBSCRIBE | protocol::SWITCH
+            ) {
+                self.reject_unjoined(addr, &protocol::parse_from_client_packet(buf)?)
+                    .await;
+            }
+            return Ok(());
+        }
+        let packet = protocol::parse_from_client_packet(buf)?;
+        match packet {
+            PacketType::Handshake { public_key, cookie } => {
+                self.handle_handshake(addr, public_key, cookie).await
+            }
+            PacketType::Sealed {
+                session_id,
+                counter,
+                ciphertext,
+            } => {
+                let plaintext = self.open_sealed(addr, buf, session_id, counter, &ciphertext)?;
+                let packet = protocol::parse_from_client_packet(&plaintext)?;
+                self.reassemble(addr, &plaintext, packet).await
+            }
+            _ if self.secure_addrs.contains_key(&addr) => {
+                anyhow::bail!("plaintext packet from encrypted session {addr}")
+            }
+            packet => self.reassemble(addr, buf, packet).await,
+        }
+    }
+
+    async fn reassemble(
+        &self,
+        addr: SocketAddr,
+        buf: &[u8],
+        packet: PacketType<'_>,
+    ) -> anyhow::Result<()> {
+        let PacketType::Fragment {
+            frag_id,
+            index,
+            count,
+            chunk,
+        } = packet
+        else {
+            return self.handle(addr, buf, packet).await;
+        };
+
+        let whole = self.fragments.lock().unwrap().push(
+            (addr, frag_id),
+            index,
+            count,
+            &chunk,
+            Instant::now(),
+        );
+        let Some(whole) = whole else {
+            return Ok(());
+        };
+        let packet = protocol::parse_from_client_packet(&whole)?;
+        if matches!(packet, PacketType::Fragment { .. }) || !self.admit(addr, &packet) {
+            return Ok(());
+        }
+        self.handle(addr, &whole, packet).await
+    }
+
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub async fn send_to(&self, buf: &[u8], addr: SocketAddr) -> std::io::Result<usize> {
+        if buf.len() <= self.config.mtu {
+            let pkt = self.seal_for(addr, buf);
+            return self.listener.send_to(&pkt, addr).await;
+        }
+
+        let frag_id = self.next_frag_id.fetch_add(1, Ordering::Relaxed);
+        let mut sent = 0;
+        let fragments = protocol::fragment(buf, frag_id, self.config.mtu).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "packet too large to send")
+        })?;
+        for fragment in fragments {
+            let pkt = self.seal_for(addr, &fragment);
+            sent += self.listener.send_to(&pkt, addr).await?;
+        }
+        Ok(sent)
     }
 
     pub async fn batch_send(&self, buf: &[u8], addrs: &[SocketAddr]) {
         for addr in addrs.iter() {
-            let _ = self.listener.send_to(buf, addr).await;
+            let _ = self.send_to(buf, *addr).await;
         }
     }
 
@@ -29,15 +199,37 @@ impl Server {
             Some(skip) => {
                 for addr in addrs.iter() {
                     if *addr != skip {
-                        let _ = self.listener.send_to(buf, addr).await;
+                        let _ = self.send_to(buf, *addr).await;
                     }
                 }
             }
             None => {
                 for addr in addrs.iter() {
-                    let _ = self.listener.send_to(buf, addr).await;
+                    let _ = self.send_to(buf, *addr).await;
                 }
             }
         }
     }
+
+    /// Like `batch_send_room`, but only to users that negotiated `capability`.
+    pub async fn batch_send_room_capable(
+        &self,
+        buf: &[u8],
+        room_id: u16,
+        except: SocketAddr,
+        capability: u32,
+    ) {
+        let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone()) else {
+            return;
+        };
+        let addrs: Vec<SocketAddr> = room_arc
+            .users
+            .iter()
+            .filter(|u| *u.key() != except && u.value().capabilities & capability != 0)
+            .map(|u| *u.key())
+            .collect();
+        for addr in addrs {
+            let _ = self.send_to(buf, addr).await;
+        }
+    }
 }
@@ -0,0 +1,128 @@
+// src/server/queue.rs
+use std::sync::Arc;
+
+use crate::protocol;
+use crate::server::Server;
+
+use super::model::{Room, User, Waiting};
+
+impl Server {
+    /// Puts the user at the back of `room_id`'s queue, or refreshes their
+    /// request if they are already in it, and tells them their position.
+    /// Returns false when the queue is full.
+    pub(crate) async fn enqueue(
+        &self,
+        user_arc: &Arc<User>,
+        room_id: u16,
+        room_arc: &Room,
+        request_id: u32,
+    ) -> bool {
+        let position = {
+            let mut queue = room_arc.queue.lock().unwrap();
+            match queue.iter().position(|w| w.user.id == user_arc.id) {
+                Some(index) => {
+                    queue[index].request_id = request_id;
+                    index
+                }
+                None if queue.len() >= room_arc.config().max_queue as usize => return false,
+                None => {
+                    queue.push_back(Waiting {
+                        user: user_arc.clone(),
+                        request_id,
+                    });
+                    queue.len() - 1
+                }
+            }
+        };
+        let pkt = protocol::new_queued(request_id, room_id, position as u16 + 1);
+        self.send_reliable(user_arc, &pkt).await;
+        true
+    }
+
+    pub(crate) fn is_queued(&self, user_id: u64, room_arc: &Room) -> bool {
+        room_arc
+            .queue
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|w| w.user.id == user_id)
+    }
+
+    /// Takes the user out of every queue they are waiting in.
+    pub(crate) async fn dequeue(&self, user_id: u64) {
+        let rooms: Vec<(u16, Arc<Room>)> = self
+            .rooms
+            .iter()
+            .map(|r| (*r.key(), r.value().clone()))
+            .collect();
+        for (room_id, room_arc) in rooms {
+            let removed = {
+                let mut queue = room_arc.queue.lock().unwrap();
+                let before = queue.len();
+                queue.retain(|w| w.user.id != user_id);
+                queue.len() != before
+            };
+            if removed {
+                self.send_queue_positions(room_id, &room_arc).await;
+            }
+        }
+    }
+
+    /// Moves waiting users into `room_id` while it has spots. Every user
+    /// admitted frees a spot in the room they came from, so those rooms
+    /// are checked next.
+    pub(crate) async fn admit_waiting(&self, room_id: u16) {
+        let mut freed = vec![room_id];
+        while let Some(room_id) = freed.pop() {
+            let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone()) else {
+                continue;
+            };
+            let mut admitted = false;
+            while room_arc
+                .config()
+                .max_users
+                .is_none_or(|max_users| room_arc.users.len() < max_users as usize)
+            {
+                let Some(waiting) = room_arc.queue.lock().unwrap().pop_front() else {
+                    break;
+                };
+                admitted = true;
+                let addr = *waiting.user.addr.read().await;
+                let connected = self
+                    .users
+                    .get(&addr)
+                    .is_some_and(|u| Arc::ptr_eq(u.value(), &waiting.user));
+                if !connected {
+                    continue;
+                }
+                println!(
+                    "User {} admitted to room {room_id} from its queue",
+                    waiting.user.id
+                );
+                let old_room_id = self
+                    .move_user(addr, &waiting.user, room_id, &room_arc)
+                    .await;
+                let pkt = protocol::new_switched(waiting.request_id, room_id);
+                self.send_reliable(&waiting.user, &pkt).await;
+                freed.push(old_room_id);
+            }
+            if admitted {
+                self.send_queue_positions(room_id, &room_arc).await;
+            }
+        }
+    }
+
+    async fn send_queue_positions(&self, room_id: u16, room_arc: &Room) {
+        let waiting: Vec<(Arc<User>, u32)> = room_arc
+            .queue
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|w| (w.user.clone(), w.request_id))
+            .collect();
+        for (index, (user_arc, request_id)) in waiting.iter().enumerate() {
+            let pkt = protocol::new_queued(*request_id, room_id, index as u16 + 1);
+            self.send_reliable(user_arc, &pkt).await;
+        }
+    }
+}
@@ -0,0 +1,263 @@
+// src/server/ratelimit.rs
+use dashmap::DashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use crate::protocol::{self, ErrorCode};
+
+use super::model::{
+    Budget, FLOOD_IDLE_SECS, FloodConfig, MAX_MEDIA_STRIKES, MEDIA_BURST_MS,
+    MEDIA_STRIKE_REFILL_PER_SEC, RoomConfig,
+};
+
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A bucket that refills `rate` tokens per second up to `burst`, starting
+    /// full.
+    pub fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        Self {
+            rate,
+            burst,
+            tokens: burst,
+            last: now,
+        }
+    }
+
+    pub fn set_rate(&mut self, rate: f64, burst: f64) {
+        self.rate = rate;
+        self.burst = burst;
+        self.tokens = self.tokens.min(burst);
+    }
+
+    pub fn try_take(&mut self, cost: f64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
+    }
+}
+
+pub enum MediaVerdict {
+    Forward,
+    Drop(ErrorCode),
+    Disconnect,
+}
+
+/// Per-user enforcement of the room's frame size and bitrate. Every dropped
+/// frame costs a strike; strikes refill slowly, so only sustained abuse
+/// runs out of them.
+pub struct MediaLimiter {
+    bytes: Option<TokenBucket>,
+    strikes: TokenBucket,
+}
+
+impl MediaLimiter {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            bytes: None,
+            strikes: TokenBucket::new(MEDIA_STRIKE_REFILL_PER_SEC, MAX_MEDIA_STRIKES, now),
+        }
+    }
+
+    /// `packet_len` is the whole TALK packet, `frame_len` just its audio.
+    pub fn check(
+        &mut self,
+        packet_len: usize,
+        frame_len: usize,
+        config: &RoomConfig,
+        now: Instant,
+    ) -> MediaVerdict {
+        let verdict = if frame_len > config.codec.max_frame_len as usize {
+            ErrorCode::FrameTooLarge
+        } else if config.max_bitrate == 0 || self.take_bytes(packet_len, frame_len, config, now) {
+            return MediaVerdict::Forward;
+        } else {
+            ErrorCode::RateLimited
+        };
+        if self.strikes.try_take(1.0, now) {
+            MediaVerdict::Drop(verdict)
+        } else {
+            MediaVerdict::Disconnect
+        }
+    }
+
+    fn take_bytes(
+        &mut self,
+        packet_len: usize,
+        frame_len: usize,
+        config: &RoomConfig,
+        now: Instant,
+    ) -> bool {
+        let rate = config.max_bitrate as f64 / 8.0;
+        // Always leave room for a couple of maximum-size frames.
+        let largest = config.codec.max_frame_len as usize + packet_len - frame_len;
+        let burst = (rate * MEDIA_BURST_MS as f64 / 1000.0).max(2.0 * largest as f64);
+        let bytes = self
+            .bytes
+            .get_or_insert_with(|| TokenBucket::new(rate, burst, now));
+        bytes.set_rate(rate, burst);
+        bytes.try_take(packet_len as f64, now)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Traffic {
+    Control,
+    /// Voice and anything sealed, since the inner type is unknown until the
+    /// packet is opened.
+    Media,
+    /// Packets that make the server allocate state.
+    Join,
+}
+
+impl Traffic {
+    /// Classifies a packet from its header alone, without parsing it.
+    pub fn of(packet: &[u8]) -> Self {
+        let Some(packet_type) = packet.get(4..8) else {
+            return Traffic::Control;
+        };
+        match u32::from_be_bytes(packet_type.try_into().unwrap()) {
+            protocol::TALK | protocol::PARITY | protocol::SEALED | protocol::FRAGMENT => {
+                Traffic::Media
+            }
+            protocol::JOIN | protocol::HANDSHAKE => Traffic::Join,
+            _ => Traffic::Control,
+        }
+    }
+}
+
+struct Buckets {
+    control: TokenBucket,
+    media: TokenBucket,
+    join: TokenBucket,
+    last_seen: Instant,
+}
+
+impl Buckets {
+    fn new(control: Budget, media: Budget, join: Budget, now: Instant) -> Self {
+        Self {
+            control: TokenBucket::new(control.per_sec, control.burst, now),
+            media: TokenBucket::new(media.per_sec, media.burst, now),
+            join: TokenBucket::new(join.per_sec, join.burst, now),
+            last_seen: now,
+        }
+    }
+
+    fn take(&mut self, traffic: Traffic, now: Instant) -> bool {
+        self.last_seen = now;
+        match traffic {
+            Traffic::Control => self.control.try_take(1.0, now),
+            Traffic::Media => self.media.try_take(1.0, now),
+            Traffic::Join => self.join.try_take(1.0, now),
+        }
+    }
+}
+
+struct Source {
+    buckets: Buckets,
+    malformed: TokenBucket,
+}
+
+/// Packet budgets per source address and subnet, checked before parsing,
+/// plus a temporary blocklist for addresses that keep sending garbage.
+#[derive(Default)]
+pub struct FloodGuard {
+    sources: DashMap<IpAddr, Source>,
+    subnets: DashMap<IpAddr, Buckets>,
+    blocked: DashMap<IpAddr, Instant>,
+}
+
+impl FloodGuard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn allow(&self, ip: IpAddr, packet: &[u8], config: &FloodConfig, now: Instant) -> bool {
+        if self.is_blocked(ip, now) {
+            return false;
+        }
+        let traffic = Traffic::of(packet);
+        let mut source = self.source(ip, config, now);
+        if !source.buckets.take(traffic, now) {
+            return false;
+        }
+        drop(source);
+
+        self.subnets
+            .entry(subnet(ip))
+            .or_insert_with(|| {
+                Buckets::new(
+                    config.subnet_control,
+                    config.subnet_media,
+                    config.subnet_join,
+                    now,
+                )
+            })
+            .take(traffic, now)
+    }
+
+    /// Counts a malformed packet against `ip` and returns true if that got
+    /// it blocked.
+    pub fn malformed(&self, ip: IpAddr, config: &FloodConfig, now: Instant) -> bool {
+        let mut source = self.source(ip, config, now);
+        if source.malformed.try_take(1.0, now) {
+            return false;
+        }
+        source.malformed = TokenBucket::new(config.malformed.per_sec, config.malformed.burst, now);
+        drop(source);
+        self.blocked
+            .insert(ip, now + Duration::from_secs(config.block_secs));
+        true
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr, now: Instant) -> bool {
+        self.blocked.get(&ip).is_some_and(|until| *until > now)
+    }
+
+    pub fn blocked(&self) -> usize {
+        self.blocked.len()
+    }
+
+    pub fn purge(&self, now: Instant) {
+        let idle = Duration::from_secs(FLOOD_IDLE_SECS);
+        self.blocked.retain(|_, until| *until > now);
+        self.sources
+            .retain(|_, source| now.saturating_duration_since(source.buckets.last_seen) < idle);
+        self.subnets
+            .retain(|_, buckets| now.saturating_duration_since(buckets.last_seen) < idle);
+    }
+
+    fn source(
+        &self,
+        ip: IpAddr,
+        config: &FloodConfig,
+        now: Instant,
+    ) -> dashmap::mapref::one::RefMut<'_, IpAddr, Source> {
+        self.sources.entry(ip).or_insert_with(|| Source {
+            buckets: Buckets::new(config.ip_control, config.ip_media, config.ip_join, now),
+            malformed: TokenBucket::new(config.malformed.per_sec, config.malformed.burst, now),
+        })
+    }
+}
+
+/// The /24 of an IPv4 address or the /64 of an IPv6 one.
+fn subnet(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(v4.to_bits() & 0xffff_ff00)),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => subnet(IpAddr::V4(v4)),
+            None => IpAddr::V6(Ipv6Addr::from(v6.to_bits() & !0u128 << 64)),
+        },
+    }
+}
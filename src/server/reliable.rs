@@ -0,0 +1,93 @@
+// src/server/reliable.rs
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::server::Server;
+
+use super::model::{Lingering, RETRANSMIT_TICK_MS, User};
+
+impl Server {
+    pub(crate) async fn send_reliable(&self, user_arc: &User, pkt: &[u8]) {
+        let wrapped = user_arc.reliable.lock().unwrap().wrap(pkt, Instant::now());
+        let addr = *user_arc.addr.read().await;
+        let _ = self.send_to(&wrapped, addr).await;
+    }
+
+    pub(crate) async fn handle_ack(
+        &self,
+        addr: SocketAddr,
+        token: u64,
+        cumulative: u32,
+        sack: u64,
+    ) {
+        if let Some(user_arc) = self.keepalive_session(addr, token).await {
+            user_arc.reliable.lock().unwrap().on_ack(cumulative, sack);
+            return;
+        }
+        if let Some(mut lingering) = self.lingering.get_mut(&addr)
+            && lingering.token == token
+        {
+            lingering.sender.on_ack(cumulative, sack);
+        }
+    }
+
+    /// Keeps retransmitting a disconnected user's unacknowledged packets,
+    /// including the DISCONNECT itself, until they are acknowledged or run
+    /// out of retries.
+    pub(crate) fn linger(&self, addr: SocketAddr, user_arc: &User) {
+        let sender = std::mem::take(&mut *user_arc.reliable.lock().unwrap());
+        if sender.in_flight() > 0 {
+            self.lingering.insert(
+                addr,
+                Lingering {
+                    token: user_arc.token,
+                    sender,
+                },
+            );
+        }
+    }
+
+    pub async fn retransmit_routine(&self) {
+        loop {
+            let now = Instant::now();
+
+            let users: Vec<Arc<User>> = self.users.iter().map(|u| u.value().clone()).collect();
+            for user_arc in users {
+                let due = user_arc.reliable.lock().unwrap().due(now);
+                if due.is_empty() {
+                    continue;
+                }
+                let addr = *user_arc.addr.read().await;
+                for pkt in due {
+                    let _ = self.send_to(&pkt, addr).await;
+                }
+            }
+
+            let mut lingering: Vec<(SocketAddr, Vec<Vec<u8>>)> = Vec::new();
+            let mut finished: Vec<SocketAddr> = Vec::new();
+            for mut entry in self.lingering.iter_mut() {
+                let due = entry.sender.due(now);
+                if entry.sender.in_flight() == 0 {
+                    finished.push(*entry.key());
+                }
+                if !due.is_empty() {
+                    lingering.push((*entry.key(), due));
+                }
+            }
+            for (addr, due) in lingering {
+                for pkt in due {
+                    let _ = self.send_to(&pkt, addr).await;
+                }
+            }
+            for addr in finished {
+                self.lingering.remove(&addr);
+                if !self.users.contains_key(&addr) {
+                    self.remove_secure_session(addr);
+                }
+            }
+
+            tokio::time::sleep(Duration::from_millis(RETRANSMIT_TICK_MS)).await;
+        }
+    }
+}
@@ -0,0 +1,149 @@
+// src/server/rooms.rs
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::protocol::{self, ErrorCode, RoomChange};
+use crate::server::Server;
+
+use super::model::{RoomConfig, User};
+
+impl Server {
+    /// Creates a room under the lowest free id, persists it and announces it
+    /// to every user.
+    pub async fn create_room(&self, name: &str, config: RoomConfig) -> anyhow::Result<u16> {
+        let _changing = self.room_changes.lock().await;
+        self.check_room_name(name)?;
+        let room_id = (1..=u16::MAX)
+            .find(|id| !self.rooms.contains_key(id))
+            .ok_or_else(|| anyhow::anyhow!("no free room ids left"))?;
+
+        self.room_store.create_room(room_id, name, &config).await?;
+        self.add_room_with_config(room_id, name, config);
+        println!("Created room {room_id}: {name}");
+        self.broadcast_room_change(RoomChange::Created, room_id, name)
+            .await;
+        Ok(room_id)
+    }
+
+    pub async fn rename_room(&self, room_id: u16, name: &str) -> anyhow::Result<()> {
+        let _changing = self.room_changes.lock().await;
+        let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone()) else {
+            anyhow::bail!("room {room_id} does not exist");
+        };
+        if room_arc.name() == name {
+            return Ok(());
+        }
+        self.check_room_name(name)?;
+
+        self.room_store.rename_room(room_id, name).await?;
+        *room_arc.name.write().unwrap() = name.to_string();
+        println!("Renamed room {room_id} to {name}");
+        self.broadcast_room_change(RoomChange::Renamed, room_id, name)
+            .await;
+        Ok(())
+    }
+
+    /// Changes how many users `room_id` holds and how many may wait for it.
+    /// Nobody already inside or waiting loses their place, and waiting
+    /// users are let in if the room grew.
+    pub async fn set_room_limits(
+        &self,
+        room_id: u16,
+        max_users: Option<u16>,
+        max_queue: u16,
+    ) -> anyhow::Result<()> {
+        let _changing = self.room_changes.lock().await;
+        let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone()) else {
+            anyhow::bail!("room {room_id} does not exist");
+        };
+
+        self.room_store
+            .set_room_limits(room_id, max_users, max_queue)
+            .await?;
+        {
+            let mut config = room_arc.config.write().unwrap();
+            config.max_users = max_users;
+            config.max_queue = max_queue;
+        }
+        let users = max_users.map_or("unlimited".to_string(), |n| n.to_string());
+        println!("Room {room_id} now holds {users} users with {max_queue} waiting");
+        self.admit_waiting(room_id).await;
+        Ok(())
+    }
+
+    /// Deletes a room and moves everyone in it to the fallback room, even
+    /// past its user limit. Users waiting for the room are refused.
+    pub async fn delete_room(&self, room_id: u16) -> anyhow::Result<()> {
+        let _changing = self.room_changes.lock().await;
+        let fallback = self.config.fallback_room;
+        anyhow::ensure!(
+            room_id != fallback,
+            "room {room_id} is the fallback room and cannot be deleted"
+        );
+        anyhow::ensure!(
+            self.rooms.contains_key(&room_id),
+            "room {room_id} does not exist"
+        );
+        let Some(fallback_arc) = self.rooms.get(&fallback).map(|r| r.value().clone()) else {
+            anyhow::bail!("fallback room {fallback} does not exist");
+        };
+
+        self.room_store.delete_room(room_id).await?;
+        let Some((_, room_arc)) = self.rooms.remove(&room_id) else {
+            anyhow::bail!("room {room_id} does not exist");
+        };
+        self.invites.retain(|_, invite| invite.room_id != room_id);
+        let name = room_arc.name();
+        println!("Deleted room {room_id}: {name}");
+        self.broadcast_room_change(RoomChange::Deleted, room_id, &name)
+            .await;
+
+        let waiting: Vec<_> = room_arc.queue.lock().unwrap().drain(..).collect();
+        for waiting in waiting {
+            let pkt = protocol::new_error(
+                ErrorCode::UnknownRoom,
+                protocol::SWITCH,
+                waiting.request_id,
+                &format!("{name} was deleted"),
+            );
+            self.send_reliable(&waiting.user, &pkt).await;
+        }
+
+        for subscriber in room_arc.subscribers.iter() {
+            subscriber.subscriptions.lock().unwrap().remove(&room_id);
+        }
+
+        let occupants: Vec<(SocketAddr, Arc<User>)> = room_arc
+            .users
+            .iter()
+            .map(|u| (*u.key(), u.value().clone()))
+            .collect();
+        for (addr, user_arc) in occupants {
+            self.move_user(addr, &user_arc, fallback, &fallback_arc)
+                .await;
+            // Request id 0 is never used by clients, so this reads as a
+            // move the user did not ask for.
+            self.send_reliable(&user_arc, &protocol::new_switched(0, fallback))
+                .await;
+        }
+        Ok(())
+    }
+
+    fn check_room_name(&self, name: &str) -> anyhow::Result<()> {
+        anyhow::ensure!(!name.is_empty(), "room name cannot be empty");
+        anyhow::ensure!(!name.contains('\0'), "room name cannot contain NUL");
+        anyhow::ensure!(
+            !self.rooms.iter().any(|r| r.value().name() == name),
+            "a room named {name} already exists"
+        );
+        Ok(())
+    }
+
+    async fn broadcast_room_change(&self, change: RoomChange, room_id: u16, name: &str) {
+        let pkt = protocol::new_room_changed(change, room_id, name);
+        let users: Vec<Arc<User>> = self.users.iter().map(|u| u.value().clone()).collect();
+        for user_arc in users {
+            self.send_reliable(&user_arc, &pkt).await;
+        }
+    }
+}
@@ -1,9 +1,9 @@
 // src/server/routine.rs
 use std::net::SocketAddr;
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::protocol;
+use crate::protocol::{self, DisconnectReason, EventKind};
 use crate::server::Server;
 
 use super::model::{ROUTINE_SLEEP_MS, USER_TIMEOUT_SECS, User};
@@ -12,21 +12,41 @@ impl Server {
     pub async fn routine(&self) -> anyhow::Result<()> {
         loop {
             let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+            let mut to_suspend: Vec<SocketAddr> = Vec::new();
             let mut to_remove: Vec<SocketAddr> = Vec::new();
+            let grace = self.config.reconnect_grace_secs;
 
             for entry in self.users.iter() {
                 let user = entry.value();
                 let addr = *entry.key();
                 let expires_at = user.last_seen.load(std::sync::atomic::Ordering::Relaxed);
                 if expires_at <= now {
-                    to_remove.push(addr);
+                    if grace > 0 && !user.reconnecting.load(std::sync::atomic::Ordering::Relaxed) {
+                        to_suspend.push(addr);
+                    } else {
+                        to_remove.push(addr);
+                    }
                 }
             }
 
+            self.purge_secure_sessions(now);
+            self.purge_credentials(now);
+            self.fragments.lock().unwrap().purge(Instant::now());
+            self.flood.purge(Instant::now());
+
+            for addr in to_suspend.drain(..) {
+                println!("User {addr} lost connection, waiting {grace}s for reconnect");
+                self.suspend_user(addr, now + grace).await;
+            }
+
             if !to_remove.is_empty() {
                 for addr in to_remove.drain(..) {
                     println!("Removing inactive user {addr}");
-                    self.disconnect_user(addr, Some("Inactivity timeout")).await;
+                    self.disconnect_user(
+                        addr,
+                        Some((DisconnectReason::Timeout, "Inactivity timeout")),
+                    )
+                    .await;
                 }
             }
             tokio::time::sleep(Duration::from_millis(ROUTINE_SLEEP_MS)).await;
@@ -34,34 +54,188 @@ impl Server {
     }
 
     pub async fn keepalive_user_arc(&self, addr: SocketAddr) -> Option<Arc<User>> {
-        if let Some(user_entry) = self.users.get(&addr) {
-            let user_arc = user_entry.value().clone();
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            user_arc.last_seen.store(
-                now + USER_TIMEOUT_SECS,
-                std::sync::atomic::Ordering::Relaxed,
-            );
-            return Some(user_arc);
+        let user_arc = self.users.get(&addr).map(|u| u.value().clone())?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        user_arc.last_seen.store(
+            now + USER_TIMEOUT_SECS,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+        if user_arc
+            .reconnecting
+            .swap(false, std::sync::atomic::Ordering::Relaxed)
+        {
+            println!("User {addr} reconnected");
+            self.broadcast_presence(&user_arc, EventKind::Reconnected)
+                .await;
+        }
+        Some(user_arc)
+    }
+
+    async fn suspend_user(&self, addr: SocketAddr, grace_until: u64) {
+        use std::sync::atomic::Ordering;
+
+        let Some(user_arc) = self.users.get(&addr).map(|u| u.value().clone()) else {
+            return;
+        };
+        user_arc.reconnecting.store(true, Ordering::Relaxed);
+        user_arc.last_seen.store(grace_until, Ordering::Relaxed);
+        self.broadcast_presence(&user_arc, EventKind::ConnectionLost)
+            .await;
+    }
+
+    pub(crate) async fn resume_user(&self, addr: SocketAddr, user_arc: &User) {
+        self.keepalive_user_arc(addr).await;
+        self.welcome_user(addr, user_arc).await;
+    }
+
+    /// Resumes the user `hwid` at `addr` if it lost its connection and its
+    /// grace window is still open, wherever it comes back from.
+    pub(crate) async fn resume_lost_user(&self, addr: SocketAddr, hwid: &str) -> bool {
+        use std::sync::atomic::Ordering;
+
+        let Some(user_arc) = self
+            .users
+            .iter()
+            .find(|u| u.hwid == hwid && u.reconnecting.load(Ordering::Relaxed))
+            .map(|u| u.value().clone())
+        else {
+            return false;
+        };
+        let old_addr = *user_arc.addr.read().await;
+        if old_addr != addr {
+            // Whatever session the new address brought replaces the old one.
+            self.remove_secure_session(old_addr);
+            self.migrate_user(&user_arc, old_addr, addr).await;
+        }
+        println!("User {} resumed from {addr}", user_arc.id);
+        self.resume_user(addr, &user_arc).await;
+        true
+    }
+
+    pub(crate) async fn welcome_user(&self, addr: SocketAddr, user_arc: &User) {
+        let subscriptions = user_arc.subscriptions.lock().unwrap().clone();
+        for room_id in subscriptions {
+            if let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone()) {
+                self.send_room_state(user_arc, room_id, &room_arc).await;
+            }
+        }
+
+        let pkt = protocol::new_accepted(
+            user_arc.id,
+            user_arc.version,
+            user_arc.capabilities,
+            user_arc.token,
+        );
+        let _ = self.send_to(&pkt, addr).await;
+    }
+
+    async fn broadcast_presence(&self, user_arc: &User, kind: EventKind) {
+        let room_id = user_arc.room_id.load(std::sync::atomic::Ordering::Relaxed);
+        self.broadcast_event(room_id, |seq| {
+            protocol::new_event(seq, kind, room_id, user_arc.id, &user_arc.name)
+        })
+        .await;
+    }
+
+    pub async fn keepalive_session(&self, addr: SocketAddr, token: u64) -> Option<Arc<User>> {
+        let user_arc = self.sessions.get(&token).map(|u| u.value().clone())?;
+        let old_addr = *user_arc.addr.read().await;
+        if old_addr != addr {
+            // Encrypted users may only move through their sealed session.
+            if self.users.contains_key(&addr) || self.secure_addrs.contains_key(&old_addr) {
+                return None;
+            }
+            self.migrate_user(&user_arc, old_addr, addr).await;
+        }
+        self.keepalive_user_arc(addr).await
+    }
+
+    async fn migrate_user(&self, user_arc: &Arc<User>, old_addr: SocketAddr, new_addr: SocketAddr) {
+        use std::sync::atomic::Ordering;
+
+        println!("User {} moved from {old_addr} to {new_addr}", user_arc.id);
+
+        *user_arc.addr.write().await = new_addr;
+        if self.users.remove(&old_addr).is_some() {
+            self.release_ip(old_addr);
+        }
+        if self.users.insert(new_addr, user_arc.clone()).is_none() {
+            self.hold_ip(new_addr);
+        }
+        self.move_secure_session(old_addr, new_addr);
+
+        let room_id = user_arc.room_id.load(Ordering::Relaxed);
+        if let Some(room_arc) = self.rooms.get(&room_id).map(|r| r.value().clone()) {
+            room_arc.users.remove(&old_addr);
+            room_arc.users.insert(new_addr, user_arc.clone());
+            let mut addrs = room_arc.addr_list.write().await;
+            if let Some(pos) = addrs.iter().position(|a| *a == old_addr) {
+                addrs[pos] = new_addr;
+            }
+        }
+    }
+
+    /// Disconnects the user with id `user_id`, returning whether one was
+    /// connected.
+    pub async fn kick_user(&self, user_id: u64, detail: &str) -> bool {
+        let Some(addr) = self
+            .users
+            .iter()
+            .find(|u| u.id == user_id)
+            .map(|u| *u.key())
+        else {
+            return false;
+        };
+        self.disconnect_user(addr, Some((DisconnectReason::Kicked, detail)))
+            .await;
+        true
+    }
+
+    /// Tells every connected user the server is going away.
+    pub async fn shutdown(&self) {
+        let addrs: Vec<SocketAddr> = self.users.iter().map(|u| *u.key()).collect();
+        for addr in addrs {
+            self.disconnect_user(
+                addr,
+                Some((DisconnectReason::Shutdown, "Server shutting down")),
+            )
+            .await;
         }
-        None
     }
 
-    pub async fn disconnect_user(&self, addr: SocketAddr, notify_reason: Option<&str>) {
+    pub async fn disconnect_user(
+        &self,
+        addr: SocketAddr,
+        notify_reason: Option<(DisconnectReason, &str)>,
+    ) {
         use std::sync::atomic::Ordering;
 
-        if let Some(reason) = notify_reason {
-            let pkt = protocol::new_disconnect(reason);
-            let _ =
-                tokio::time::timeout(Duration::from_millis(50), self.listener.send_to(&pkt, addr))
+        let user_arc = self.users.get(&addr).map(|u| u.value().clone());
+        if let Some((reason, detail)) = notify_reason {
+            let pkt = protocol::new_disconnect(reason, detail);
+            match &user_arc {
+                Some(user_arc) => {
+                    let _ = tokio::time::timeout(
+                        Duration::from_millis(50),
+                        self.send_reliable(user_arc, &pkt),
+                    )
                     .await;
+                }
+                None => {
+                    let _ =
+                        tokio::time::timeout(Duration::from_millis(50), self.send_to(&pkt, addr))
+                            .await;
+                }
+            }
         }
 
         let Some((_, user_arc)) = self.users.remove(&addr) else {
             return;
         };
+        self.release_ip(addr);
 
         let room_id = user_arc.room_id.load(Ordering::Relaxed);
         let user_id = user_arc.id;
@@ -83,28 +257,21 @@ impl Server {
             }
         }
 
-        {
-            let mut addrs = self.connected_addrs.write().await;
-            if let Some(pos) = addrs.iter().position(|a| *a == addr) {
-                addrs.swap_remove(pos);
-            }
-        }
-
-        let recipients: Vec<SocketAddr> = {
-            let addrs = self.connected_addrs.read().await;
-            addrs.iter().copied().collect()
-        };
-
-        self.broadcast_event(
-            |seq| protocol::new_event(seq, false, room_id, user_id, &user_name),
-            &recipients,
-        )
+        self.unsubscribe_all(&user_arc);
+        self.dequeue(user_id).await;
+        self.broadcast_event(room_id, |seq| {
+            protocol::new_event(seq, EventKind::Left, room_id, user_id, &user_name)
+        })
         .await;
+        self.admit_waiting(room_id).await;
+
+        self.sessions.remove(&user_arc.token);
+        self.linger(addr, &user_arc);
+        if !self.lingering.contains_key(&addr) {
+            self.remove_secure_session(addr);
+        }
 
-        if self.users.len() == 0 {
-            let mut event = self.event_system.write().await;
-            event.next_seq = 1;
-            event.history.clear();
+        if self.users.is_empty() {
             self.next_user_id.store(0, Ordering::Relaxed);
         }
 
@@ -0,0 +1,51 @@
+// src/server/store.rs
+use std::pin::Pin;
+
+use super::model::RoomConfig;
+
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>;
+
+/// Where rooms created, renamed, resized or deleted at runtime are
+/// persisted. Each
+/// call happens before the server applies the change, so a failing store
+/// leaves the room as it was.
+pub trait RoomStore: Send + Sync {
+    fn create_room<'a>(
+        &'a self,
+        room_id: u16,
+        name: &'a str,
+        config: &'a RoomConfig,
+    ) -> StoreFuture<'a, ()>;
+
+    fn rename_room<'a>(&'a self, room_id: u16, name: &'a str) -> StoreFuture<'a, ()>;
+
+    fn set_room_limits(
+        &self,
+        room_id: u16,
+        max_users: Option<u16>,
+        max_queue: u16,
+    ) -> StoreFuture<'_, ()>;
+
+    fn delete_room(&self, room_id: u16) -> StoreFuture<'_, ()>;
+}
+
+/// Keeps nothing: changes last until the server stops.
+pub struct MemoryRoomStore;
+
+impl RoomStore for MemoryRoomStore {
+    fn create_room<'a>(&'a self, _: u16, _: &'a str, _: &'a RoomConfig) -> StoreFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn rename_room<'a>(&'a self, _: u16, _: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn set_room_limits(&self, _: u16, _: Option<u16>, _: u16) -> StoreFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn delete_room(&self, _: u16) -> StoreFuture<'_, ()> {
+        Box::pin(async { Ok(()) })
+    }
+}
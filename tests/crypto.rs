@@ -0,0 +1,78 @@
+use pigeonvc2::protocol::{self, DecodeError, Identity, KeyExchange, ReplayWindow};
+
+fn session_pair() -> (protocol::CryptoSession, protocol::CryptoSession) {
+    let identity = Identity::generate();
+    let client = KeyExchange::new();
+    let server = KeyExchange::new();
+    let client_public = client.public_key();
+    let server_public = server.public_key();
+    (
+        client.finish_client(server_public, identity.public_key(), 42),
+        server.finish_server(client_public, &identity, 42),
+    )
+}
+
+#[test]
+fn sealed_packets_open_in_both_directions() {
+    let (client, server) = session_pair();
+
+    let talk = protocol::new_talk(0, 0, protocol::PAYLOAD_OPUS, b"voice");
+    let sealed = client.seal(&talk);
+    assert_ne!(sealed[8..], talk[..]);
+    assert_eq!(server.open(&sealed).unwrap(), talk);
+
+    let pong = protocol::new_pong();
+    assert_eq!(client.open(&server.seal(&pong)).unwrap(), pong);
+}
+
+#[test]
+fn tampered_and_reflected_packets_are_rejected() {
+    let (client, server) = session_pair();
+
+    let mut sealed = client.seal(&protocol::new_ping());
+    let last = sealed.len() - 1;
+    sealed[last] ^= 1;
+    assert_eq!(server.open(&sealed), Err(DecodeError::Unauthenticated));
+
+    let sealed = client.seal(&protocol::new_ping());
+    assert_eq!(client.open(&sealed), Err(DecodeError::Unauthenticated));
+}
+
+#[test]
+fn impostor_identity_cannot_open_packets() {
+    let real = Identity::generate();
+    let impostor = Identity::generate();
+    let client = KeyExchange::new();
+    let server = KeyExchange::new();
+    let client_public = client.public_key();
+    let server_public = server.public_key();
+    let client = client.finish_client(server_public, real.public_key(), 42);
+    let server = server.finish_server(client_public, &impostor, 42);
+
+    let sealed = client.seal(&protocol::new_ping());
+    assert_eq!(server.open(&sealed), Err(DecodeError::Unauthenticated));
+}
+
+#[test]
+fn replayed_packets_are_rejected() {
+    let (client, server) = session_pair();
+
+    let first = client.seal(&protocol::new_ping());
+    let second = client.seal(&protocol::new_ping());
+    assert!(server.open(&second).is_ok());
+    assert!(server.open(&first).is_ok());
+    assert_eq!(server.open(&first), Err(DecodeError::Replayed));
+    assert_eq!(server.open(&second), Err(DecodeError::Replayed));
+}
+
+#[test]
+fn replay_window_slides() {
+    let mut window = ReplayWindow::default();
+    assert!(window.accept(10));
+    assert!(window.accept(8));
+    assert!(!window.accept(8));
+    assert!(window.accept(100));
+    assert!(!window.accept(10));
+    assert!(window.accept(37));
+    assert!(!window.accept(36));
+}
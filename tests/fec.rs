@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+use pigeonvc2::client::{Client, SessionEvent};
+use pigeonvc2::protocol::{self, FecDecoder, FecEncoder, PacketType};
+use proptest::prelude::*;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+type Frame = (u16, u32, Vec<u8>);
+
+fn parity_of(packet: &[u8]) -> (u16, u8, u32, Vec<u8>) {
+    match protocol::parse_from_client_packet(packet).unwrap() {
+        PacketType::Parity {
+            seq,
+            count,
+            timestamp,
+            parity,
+        } => (seq, count, timestamp, parity.into_owned()),
+        other => panic!("expected parity, got {other:?}"),
+    }
+}
+
+fn encode_group(frames: &[Frame]) -> (u16, u8, u32, Vec<u8>) {
+    let mut encoder = FecEncoder::new(frames.len() as u8);
+    let mut parity = None;
+    for (i, (seq, timestamp, data)) in frames.iter().enumerate() {
+        let out = encoder.push(*seq, *timestamp, data);
+        assert_eq!(out.is_some(), i + 1 == frames.len());
+        parity = out;
+    }
+    parity_of(&parity.unwrap())
+}
+
+proptest! {
+    #[test]
+    fn single_loss_is_recovered(
+        first_seq: u16,
+        first_timestamp: u32,
+        payloads in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..200), 2..8),
+        lost in any::<prop::sample::Index>(),
+    ) {
+        let frames: Vec<Frame> = payloads
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| {
+                let seq = first_seq.wrapping_add(i as u16);
+                (seq, first_timestamp.wrapping_add(960 * i as u32), data)
+            })
+            .collect();
+        let (seq, count, timestamp, parity) = encode_group(&frames);
+        prop_assert_eq!(seq, first_seq);
+        prop_assert_eq!(count as usize, frames.len());
+
+        let lost = lost.index(frames.len());
+        let mut decoder = FecDecoder::new();
+        for (i, (seq, timestamp, data)) in frames.iter().enumerate() {
+            if i != lost {
+                prop_assert_eq!(decoder.push_frame(*seq, *timestamp, data), None);
+            }
+        }
+        prop_assert_eq!(
+            decoder.push_parity(seq, count, timestamp, &parity),
+            Some(frames[lost].clone())
+        );
+    }
+}
+
+fn frames(count: u16) -> Vec<Frame> {
+    (0..count)
+        .map(|seq| (seq, seq as u32 * 960, vec![seq as u8; 10 + seq as usize]))
+        .collect()
+}
+
+#[test]
+fn parity_before_the_last_frame_still_recovers() {
+    let frames = frames(4);
+    let (seq, count, timestamp, parity) = encode_group(&frames);
+
+    let mut decoder = FecDecoder::new();
+    decoder.push_frame(frames[0].0, frames[0].1, &frames[0].2);
+    assert_eq!(decoder.push_parity(seq, count, timestamp, &parity), None);
+    decoder.push_frame(frames[1].0, frames[1].1, &frames[1].2);
+    assert_eq!(
+        decoder.push_frame(frames[3].0, frames[3].1, &frames[3].2),
+        Some(frames[2].clone())
+    );
+}
+
+#[test]
+fn recovered_frames_are_remembered() {
+    let frames = frames(4);
+    let (seq, count, timestamp, parity) = encode_group(&frames);
+
+    let mut decoder = FecDecoder::new();
+    for (seq, timestamp, data) in [&frames[0], &frames[1], &frames[3]] {
+        decoder.push_frame(*seq, *timestamp, data);
+    }
+    assert!(!decoder.is_recovered(2));
+    assert_eq!(
+        decoder.push_parity(seq, count, timestamp, &parity),
+        Some(frames[2].clone())
+    );
+    assert!(decoder.is_recovered(2));
+    assert!(!decoder.is_recovered(3));
+}
+
+#[tokio::test]
+async fn late_original_of_a_recovered_frame_is_dropped() {
+    // A stand-in server that accepts the join and then plays a talker.
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = server.local_addr().unwrap().to_string();
+    let joining = tokio::spawn(async move {
+        Client::connect(addr)
+            .await?
+            .with_capabilities(protocol::CAP_FEC)
+            .join("alice", "hw-a", 1)
+            .await
+    });
+    let mut buf = vec![0u8; 1500];
+    let (_, client) = server.recv_from(&mut buf).await.unwrap();
+    let accepted = protocol::new_accepted(1, protocol::PROTOCOL_VERSION, protocol::CAP_FEC, 42);
+    server.send_to(&accepted, client).await.unwrap();
+    let mut session = joining.await.unwrap().unwrap();
+
+    let frames = frames(4);
+    let (seq, count, timestamp, parity) = encode_group(&frames);
+    let talker = 7;
+    for (seq, timestamp, data) in [&frames[0], &frames[1], &frames[3]] {
+        let pkt =
+            protocol::new_talked_audio(talker, *seq, *timestamp, protocol::PAYLOAD_OPUS, data);
+        server.send_to(&pkt, client).await.unwrap();
+    }
+    let pkt = protocol::new_talked_parity(talker, seq, count, timestamp, &parity);
+    server.send_to(&pkt, client).await.unwrap();
+    let (seq, timestamp, data) = &frames[2];
+    let pkt = protocol::new_talked_audio(talker, *seq, *timestamp, protocol::PAYLOAD_OPUS, data);
+    server.send_to(&pkt, client).await.unwrap();
+
+    let mut played = Vec::new();
+    while let Ok(Some(event)) = timeout(Duration::from_millis(300), session.recv()).await {
+        if let SessionEvent::Audio { seq, .. } = event {
+            played.push(seq);
+        }
+    }
+    assert_eq!(played, vec![0, 1, 3, 2]);
+}
+
+#[test]
+fn double_loss_is_not_recovered() {
+    let frames = frames(4);
+    let (seq, count, timestamp, parity) = encode_group(&frames);
+
+    let mut decoder = FecDecoder::new();
+    decoder.push_frame(frames[0].0, frames[0].1, &frames[0].2);
+    decoder.push_frame(frames[3].0, frames[3].1, &frames[3].2);
+    assert_eq!(decoder.push_parity(seq, count, timestamp, &parity), None);
+}
+
+#[test]
+fn complete_group_needs_no_recovery() {
+    let frames = frames(4);
+    let (seq, count, timestamp, parity) = encode_group(&frames);
+
+    let mut decoder = FecDecoder::new();
+    for (seq, timestamp, data) in frames.iter() {
+        decoder.push_frame(*seq, *timestamp, data);
+    }
+    assert_eq!(decoder.push_parity(seq, count, timestamp, &parity), None);
+}
+
+#[test]
+fn sequence_gap_restarts_the_group() {
+    let mut encoder = FecEncoder::new(2);
+    assert_eq!(encoder.push(0, 0, b"a"), None);
+    assert_eq!(encoder.push(5, 0, b"b"), None);
+    let (seq, count, _, _) = parity_of(&encoder.push(6, 0, b"c").unwrap());
+    assert_eq!((seq, count), (5, 2));
+}
@@ -0,0 +1,103 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use pigeonvc2::protocol::{self, ErrorCode, MAGIC, PacketType};
+use pigeonvc2::server::{DropStats, Server};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+async fn server() -> Server {
+    let server = Server::new("127.0.0.1:0".into(), |_| async { Ok(()) }, |_| async {})
+        .await
+        .unwrap();
+    server.add_room_with_id(1, "Lobby");
+    server
+}
+
+fn stranger() -> SocketAddr {
+    "127.0.0.1:9".parse().unwrap()
+}
+
+#[tokio::test]
+async fn unknown_sources_may_only_look_around_and_join() {
+    let server = server().await;
+
+    for packet in [
+        protocol::new_ping(),
+        protocol::new_rooms(0),
+        protocol::new_join("alice", "hw-a", 1, protocol::PROTOCOL_VERSION, 0, "", None),
+    ] {
+        server.receive(stranger(), &packet).await.unwrap();
+    }
+    assert_eq!(server.drop_stats(), DropStats::default());
+
+    for packet in [
+        protocol::new_talk(0, 0, protocol::PAYLOAD_OPUS, b"hello"),
+        protocol::new_switch(1, 42, 7, ""),
+        protocol::new_leave(42),
+        protocol::new_alive(42, &[]),
+        protocol::new_subscribe(42, &[1]),
+        protocol::new_ack(42, 0, 0),
+        protocol::new_fragment(1, 0, 2, b"part"),
+    ] {
+        server.receive(stranger(), &packet).await.unwrap();
+    }
+    assert_eq!(server.drop_stats().unknown_source, 7);
+}
+
+#[tokio::test]
+async fn strangers_cannot_fill_the_reassembler() {
+    let server = server().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = client.local_addr().unwrap();
+
+    let ping = protocol::new_ping();
+    for fragment in protocol::fragment(&ping, 1, protocol::FRAGMENT_HEADER_LEN + 4).unwrap() {
+        server.receive(addr, &fragment).await.unwrap();
+    }
+    let mut buf = [0u8; 1500];
+    assert!(
+        timeout(Duration::from_millis(200), client.recv(&mut buf))
+            .await
+            .is_err()
+    );
+}
+
+#[tokio::test]
+async fn junk_is_rejected_before_parsing() {
+    let server = server().await;
+
+    let mut wrong_type = MAGIC.to_vec();
+    wrong_type.extend_from_slice(&protocol::PONG.to_be_bytes());
+    for packet in [&b"junk"[..], &[0u8; 64][..], &wrong_type[..]] {
+        assert!(server.receive(stranger(), packet).await.is_err());
+    }
+    assert_eq!(server.drop_stats().unknown_source, 0);
+}
+
+#[tokio::test]
+async fn unjoined_control_requests_get_an_error() {
+    let server = server().await;
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = client.local_addr().unwrap();
+
+    server
+        .receive(addr, &protocol::new_switch(2, 42, 7, ""))
+        .await
+        .unwrap();
+
+    let mut buf = [0u8; 1500];
+    let n = timeout(Duration::from_secs(1), client.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        protocol::parse_from_server_packet(&buf[..n]).unwrap(),
+        PacketType::Error {
+            code: ErrorCode::NotJoined,
+            request_type: protocol::SWITCH,
+            request_id: 7,
+            detail: "".into(),
+        }
+    );
+}
@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+use pigeonvc2::protocol::{self, PacketType, Reassembler};
+use proptest::prelude::*;
+
+fn split(packet: &[u8], frag_id: u32, mtu: usize) -> Vec<(u16, u16, Vec<u8>)> {
+    protocol::fragment(packet, frag_id, mtu)
+        .unwrap()
+        .iter()
+        .map(|buf| {
+            assert!(buf.len() <= mtu);
+            match protocol::parse_from_server_packet(buf).unwrap() {
+                PacketType::Fragment {
+                    frag_id: got,
+                    index,
+                    count,
+                    chunk,
+                } => {
+                    assert_eq!(got, frag_id);
+                    (index, count, chunk.into_owned())
+                }
+                other => panic!("expected a fragment, got {other:?}"),
+            }
+        })
+        .collect()
+}
+
+proptest! {
+    #[test]
+    fn fragments_reassemble_in_any_order(
+        packet in proptest::collection::vec(any::<u8>(), 1..8000),
+        mtu in 200usize..1400,
+        seed: u64,
+    ) {
+        let mut fragments = split(&packet, 9, mtu);
+        let len = fragments.len();
+        for i in 0..len {
+            fragments.swap(i, (seed as usize).wrapping_mul(i + 1) % len);
+        }
+
+        let mut reassembler = Reassembler::new();
+        let now = Instant::now();
+        let mut result = None;
+        for (i, (index, count, chunk)) in fragments.iter().enumerate() {
+            let out = reassembler.push(9u32, *index, *count, chunk, now);
+            prop_assert_eq!(out.is_some(), i + 1 == len);
+            result = result.or(out);
+        }
+        prop_assert_eq!(result.unwrap(), packet);
+        prop_assert_eq!(reassembler.pending_bytes(), 0);
+    }
+}
+
+#[test]
+fn duplicate_and_mismatched_fragments_are_ignored() {
+    let packet = vec![7u8; 3000];
+    let fragments = split(&packet, 1, 1200);
+    assert_eq!(fragments.len(), 3);
+
+    let mut reassembler = Reassembler::new();
+    let now = Instant::now();
+    let (index, count, chunk) = &fragments[0];
+    assert_eq!(reassembler.push(1u32, *index, *count, chunk, now), None);
+    assert_eq!(reassembler.push(1u32, *index, *count, chunk, now), None);
+    assert_eq!(reassembler.push(1u32, 1, *count + 1, chunk, now), None);
+    assert_eq!(reassembler.push(1u32, 5, 5, chunk, now), None);
+    assert_eq!(reassembler.push(1u32, 0, 1000, chunk, now), None);
+
+    for (index, count, chunk) in &fragments[1..] {
+        if let Some(out) = reassembler.push(1u32, *index, *count, chunk, now) {
+            assert_eq!(out, packet);
+            return;
+        }
+    }
+    panic!("packet was not reassembled");
+}
+
+#[test]
+fn packets_needing_too_many_fragments_are_refused() {
+    let chunk = 200 - protocol::FRAGMENT_HEADER_LEN;
+    let max = protocol::MAX_FRAGMENTS as usize;
+    assert_eq!(split(&vec![1u8; max * chunk], 2, 200).len(), max);
+    assert!(protocol::fragment(&vec![1u8; max * chunk + 1], 2, 200).is_none());
+}
+
+#[test]
+fn stale_fragments_expire() {
+    let fragments = split(&[1u8; 2000], 4, 1200);
+    let mut reassembler = Reassembler::new();
+    let start = Instant::now();
+
+    let (index, count, chunk) = &fragments[0];
+    reassembler.push(4u32, *index, *count, chunk, start);
+    assert!(reassembler.pending_bytes() > 0);
+
+    reassembler.purge(start + Duration::from_secs(5));
+    assert_eq!(reassembler.pending_bytes(), 0);
+
+    let (index, count, chunk) = &fragments[1];
+    assert_eq!(
+        reassembler.push(4u32, *index, *count, chunk, start + Duration::from_secs(5)),
+        None
+    );
+}
+
+#[test]
+fn memory_limit_evicts_oldest_partial() {
+    let mut reassembler = Reassembler::with_limit(3000);
+    let start = Instant::now();
+
+    let first = split(&[1u8; 2000], 1, 1200);
+    let second = split(&[2u8; 2000], 2, 1200);
+
+    let (index, count, chunk) = &first[0];
+    reassembler.push(1u32, *index, *count, chunk, start);
+    let later = start + Duration::from_millis(10);
+    let (index, count, chunk) = &second[0];
+    reassembler.push(2u32, *index, *count, chunk, later);
+
+    let (index, count, chunk) = &second[1];
+    assert_eq!(
+        reassembler.push(2u32, *index, *count, chunk, later),
+        Some(vec![2u8; 2000])
+    );
+    assert_eq!(reassembler.pending_bytes(), 0);
+
+    let (index, count, chunk) = &first[1];
+    assert_eq!(reassembler.push(1u32, *index, *count, chunk, later), None);
+}
@@ -0,0 +1,224 @@
+use std::time::{Duration, Instant};
+
+use pigeonvc2::client::{JitterBuffer, JitterConfig, Playout};
+
+const TALKER: u64 = 7;
+const FRAME: Duration = Duration::from_millis(20);
+const SAMPLES: u32 = 960;
+
+#[derive(Debug, PartialEq)]
+enum Out {
+    Play(u16),
+    Conceal(u16),
+}
+
+#[derive(Default)]
+struct Recorder(Vec<Out>);
+
+impl Playout for Recorder {
+    fn play(&mut self, talker: u64, seq: u16, timestamp: u32, data: &[u8]) {
+        assert_eq!(talker, TALKER);
+        assert_eq!(timestamp % SAMPLES, 0);
+        assert_eq!(data, seq.to_be_bytes());
+        self.0.push(Out::Play(seq));
+    }
+
+    fn conceal(&mut self, talker: u64, seq: u16) {
+        assert_eq!(talker, TALKER);
+        self.0.push(Out::Conceal(seq));
+    }
+}
+
+fn push(buffer: &mut JitterBuffer, seq: u16, now: Instant) {
+    buffer.push(TALKER, seq, seq as u32 * SAMPLES, &seq.to_be_bytes(), now);
+}
+
+fn push_frame(buffer: &mut JitterBuffer, seq: u16, frame: u32, now: Instant) {
+    buffer.push(TALKER, seq, frame * SAMPLES, &seq.to_be_bytes(), now);
+}
+
+fn depth(min_depth: usize) -> JitterBuffer {
+    JitterBuffer::new(JitterConfig {
+        min_depth,
+        ..Default::default()
+    })
+}
+
+#[test]
+fn steady_stream_plays_in_order() {
+    let mut buffer = JitterBuffer::default();
+    let mut out = Recorder::default();
+    let start = Instant::now();
+
+    for seq in 0..5u16 {
+        let now = start + FRAME * seq as u32;
+        push(&mut buffer, seq, now);
+        buffer.tick(now, &mut out);
+    }
+
+    assert_eq!(out.0, (0..5).map(Out::Play).collect::<Vec<_>>());
+    let stats = buffer.stats(TALKER).unwrap();
+    assert_eq!((stats.received, stats.played, stats.lost), (5, 5, 0));
+    assert_eq!(stats.target_depth, 1);
+}
+
+#[test]
+fn reordered_frames_are_sorted_within_the_depth() {
+    let mut buffer = depth(3);
+    let mut out = Recorder::default();
+    let now = Instant::now();
+
+    for seq in [0, 2, 1, 3] {
+        push(&mut buffer, seq, now);
+    }
+    for _ in 0..4 {
+        buffer.tick(now, &mut out);
+    }
+
+    assert_eq!(out.0, (0..4).map(Out::Play).collect::<Vec<_>>());
+}
+
+#[test]
+fn gaps_are_concealed_and_late_frames_reported() {
+    let mut buffer = depth(2);
+    let mut out = Recorder::default();
+    let now = Instant::now();
+
+    for seq in [0, 1, 3, 4] {
+        push(&mut buffer, seq, now);
+    }
+    for _ in 0..3 {
+        buffer.tick(now, &mut out);
+    }
+    push(&mut buffer, 2, now);
+    for _ in 0..2 {
+        buffer.tick(now, &mut out);
+    }
+
+    assert_eq!(
+        out.0,
+        vec![
+            Out::Play(0),
+            Out::Play(1),
+            Out::Conceal(2),
+            Out::Play(3),
+            Out::Play(4)
+        ]
+    );
+    let stats = buffer.stats(TALKER).unwrap();
+    assert_eq!((stats.lost, stats.late, stats.concealed), (1, 1, 1));
+}
+
+#[test]
+fn duplicates_are_ignored() {
+    let mut buffer = depth(2);
+    let now = Instant::now();
+
+    push(&mut buffer, 0, now);
+    push(&mut buffer, 0, now);
+
+    let stats = buffer.stats(TALKER).unwrap();
+    assert_eq!((stats.received, stats.duplicates), (1, 1));
+}
+
+#[test]
+fn silent_talker_stops_concealing() {
+    let mut buffer = JitterBuffer::default();
+    let mut out = Recorder::default();
+    let start = Instant::now();
+
+    push(&mut buffer, 0, start);
+    for i in 0..10 {
+        buffer.tick(start + FRAME * i, &mut out);
+    }
+    let mut expected = vec![Out::Play(0)];
+    expected.extend((0..5).map(|_| Out::Conceal(1)));
+    assert_eq!(out.0, expected);
+
+    // The talker resumes after a pause; nothing of it is late.
+    out.0.clear();
+    let now = start + Duration::from_secs(1);
+    push(&mut buffer, 1, now);
+    buffer.tick(now, &mut out);
+    assert_eq!(out.0, vec![Out::Play(1)]);
+    assert_eq!(buffer.stats(TALKER).unwrap().late, 0);
+}
+
+#[test]
+fn sequence_numbers_wrap() {
+    let mut buffer = depth(2);
+    let mut out = Recorder::default();
+    let start = Instant::now();
+
+    for (frame, seq) in [65534u16, 65535, 0, 1].into_iter().enumerate() {
+        let now = start + FRAME * frame as u32;
+        push_frame(&mut buffer, seq, frame as u32, now);
+        buffer.tick(now, &mut out);
+    }
+    buffer.tick(start + FRAME * 4, &mut out);
+
+    assert_eq!(
+        out.0,
+        [65534, 65535, 0, 1]
+            .map(Out::Play)
+            .into_iter()
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn jitter_grows_the_target_depth() {
+    let mut steady = JitterBuffer::default();
+    let mut jittery = JitterBuffer::default();
+    let start = Instant::now();
+
+    for seq in 0..100u16 {
+        let now = start + FRAME * seq as u32;
+        push(&mut steady, seq, now);
+        let wobble = if seq % 2 == 0 {
+            Duration::ZERO
+        } else {
+            Duration::from_millis(60)
+        };
+        push(&mut jittery, seq, now + wobble);
+    }
+
+    let steady = steady.stats(TALKER).unwrap();
+    let jittery = jittery.stats(TALKER).unwrap();
+    assert!(steady.jitter_ms < 1.0);
+    assert_eq!(steady.target_depth, 1);
+    assert!(jittery.jitter_ms > 40.0);
+    assert!(jittery.target_depth > 4);
+}
+
+#[test]
+fn overfull_buffer_catches_up() {
+    let mut buffer = JitterBuffer::default();
+    let mut out = Recorder::default();
+    let start = Instant::now();
+
+    for seq in 0..8u16 {
+        push(&mut buffer, seq, start + FRAME * seq as u32);
+    }
+    buffer.tick(start + FRAME * 7, &mut out);
+
+    // Target depth 1 plus two frames of slack leaves the newest three.
+    assert_eq!(out.0, vec![Out::Play(5)]);
+    let stats = buffer.stats(TALKER).unwrap();
+    assert_eq!((stats.dropped, stats.lost), (5, 0));
+}
+
+#[test]
+fn restarted_stream_is_accepted() {
+    let mut buffer = JitterBuffer::default();
+    let mut out = Recorder::default();
+    let now = Instant::now();
+
+    push(&mut buffer, 5000, now);
+    buffer.tick(now, &mut out);
+    push(&mut buffer, 0, now);
+    buffer.tick(now, &mut out);
+
+    assert_eq!(out.0, vec![Out::Play(5000), Out::Play(0)]);
+    assert_eq!(buffer.stats(TALKER).unwrap().late, 0);
+}
@@ -0,0 +1,149 @@
+use pigeonvc2::protocol::{self, DecodeError, DisconnectReason, MAGIC};
+use proptest::prelude::*;
+
+fn header(packet_type: u32) -> Vec<u8> {
+    let mut packet = MAGIC.to_vec();
+    packet.extend_from_slice(&packet_type.to_be_bytes());
+    packet
+}
+
+#[test]
+fn truncated_join_is_rejected() {
+    let mut packet = header(protocol::JOIN);
+    packet.extend_from_slice(b"name\0hwid\0");
+    assert_eq!(
+        protocol::parse_from_client_packet(&packet),
+        Err(DecodeError::InvalidLength(protocol::JOIN))
+    );
+
+    packet.push(0);
+    assert_eq!(
+        protocol::parse_from_client_packet(&packet),
+        Err(DecodeError::InvalidLength(protocol::JOIN))
+    );
+}
+
+#[test]
+fn talk_without_frame_header_is_rejected() {
+    let mut packet = header(protocol::TALK);
+    packet.extend_from_slice(&[0, 1, 0, 0, 0, 0]);
+    assert_eq!(
+        protocol::parse_from_client_packet(&packet),
+        Err(DecodeError::InvalidLength(protocol::TALK))
+    );
+}
+
+#[test]
+fn unknown_talked_kind_is_rejected() {
+    let mut packet = header(protocol::TALKED);
+    packet.push(2);
+    packet.extend_from_slice(&[0; 16]);
+    assert_eq!(
+        protocol::parse_from_server_packet(&packet),
+        Err(DecodeError::InvalidValue(protocol::TALKED))
+    );
+}
+
+#[test]
+fn unknown_error_code_is_rejected() {
+    let mut packet = header(protocol::ERROR);
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.push(0);
+    assert_eq!(
+        protocol::parse_from_server_packet(&packet),
+        Err(DecodeError::InvalidValue(protocol::ERROR))
+    );
+}
+
+#[test]
+fn unknown_disconnect_reason_is_rejected() {
+    let mut packet = header(protocol::DISCONNECT);
+    packet.extend_from_slice(&0u16.to_be_bytes());
+    packet.push(0);
+    assert_eq!(
+        protocol::parse_from_server_packet(&packet),
+        Err(DecodeError::InvalidValue(protocol::DISCONNECT))
+    );
+}
+
+#[test]
+fn header_errors() {
+    assert_eq!(
+        protocol::parse_from_client_packet(&MAGIC),
+        Err(DecodeError::TooShort)
+    );
+    assert_eq!(
+        protocol::parse_from_server_packet(&[0u8; 8]),
+        Err(DecodeError::BadMagic)
+    );
+    assert_eq!(
+        protocol::parse_from_client_packet(&header(protocol::PONG)),
+        Err(DecodeError::UnknownType(protocol::PONG))
+    );
+    assert_eq!(
+        protocol::parse_from_server_packet(&header(u32::MAX)),
+        Err(DecodeError::UnknownType(u32::MAX))
+    );
+}
+
+#[test]
+fn string_errors() {
+    let mut packet = header(protocol::JOIN);
+    packet.extend_from_slice(b"name");
+    assert_eq!(
+        protocol::parse_from_client_packet(&packet),
+        Err(DecodeError::MissingNul)
+    );
+
+    let mut packet = header(protocol::DISCONNECT);
+    packet.extend_from_slice(&(DisconnectReason::Kicked as u16).to_be_bytes());
+    packet.extend_from_slice(&[0xff, 0xfe, 0]);
+    assert_eq!(
+        protocol::parse_from_server_packet(&packet),
+        Err(DecodeError::InvalidUtf8)
+    );
+}
+
+#[test]
+fn snapshot_part_out_of_range_is_rejected() {
+    let mut packet = header(protocol::SNAPSHOT);
+    packet.extend_from_slice(&7u32.to_be_bytes());
+    packet.extend_from_slice(&2u16.to_be_bytes());
+    packet.extend_from_slice(&2u16.to_be_bytes());
+    assert_eq!(
+        protocol::parse_from_server_packet(&packet),
+        Err(DecodeError::InvalidValue(protocol::SNAPSHOT))
+    );
+}
+
+#[test]
+fn fragment_index_out_of_range_is_rejected() {
+    let mut packet = header(protocol::FRAGMENT);
+    packet.extend_from_slice(&7u32.to_be_bytes());
+    packet.extend_from_slice(&3u16.to_be_bytes());
+    packet.extend_from_slice(&3u16.to_be_bytes());
+    packet.extend_from_slice(b"chunk");
+    assert_eq!(
+        protocol::parse_from_client_packet(&packet),
+        Err(DecodeError::InvalidValue(protocol::FRAGMENT))
+    );
+}
+
+proptest! {
+    #[test]
+    fn arbitrary_bytes_never_panic(data in proptest::collection::vec(any::<u8>(), 0..64)) {
+        let _ = protocol::parse_from_client_packet(&data);
+        let _ = protocol::parse_from_server_packet(&data);
+    }
+
+    #[test]
+    fn arbitrary_payloads_never_panic(
+        packet_type in 0u32..32,
+        payload in proptest::collection::vec(any::<u8>(), 0..64),
+    ) {
+        let mut packet = header(packet_type);
+        packet.extend_from_slice(&payload);
+        let _ = protocol::parse_from_client_packet(&packet);
+        let _ = protocol::parse_from_server_packet(&packet);
+    }
+}
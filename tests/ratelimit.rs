@@ -0,0 +1,255 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use pigeonvc2::protocol::{self, CodecPolicy, ErrorCode, MAGIC};
+use pigeonvc2::server::{
+    Budget, FloodConfig, FloodGuard, MediaLimiter, MediaVerdict, RoomConfig, TokenBucket, Traffic,
+};
+
+const HEADER_LEN: usize = 15;
+
+fn config(max_bitrate: u32) -> RoomConfig {
+    RoomConfig {
+        codec: CodecPolicy {
+            max_frame_len: 100,
+            ..CodecPolicy::OPUS_48K_MONO
+        },
+        max_bitrate,
+        ..RoomConfig::default()
+    }
+}
+
+fn check(
+    limiter: &mut MediaLimiter,
+    frame_len: usize,
+    config: &RoomConfig,
+    now: Instant,
+) -> MediaVerdict {
+    limiter.check(HEADER_LEN + frame_len, frame_len, config, now)
+}
+
+#[test]
+fn bucket_refills_over_time() {
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(10.0, 5.0, start);
+
+    for _ in 0..5 {
+        assert!(bucket.try_take(1.0, start));
+    }
+    assert!(!bucket.try_take(1.0, start));
+    assert!(!bucket.try_take(1.0, start + Duration::from_millis(50)));
+    assert!(bucket.try_take(1.0, start + Duration::from_millis(150)));
+
+    // Refills stop at the burst size.
+    let later = start + Duration::from_secs(10);
+    for _ in 0..5 {
+        assert!(bucket.try_take(1.0, later));
+    }
+    assert!(!bucket.try_take(1.0, later));
+}
+
+#[test]
+fn frames_within_the_bitrate_are_forwarded() {
+    let config = config(32_000);
+    let mut limiter = MediaLimiter::new(Instant::now());
+    let start = Instant::now();
+
+    // 50 frames per second of 65 bytes on the wire is 26 kbit/s.
+    for i in 0..500 {
+        let now = start + Duration::from_millis(20 * i);
+        assert!(matches!(
+            check(&mut limiter, 50, &config, now),
+            MediaVerdict::Forward
+        ));
+    }
+}
+
+#[test]
+fn oversized_frames_are_dropped() {
+    let config = config(0);
+    let mut limiter = MediaLimiter::new(Instant::now());
+
+    assert!(matches!(
+        check(&mut limiter, 101, &config, Instant::now()),
+        MediaVerdict::Drop(ErrorCode::FrameTooLarge)
+    ));
+    assert!(matches!(
+        check(&mut limiter, 100, &config, Instant::now()),
+        MediaVerdict::Forward
+    ));
+}
+
+#[test]
+fn sustained_flooding_disconnects() {
+    let config = config(32_000);
+    let start = Instant::now();
+    let mut limiter = MediaLimiter::new(start);
+
+    let mut forwarded = 0;
+    let mut dropped = 0;
+    let mut disconnected_at = None;
+    // Four times the allowed rate.
+    for i in 0..2000 {
+        let now = start + Duration::from_millis(5 * i);
+        match check(&mut limiter, 50, &config, now) {
+            MediaVerdict::Forward => forwarded += 1,
+            MediaVerdict::Drop(code) => {
+                assert_eq!(code, ErrorCode::RateLimited);
+                dropped += 1;
+            }
+            MediaVerdict::Disconnect => {
+                disconnected_at = Some(now - start);
+                break;
+            }
+        }
+    }
+
+    let elapsed = disconnected_at.expect("flooding client was never disconnected");
+    assert!(elapsed < Duration::from_secs(2));
+    assert!(dropped > forwarded);
+}
+
+#[test]
+fn brief_bursts_are_tolerated() {
+    let config = config(32_000);
+    let start = Instant::now();
+    let mut limiter = MediaLimiter::new(start);
+
+    for _ in 0..40 {
+        assert!(!matches!(
+            check(&mut limiter, 50, &config, start),
+            MediaVerdict::Disconnect
+        ));
+    }
+    for i in 1..100 {
+        let now = start + Duration::from_millis(20 * i);
+        assert!(!matches!(
+            check(&mut limiter, 50, &config, now),
+            MediaVerdict::Disconnect
+        ));
+    }
+}
+
+fn packet(packet_type: u32) -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&packet_type.to_be_bytes());
+    buf
+}
+
+fn flood_config() -> FloodConfig {
+    let budget = |per_sec, burst| Budget { per_sec, burst };
+    FloodConfig {
+        ip_control: budget(5.0, 5.0),
+        ip_media: budget(50.0, 50.0),
+        ip_join: budget(1.0, 2.0),
+        subnet_control: budget(8.0, 8.0),
+        subnet_media: budget(500.0, 500.0),
+        subnet_join: budget(10.0, 10.0),
+        malformed: budget(1.0, 3.0),
+        block_secs: 60,
+        session_control: budget(20.0, 20.0),
+    }
+}
+
+#[test]
+fn traffic_is_classified_from_the_header() {
+    assert_eq!(Traffic::of(&packet(protocol::TALK)), Traffic::Media);
+    assert_eq!(Traffic::of(&packet(protocol::SEALED)), Traffic::Media);
+    assert_eq!(Traffic::of(&packet(protocol::JOIN)), Traffic::Join);
+    assert_eq!(Traffic::of(&packet(protocol::PING)), Traffic::Control);
+    assert_eq!(Traffic::of(&[1, 2, 3]), Traffic::Control);
+}
+
+#[test]
+fn control_and_media_budgets_are_separate() {
+    let guard = FloodGuard::new();
+    let config = flood_config();
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let now = Instant::now();
+
+    for _ in 0..5 {
+        assert!(guard.allow(ip, &packet(protocol::PING), &config, now));
+    }
+    assert!(!guard.allow(ip, &packet(protocol::PING), &config, now));
+
+    // Exhausting control traffic leaves voice untouched.
+    for _ in 0..50 {
+        assert!(guard.allow(ip, &packet(protocol::TALK), &config, now));
+    }
+    assert!(!guard.allow(ip, &packet(protocol::TALK), &config, now));
+
+    assert!(guard.allow(
+        ip,
+        &packet(protocol::PING),
+        &config,
+        now + Duration::from_millis(250)
+    ));
+}
+
+#[test]
+fn subnet_budget_is_shared() {
+    let guard = FloodGuard::new();
+    let config = flood_config();
+    let now = Instant::now();
+
+    let mut allowed = 0;
+    for host in 1..=4 {
+        let ip: IpAddr = format!("10.0.0.{host}").parse().unwrap();
+        for _ in 0..5 {
+            allowed += guard.allow(ip, &packet(protocol::PING), &config, now) as usize;
+        }
+    }
+    assert_eq!(allowed, 8);
+
+    // A neighbouring /24 has its own budget.
+    let other: IpAddr = "10.0.1.1".parse().unwrap();
+    assert!(guard.allow(other, &packet(protocol::PING), &config, now));
+}
+
+#[test]
+fn ipv6_subnets_are_slash_64() {
+    let guard = FloodGuard::new();
+    let config = flood_config();
+    let now = Instant::now();
+
+    let mut allowed = 0;
+    for host in 1..=4 {
+        let ip: IpAddr = format!("2001:db8::{host}").parse().unwrap();
+        for _ in 0..5 {
+            allowed += guard.allow(ip, &packet(protocol::PING), &config, now) as usize;
+        }
+    }
+    assert_eq!(allowed, 8);
+
+    let other: IpAddr = "2001:db8:0:1::1".parse().unwrap();
+    assert!(guard.allow(other, &packet(protocol::PING), &config, now));
+}
+
+#[test]
+fn sustained_malformed_packets_block_the_address() {
+    let guard = FloodGuard::new();
+    let config = flood_config();
+    let ip: IpAddr = "10.0.0.1".parse().unwrap();
+    let neighbour: IpAddr = "10.0.0.2".parse().unwrap();
+    let start = Instant::now();
+
+    // Occasional garbage is forgiven.
+    for i in 0..10 {
+        assert!(!guard.malformed(ip, &config, start + Duration::from_secs(i)));
+    }
+
+    let now = start + Duration::from_secs(10);
+    assert!(!guard.malformed(ip, &config, now));
+    assert!(!guard.malformed(ip, &config, now));
+    assert!(!guard.malformed(ip, &config, now));
+    assert!(guard.malformed(ip, &config, now));
+    assert!(guard.is_blocked(ip, now));
+    assert!(!guard.allow(ip, &packet(protocol::PING), &config, now));
+    assert!(guard.allow(neighbour, &packet(protocol::PING), &config, now));
+
+    let expired = now + Duration::from_secs(61);
+    assert!(!guard.is_blocked(ip, expired));
+    guard.purge(expired);
+    assert_eq!(guard.blocked(), 0);
+    assert!(guard.allow(ip, &packet(protocol::PING), &config, expired));
+}
@@ -0,0 +1,130 @@
+use std::time::{Duration, Instant};
+
+use pigeonvc2::protocol::{self, DisconnectReason, PacketType, ReliableReceiver, ReliableSender};
+
+fn seq_of(packet: &[u8]) -> u32 {
+    match protocol::parse_from_server_packet(packet).unwrap() {
+        PacketType::Reliable { seq, .. } => seq,
+        other => panic!("expected a reliable packet, got {other:?}"),
+    }
+}
+
+#[test]
+fn wrapped_packets_carry_the_inner_packet() {
+    let mut sender = ReliableSender::new();
+    let inner = protocol::new_disconnect(DisconnectReason::Kicked, "bye");
+    let wrapped = sender.wrap(&inner, Instant::now());
+
+    let PacketType::Reliable { seq, payload } =
+        protocol::parse_from_server_packet(&wrapped).unwrap()
+    else {
+        panic!("expected a reliable packet");
+    };
+    assert_eq!(seq, 1);
+    assert_eq!(payload, inner);
+    assert_eq!(sender.in_flight(), 1);
+}
+
+#[test]
+fn unacked_packets_are_retransmitted_with_backoff() {
+    let mut sender = ReliableSender::new();
+    let start = Instant::now();
+    let wrapped = sender.wrap(&protocol::new_pong(), start);
+
+    assert!(sender.due(start + Duration::from_millis(100)).is_empty());
+
+    let first = start + Duration::from_millis(300);
+    assert_eq!(sender.due(first), vec![wrapped.clone()]);
+    assert!(sender.due(first + Duration::from_millis(300)).is_empty());
+    assert_eq!(
+        sender.due(first + Duration::from_millis(600)),
+        vec![wrapped]
+    );
+}
+
+#[test]
+fn packets_give_up_after_max_retransmits() {
+    let mut sender = ReliableSender::new();
+    let mut now = Instant::now();
+    sender.wrap(&protocol::new_pong(), now);
+
+    let mut retransmits = 0;
+    for _ in 0..20 {
+        now += Duration::from_secs(60);
+        retransmits += sender.due(now).len();
+    }
+    assert_eq!(retransmits, 5);
+    assert_eq!(sender.in_flight(), 0);
+}
+
+#[test]
+fn selective_ack_only_resends_the_gaps() {
+    let mut sender = ReliableSender::new();
+    let start = Instant::now();
+    let packets: Vec<Vec<u8>> = (0..5)
+        .map(|i| {
+            sender.wrap(
+                &protocol::new_event(i, protocol::EventKind::Joined, 1, i, "u"),
+                start,
+            )
+        })
+        .collect();
+
+    let mut receiver = ReliableReceiver::new();
+    for packet in [&packets[0], &packets[2], &packets[4]] {
+        assert!(receiver.accept(seq_of(packet)));
+    }
+    let (cumulative, sack) = receiver.ack();
+    assert_eq!(cumulative, 1);
+    assert_eq!(sack, 0b1010);
+
+    sender.on_ack(cumulative, sack);
+    assert_eq!(sender.in_flight(), 2);
+
+    let resent = sender.due(start + Duration::from_secs(1));
+    let resent: Vec<u32> = resent.iter().map(|p| seq_of(p)).collect();
+    assert_eq!(resent, vec![2, 4]);
+}
+
+#[test]
+fn receiver_filters_duplicates_and_advances() {
+    let mut receiver = ReliableReceiver::new();
+    assert!(receiver.accept(2));
+    assert!(!receiver.accept(2));
+    assert_eq!(receiver.ack(), (0, 0b10));
+
+    assert!(receiver.accept(1));
+    assert!(!receiver.accept(1));
+    assert_eq!(receiver.ack(), (2, 0));
+
+    assert!(!receiver.accept(0));
+    assert!(!receiver.accept(5000));
+}
+
+#[test]
+fn seqs_wrap_around() {
+    let mut sender = ReliableSender::starting_after(u32::MAX - 2);
+    let mut receiver = ReliableReceiver::starting_after(u32::MAX - 2);
+    let start = Instant::now();
+    let packets: Vec<Vec<u8>> = (0..5)
+        .map(|_| sender.wrap(&protocol::new_pong(), start))
+        .collect();
+    let seqs: Vec<u32> = packets.iter().map(|p| seq_of(p)).collect();
+    assert_eq!(seqs, vec![u32::MAX - 1, u32::MAX, 0, 1, 2]);
+
+    for seq in [seqs[0], seqs[2], seqs[4]] {
+        assert!(receiver.accept(seq));
+    }
+    assert!(!receiver.accept(seqs[2]));
+    assert_eq!(receiver.ack(), (u32::MAX - 1, 0b1010));
+
+    let (cumulative, sack) = receiver.ack();
+    sender.on_ack(cumulative, sack);
+    assert_eq!(sender.in_flight(), 2);
+
+    assert!(receiver.accept(seqs[1]));
+    assert!(receiver.accept(seqs[3]));
+    assert_eq!(receiver.ack(), (2, 0));
+    sender.on_ack(2, 0);
+    assert_eq!(sender.in_flight(), 0);
+}
@@ -0,0 +1,544 @@
+use std::sync::{Arc, Mutex};
+
+use std::time::Duration;
+
+use pigeonvc2::client::{Client, SessionEvent, VoiceSession};
+use pigeonvc2::protocol::{ErrorCode, RoomChange};
+use pigeonvc2::server::{RoomConfig, RoomStore, Server, StoreFuture};
+use tokio::time::{sleep, timeout};
+
+async fn start(server: Server) -> String {
+    serve(Arc::new(server)).await
+}
+
+async fn serve(server: Arc<Server>) -> String {
+    let addr = server.local_addr().unwrap().to_string();
+    {
+        let server = server.clone();
+        tokio::spawn(async move { server.listen().await });
+    }
+    {
+        let server = server.clone();
+        tokio::spawn(async move { server.retransmit_routine().await });
+    }
+    addr
+}
+
+async fn server() -> Server {
+    let server = Server::new("127.0.0.1:0".into(), |_| async { Ok(()) }, |_| async {})
+        .await
+        .unwrap()
+        .with_room_permission(|hwid, room_id| async move {
+            if room_id == 3 && hwid != "hw-admin" {
+                anyhow::bail!("room 3 is for admins only");
+            }
+            Ok(())
+        });
+    server.add_room_with_id(1, "Lobby");
+    server.add_room_with_config(
+        2,
+        "Booth",
+        RoomConfig {
+            max_users: Some(1),
+            ..RoomConfig::default()
+        },
+    );
+    server.add_room_with_id(3, "Admins");
+    server.add_room_with_config(
+        4,
+        "Stage",
+        RoomConfig {
+            max_users: Some(1),
+            max_queue: 2,
+            ..RoomConfig::default()
+        },
+    );
+    server
+}
+
+async fn join(addr: &str, hwid: &str, room_id: u16) -> anyhow::Result<VoiceSession> {
+    Client::connect(addr.to_string())
+        .await?
+        .join(hwid, hwid, room_id)
+        .await
+}
+
+/// Queue positions reported to `session` so far.
+async fn positions(session: &mut VoiceSession) -> Vec<u16> {
+    let mut positions = Vec::new();
+    while let Ok(Some(event)) = timeout(Duration::from_millis(200), session.recv()).await {
+        if let SessionEvent::Queued {
+            room_id: 4,
+            position,
+        } = event
+        {
+            positions.push(position);
+        }
+    }
+    positions
+}
+
+fn refusal<T>(result: anyhow::Result<T>) -> Option<ErrorCode> {
+    result.err()?.downcast_ref::<ErrorCode>().copied()
+}
+
+#[tokio::test]
+async fn switch_is_confirmed() {
+    let addr = start(server().await).await;
+    let alice = join(&addr, "hw-a", 1).await.unwrap();
+
+    alice.switch(2).await.unwrap();
+    assert_eq!(alice.room_id(), 2);
+    // Switching to the current room is a no-op that still succeeds.
+    alice.switch(2).await.unwrap();
+}
+
+#[tokio::test]
+async fn refused_switch_keeps_the_old_room() {
+    let addr = start(server().await).await;
+    let alice = join(&addr, "hw-a", 2).await.unwrap();
+    let bob = join(&addr, "hw-b", 1).await.unwrap();
+
+    assert_eq!(refusal(bob.switch(2).await), Some(ErrorCode::RoomFull));
+    let forbidden = bob.switch(3).await.unwrap_err();
+    assert_eq!(
+        forbidden.downcast_ref::<ErrorCode>(),
+        Some(&ErrorCode::PermissionDenied)
+    );
+    assert!(format!("{forbidden:#}").contains("admins only"));
+    assert_eq!(refusal(bob.switch(9).await), Some(ErrorCode::UnknownRoom));
+    assert_eq!(bob.room_id(), 1);
+
+    alice.switch(1).await.unwrap();
+    bob.switch(2).await.unwrap();
+    assert_eq!(bob.room_id(), 2);
+}
+
+#[tokio::test]
+async fn join_checks_the_room_too() {
+    let addr = start(server().await).await;
+    let _alice = join(&addr, "hw-a", 2).await.unwrap();
+
+    assert_eq!(
+        refusal(join(&addr, "hw-b", 2).await),
+        Some(ErrorCode::RoomFull)
+    );
+    assert_eq!(
+        refusal(join(&addr, "hw-c", 3).await),
+        Some(ErrorCode::PermissionDenied)
+    );
+    join(&addr, "hw-admin", 3).await.unwrap();
+}
+
+#[tokio::test]
+async fn queued_users_are_admitted_in_order() {
+    let addr = start(server().await).await;
+    let alice = join(&addr, "hw-a", 4).await.unwrap();
+    let mut bob = join(&addr, "hw-b", 1).await.unwrap();
+    let mut carol = join(&addr, "hw-c", 1).await.unwrap();
+    let dave = join(&addr, "hw-d", 1).await.unwrap();
+
+    let (bob_switch, carol_switch, ()) = tokio::join!(
+        bob.switch(4),
+        async {
+            sleep(Duration::from_millis(100)).await;
+            timeout(Duration::from_millis(1500), carol.switch(4)).await
+        },
+        async {
+            sleep(Duration::from_millis(300)).await;
+            // The queue only holds two.
+            assert_eq!(refusal(dave.switch(4).await), Some(ErrorCode::RoomFull));
+            alice.switch(1).await.unwrap();
+        },
+    );
+
+    bob_switch.unwrap();
+    assert_eq!(bob.room_id(), 4);
+    assert!(carol_switch.is_err(), "carol should still be waiting");
+    assert_eq!(carol.room_id(), 1);
+    assert_eq!(positions(&mut bob).await, vec![1]);
+    assert_eq!(positions(&mut carol).await, vec![2, 1]);
+}
+
+async fn private_server() -> (Server, String) {
+    let server = server().await;
+    let config = RoomConfig {
+        password_hash: Some(pigeonvc2::server::hash_password("hunter2").unwrap()),
+        ..RoomConfig::default()
+    };
+    server.add_room_with_config(5, "Vault", config);
+    server.add_room_with_config(
+        6,
+        "Backstage",
+        RoomConfig {
+            invite_only: true,
+            ..RoomConfig::default()
+        },
+    );
+    let invite = server.create_invite(6, Duration::from_secs(60));
+    (server, invite)
+}
+
+async fn join_private(
+    addr: &str,
+    hwid: &str,
+    room_id: u16,
+    credential: &str,
+) -> anyhow::Result<VoiceSession> {
+    Client::connect(addr.to_string())
+        .await?
+        .join_with_credential(hwid, hwid, room_id, credential)
+        .await
+}
+
+#[tokio::test]
+async fn password_rooms_need_the_password() {
+    let (server, _) = private_server().await;
+    let addr = start(server).await;
+
+    assert_eq!(
+        refusal(join(&addr, "hw-a", 5).await),
+        Some(ErrorCode::Unauthorized)
+    );
+    assert_eq!(
+        refusal(join_private(&addr, "hw-a", 5, "hunter3").await),
+        Some(ErrorCode::Unauthorized)
+    );
+    let a = join_private(&addr, "hw-a", 5, "hunter2").await.unwrap();
+    assert_eq!(a.room_id(), 5);
+
+    let b = join(&addr, "hw-b", 1).await.unwrap();
+    assert_eq!(refusal(b.switch(5).await), Some(ErrorCode::Unauthorized));
+    assert_eq!(b.room_id(), 1);
+    b.switch_with_credential(5, "hunter2").await.unwrap();
+    assert_eq!(b.room_id(), 5);
+}
+
+#[tokio::test]
+async fn invite_codes_open_their_room_until_they_expire() {
+    let (server, invite) = private_server().await;
+    server.add_invite("EXPIRED", 6, 1);
+    let addr = start(server).await;
+
+    // An invite-only room ignores passwords, and invites are per room.
+    assert_eq!(
+        refusal(join_private(&addr, "hw-a", 6, "hunter2").await),
+        Some(ErrorCode::Unauthorized)
+    );
+    assert_eq!(
+        refusal(join_private(&addr, "hw-a", 6, "EXPIRED").await),
+        Some(ErrorCode::Unauthorized)
+    );
+    assert_eq!(
+        refusal(join_private(&addr, "hw-a", 5, &invite).await),
+        Some(ErrorCode::Unauthorized)
+    );
+
+    let a = join_private(&addr, "hw-a", 6, &invite.to_lowercase())
+        .await
+        .unwrap();
+    assert_eq!(a.room_id(), 6);
+    let b = join(&addr, "hw-b", 1).await.unwrap();
+    b.switch_with_credential(6, &invite).await.unwrap();
+    assert_eq!(b.room_id(), 6);
+}
+
+#[tokio::test]
+async fn synced_invites_replace_the_old_ones() {
+    let (server, invite) = private_server().await;
+    let server = Arc::new(server);
+    let addr = serve(server.clone()).await;
+
+    // The stored copy of `invite` was revoked, and a new one issued.
+    server.sync_invites(&[("STORED".to_string(), 6, u64::MAX)]);
+    assert_eq!(
+        refusal(join_private(&addr, "hw-a", 6, &invite).await),
+        Some(ErrorCode::Unauthorized)
+    );
+    let a = join_private(&addr, "hw-a", 6, "stored").await.unwrap();
+    assert_eq!(a.room_id(), 6);
+}
+
+#[tokio::test]
+async fn repeated_wrong_credentials_lock_the_address_out() {
+    let (server, invite) = private_server().await;
+    let addr = start(server).await;
+
+    let a = join(&addr, "hw-a", 1).await.unwrap();
+    for _ in 0..5 {
+        assert_eq!(
+            refusal(a.switch_with_credential(6, "GUESS").await),
+            Some(ErrorCode::Unauthorized)
+        );
+    }
+    assert_eq!(
+        refusal(a.switch_with_credential(6, &invite).await),
+        Some(ErrorCode::Unauthorized)
+    );
+
+    // A fresh hwid is no way around it, but other rooms are unaffected.
+    assert_eq!(
+        refusal(join_private(&addr, "hw-b", 6, &invite).await),
+        Some(ErrorCode::Unauthorized)
+    );
+    let b = join_private(&addr, "hw-b", 5, "hunter2").await.unwrap();
+    assert_eq!(b.room_id(), 5);
+}
+
+#[tokio::test]
+async fn password_checks_do_not_hold_up_other_packets() {
+    let (server, _) = private_server().await;
+    let addr = start(server).await;
+    let pinger = Client::connect(addr.clone()).await.unwrap();
+
+    let guess = tokio::spawn({
+        let addr = addr.clone();
+        async move { refusal(join_private(&addr, "hw-a", 5, "hunter3").await) }
+    });
+    sleep(Duration::from_millis(20)).await;
+    pinger.ping().await.unwrap();
+    assert!(!guess.is_finished());
+    assert_eq!(guess.await.unwrap(), Some(ErrorCode::Unauthorized));
+}
+
+/// Records every change, or refuses them all when `fail` is set.
+#[derive(Clone, Default)]
+struct RecordingStore {
+    log: Arc<Mutex<Vec<String>>>,
+    fail: bool,
+}
+
+impl RecordingStore {
+    fn record(&self, entry: String) -> StoreFuture<'_, ()> {
+        let result = if self.fail {
+            Err(anyhow::anyhow!("storage is down"))
+        } else {
+            self.log.lock().unwrap().push(entry);
+            Ok(())
+        };
+        Box::pin(async move { result })
+    }
+}
+
+impl RoomStore for RecordingStore {
+    fn create_room<'a>(
+        &'a self,
+        room_id: u16,
+        name: &'a str,
+        _: &'a RoomConfig,
+    ) -> StoreFuture<'a, ()> {
+        self.record(format!("create {room_id} {name}"))
+    }
+
+    fn rename_room<'a>(&'a self, room_id: u16, name: &'a str) -> StoreFuture<'a, ()> {
+        self.record(format!("rename {room_id} {name}"))
+    }
+
+    fn set_room_limits(
+        &self,
+        room_id: u16,
+        max_users: Option<u16>,
+        max_queue: u16,
+    ) -> StoreFuture<'_, ()> {
+        self.record(format!("limits {room_id} {max_users:?} {max_queue}"))
+    }
+
+    fn delete_room(&self, room_id: u16) -> StoreFuture<'_, ()> {
+        self.record(format!("delete {room_id}"))
+    }
+}
+
+async fn next_change(session: &mut VoiceSession) -> (RoomChange, u16, String) {
+    loop {
+        let event = timeout(Duration::from_secs(2), session.recv())
+            .await
+            .expect("no room change arrived")
+            .unwrap();
+        if let SessionEvent::RoomChanged {
+            change,
+            room_id,
+            name,
+        } = event
+        {
+            return (change, room_id, name);
+        }
+    }
+}
+
+async fn room_list(addr: &str) -> Vec<(u16, String)> {
+    Client::connect(addr.to_string())
+        .await
+        .unwrap()
+        .rooms()
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn rooms_change_at_runtime() {
+    let store = RecordingStore::default();
+    let server = Arc::new(server().await.with_room_store(store.clone()));
+    let addr = serve(server.clone()).await;
+
+    let a = join(&addr, "hw-a", 2).await.unwrap();
+    let mut b = join(&addr, "hw-b", 1).await.unwrap();
+
+    let room_id = server
+        .create_room("Studio", RoomConfig::default())
+        .await
+        .unwrap();
+    assert_eq!(room_id, 5);
+    assert_eq!(
+        next_change(&mut b).await,
+        (RoomChange::Created, 5, "Studio".to_string())
+    );
+
+    server.rename_room(5, "Loft").await.unwrap();
+    assert_eq!(
+        next_change(&mut b).await,
+        (RoomChange::Renamed, 5, "Loft".to_string())
+    );
+    assert!(server.rename_room(5, "Lobby").await.is_err());
+
+    // The lobby is the default fallback room.
+    assert!(server.delete_room(1).await.is_err());
+    server.delete_room(2).await.unwrap();
+    assert_eq!(
+        next_change(&mut b).await,
+        (RoomChange::Deleted, 2, "Booth".to_string())
+    );
+    for _ in 0..20 {
+        if a.room_id() == 1 {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(a.room_id(), 1);
+    assert_eq!(refusal(a.switch(2).await), Some(ErrorCode::UnknownRoom));
+
+    assert_eq!(
+        room_list(&addr).await,
+        vec![
+            (1, "Lobby".to_string()),
+            (3, "Admins".to_string()),
+            (4, "Stage".to_string()),
+            (5, "Loft".to_string()),
+        ]
+    );
+    assert_eq!(
+        *store.log.lock().unwrap(),
+        ["create 5 Studio", "rename 5 Loft", "delete 2"]
+    );
+}
+
+#[tokio::test]
+async fn room_limits_change_at_runtime() {
+    let store = RecordingStore::default();
+    let server = Arc::new(server().await.with_room_store(store.clone()));
+    let addr = serve(server.clone()).await;
+
+    let _a = join(&addr, "hw-a", 4).await.unwrap();
+    let b = join(&addr, "hw-b", 1).await.unwrap();
+    let _c = join(&addr, "hw-c", 2).await.unwrap();
+    assert_eq!(
+        refusal(join(&addr, "hw-d", 2).await),
+        Some(ErrorCode::RoomFull)
+    );
+
+    // Growing the stage lets its line in.
+    let (switched, ()) = tokio::join!(b.switch(4), async {
+        sleep(Duration::from_millis(100)).await;
+        server.set_room_limits(4, Some(2), 0).await.unwrap();
+    });
+    switched.unwrap();
+    assert_eq!(b.room_id(), 4);
+
+    server.set_room_limits(2, None, 0).await.unwrap();
+    let d = join(&addr, "hw-d", 2).await.unwrap();
+    assert_eq!(d.room_id(), 2);
+    assert!(server.set_room_limits(9, None, 0).await.is_err());
+
+    assert_eq!(
+        *store.log.lock().unwrap(),
+        ["limits 4 Some(2) 0", "limits 2 None 0"]
+    );
+}
+
+#[tokio::test]
+async fn failed_storage_leaves_rooms_unchanged() {
+    let store = RecordingStore {
+        fail: true,
+        ..RecordingStore::default()
+    };
+    let server = Arc::new(server().await.with_room_store(store));
+    let addr = serve(server.clone()).await;
+
+    let a = join(&addr, "hw-a", 2).await.unwrap();
+    assert!(
+        server
+            .create_room("Studio", RoomConfig::default())
+            .await
+            .is_err()
+    );
+    assert!(server.rename_room(2, "Closet").await.is_err());
+    assert!(server.delete_room(2).await.is_err());
+
+    assert_eq!(room_list(&addr).await.len(), 4);
+    assert_eq!(room_list(&addr).await[1], (2, "Booth".to_string()));
+    assert_eq!(a.room_id(), 2);
+}
+
+#[tokio::test]
+async fn busy_rooms_do_not_throttle_clients_sharing_an_address() {
+    let server = Arc::new(server().await);
+    let addr = serve(server.clone()).await;
+
+    // Every client here shares 127.0.0.1, and each change costs one ACK each.
+    let mut sessions = Vec::new();
+    for i in 0..5 {
+        sessions.push(join(&addr, &format!("hw-{i}"), 1).await.unwrap());
+    }
+    for i in 0..30 {
+        server.rename_room(4, &format!("Stage {i}")).await.unwrap();
+    }
+    for session in &mut sessions {
+        for i in 0..30 {
+            assert_eq!(next_change(session).await.2, format!("Stage {i}"));
+        }
+    }
+    assert_eq!(server.drop_stats().rate_limited, 0);
+}
+
+#[tokio::test]
+async fn switch_racing_a_delete_ends_in_the_fallback_room() {
+    let server = Server::new("127.0.0.1:0".into(), |_| async { Ok(()) }, |_| async {})
+        .await
+        .unwrap()
+        .with_room_permission(|_, room_id| async move {
+            if room_id == 2 {
+                sleep(Duration::from_millis(200)).await;
+            }
+            Ok(())
+        });
+    server.add_room_with_id(1, "Lobby");
+    server.add_room_with_id(2, "Booth");
+    let server = Arc::new(server);
+    let addr = serve(server.clone()).await;
+
+    let a = join(&addr, "hw-a", 1).await.unwrap();
+    let switching = tokio::spawn(async move {
+        let switched = a.switch(2).await;
+        (a, switched)
+    });
+    sleep(Duration::from_millis(50)).await;
+    server.delete_room(2).await.unwrap();
+
+    let (a, _) = switching.await.unwrap();
+    for _ in 0..20 {
+        if a.room_id() == 1 {
+            break;
+        }
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_eq!(a.room_id(), 1);
+}
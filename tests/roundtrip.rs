@@ -0,0 +1,344 @@
+use pigeonvc2::protocol::{
+    self, CodecPolicy, Decode, DisconnectReason, Encode, ErrorCode, EventKind, PacketType,
+    RoomChange, SnapshotRoom,
+};
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+
+fn cstring() -> impl Strategy<Value = String> {
+    "[^\u{0}]{0,32}"
+}
+
+fn codec() -> impl Strategy<Value = CodecPolicy> {
+    (any::<u8>(), any::<u32>(), any::<u8>(), any::<u16>()).prop_map(
+        |(payload_type, sample_rate, channels, max_frame_len)| CodecPolicy {
+            payload_type,
+            sample_rate,
+            channels,
+            max_frame_len,
+        },
+    )
+}
+
+fn client_roundtrip(buf: Vec<u8>, expected: PacketType) {
+    assert_eq!(buf, expected.encode());
+    assert_eq!(protocol::parse_from_client_packet(&buf).unwrap(), expected);
+    assert_eq!(PacketType::decode(&buf).unwrap(), expected);
+}
+
+fn server_roundtrip(buf: Vec<u8>, expected: PacketType) {
+    assert_eq!(buf, expected.encode());
+    assert_eq!(protocol::parse_from_server_packet(&buf).unwrap(), expected);
+    assert_eq!(PacketType::decode(&buf).unwrap(), expected);
+}
+
+#[test]
+fn empty_packets_roundtrip() {
+    client_roundtrip(protocol::new_ping(), PacketType::Ping);
+    server_roundtrip(protocol::new_pong(), PacketType::Pong);
+    server_roundtrip(protocol::new_alived(), PacketType::Alived);
+}
+
+proptest! {
+    #[test]
+    fn join_roundtrip(
+        name in cstring(),
+        hwid in cstring(),
+        room_id: u16,
+        version: u16,
+        capabilities: u32,
+        credential in cstring(),
+        cookie: Option<[u8; protocol::COOKIE_LEN]>,
+    ) {
+        client_roundtrip(
+            protocol::new_join(&name, &hwid, room_id, version, capabilities, &credential, cookie),
+            PacketType::Join {
+                name: name.into(),
+                hwid: hwid.into(),
+                room_id,
+                version,
+                capabilities,
+                credential: credential.into(),
+                cookie,
+            },
+        );
+    }
+
+    #[test]
+    fn talk_roundtrip(
+        seq: u16,
+        timestamp: u32,
+        payload_type: u8,
+        audio_data in proptest::collection::vec(any::<u8>(), 0..1400),
+    ) {
+        client_roundtrip(
+            protocol::new_talk(seq, timestamp, payload_type, &audio_data),
+            PacketType::Talk { seq, timestamp, payload_type, audio_data: audio_data.into() },
+        );
+    }
+
+    #[test]
+    fn rooms_roundtrip(offset: u16) {
+        client_roundtrip(protocol::new_rooms(offset), PacketType::Rooms { offset });
+    }
+
+    #[test]
+    fn switch_roundtrip(room_id: u16, token: u64, request_id: u32, credential in cstring()) {
+        client_roundtrip(
+            protocol::new_switch(room_id, token, request_id, &credential),
+            PacketType::Switch { room_id, token, request_id, credential: credential.into() },
+        );
+    }
+
+    #[test]
+    fn alive_roundtrip(
+        token: u64,
+        seqs in proptest::collection::vec((any::<u16>(), any::<u64>()), 0..16),
+    ) {
+        client_roundtrip(protocol::new_alive(token, &seqs), PacketType::Alive { token, seqs });
+    }
+
+    #[test]
+    fn subscribe_roundtrip(token: u64, rooms in proptest::collection::vec(any::<u16>(), 0..16)) {
+        client_roundtrip(
+            protocol::new_subscribe(token, &rooms),
+            PacketType::Subscribe { token, rooms },
+        );
+    }
+
+    #[test]
+    fn leave_roundtrip(token: u64) {
+        client_roundtrip(protocol::new_leave(token), PacketType::Leave { token });
+    }
+
+    #[test]
+    fn rooms_list_roundtrip(
+        remaining: bool,
+        list in proptest::collection::vec((any::<u16>(), cstring()), 0..16),
+    ) {
+        server_roundtrip(
+            protocol::new_rooms_list(remaining, list.clone()),
+            PacketType::RoomsList {
+                remaining,
+                list: list.into_iter().map(|(id, name)| (id, name.into())).collect(),
+            },
+        );
+    }
+
+    #[test]
+    fn joined_roundtrip(
+        room_id: u16,
+        seq: u64,
+        codec in codec(),
+        users in proptest::collection::vec((any::<u64>(), cstring()), 0..16),
+    ) {
+        server_roundtrip(
+            protocol::new_joined(room_id, seq, codec, users.clone()),
+            PacketType::Joined {
+                room_id,
+                seq,
+                codec,
+                users: users.into_iter().map(|(id, name)| (id, name.into())).collect(),
+            },
+        );
+    }
+
+    #[test]
+    fn talked_roundtrip(
+        talker: u64,
+        seq: u16,
+        timestamp: u32,
+        payload_type: u8,
+        audio_data in proptest::collection::vec(any::<u8>(), 0..1400),
+    ) {
+        server_roundtrip(
+            protocol::new_talked_audio(talker, seq, timestamp, payload_type, &audio_data),
+            PacketType::Talked { talker, seq, timestamp, payload_type, audio_data: audio_data.into() },
+        );
+    }
+
+    #[test]
+    fn parity_roundtrip(
+        talker: u64,
+        seq: u16,
+        count: u8,
+        timestamp: u32,
+        parity in proptest::collection::vec(any::<u8>(), 0..1400),
+    ) {
+        client_roundtrip(
+            protocol::new_parity(seq, count, timestamp, &parity),
+            PacketType::Parity { seq, count, timestamp, parity: parity.clone().into() },
+        );
+        server_roundtrip(
+            protocol::new_talked_parity(talker, seq, count, timestamp, &parity),
+            PacketType::TalkedParity { talker, seq, count, timestamp, parity: parity.into() },
+        );
+    }
+
+    #[test]
+    fn accepted_roundtrip(user_id: u64, version: u16, capabilities: u32, token: u64) {
+        server_roundtrip(
+            protocol::new_accepted(user_id, version, capabilities, token),
+            PacketType::Accepted { user_id, version, capabilities, token },
+        );
+    }
+
+    #[test]
+    fn event_roundtrip(seq: u64, kind in 0u8..4, room_id: u16, user_id: u64, name in cstring()) {
+        let kind = EventKind::from_u8(kind).unwrap();
+        server_roundtrip(
+            protocol::new_event(seq, kind, room_id, user_id, &name),
+            PacketType::Event { seq, kind, room_id, user_id, name: name.into() },
+        );
+    }
+
+    #[test]
+    fn handshake_roundtrip(public_key: [u8; 32], cookie: Option<[u8; protocol::COOKIE_LEN]>) {
+        client_roundtrip(
+            protocol::new_handshake(public_key, cookie),
+            PacketType::Handshake { public_key, cookie },
+        );
+    }
+
+    #[test]
+    fn cookie_roundtrip(cookie: [u8; protocol::COOKIE_LEN]) {
+        server_roundtrip(protocol::new_cookie(cookie), PacketType::Cookie { cookie });
+    }
+
+    #[test]
+    fn error_roundtrip(code in 1u16..=8, request_type: u32, request_id: u32, detail in cstring()) {
+        let code = ErrorCode::from_u16(code).unwrap();
+        server_roundtrip(
+            protocol::new_error(code, request_type, request_id, &detail),
+            PacketType::Error { code, request_type, request_id, detail: detail.into() },
+        );
+    }
+
+    #[test]
+    fn switched_roundtrip(request_id: u32, room_id: u16) {
+        server_roundtrip(
+            protocol::new_switched(request_id, room_id),
+            PacketType::Switched { request_id, room_id },
+        );
+    }
+
+    #[test]
+    fn queued_roundtrip(request_id: u32, room_id: u16, position: u16) {
+        server_roundtrip(
+            protocol::new_queued(request_id, room_id, position),
+            PacketType::Queued { request_id, room_id, position },
+        );
+    }
+
+    #[test]
+    fn room_changed_roundtrip(change in 0u8..3, room_id: u16, name in cstring()) {
+        let change = RoomChange::from_u8(change).unwrap();
+        server_roundtrip(
+            protocol::new_room_changed(change, room_id, &name),
+            PacketType::RoomChanged { change, room_id, name: name.into() },
+        );
+    }
+
+    #[test]
+    fn disconnect_roundtrip(reason in 1u16..=9, detail in cstring()) {
+        let reason = DisconnectReason::from_u16(reason).unwrap();
+        server_roundtrip(
+            protocol::new_disconnect(reason, &detail),
+            PacketType::Disconnect { reason, detail: detail.into() },
+        );
+    }
+
+    #[test]
+    fn reliable_roundtrip(seq: u32, payload in proptest::collection::vec(any::<u8>(), 0..1400)) {
+        server_roundtrip(
+            protocol::new_reliable(seq, &payload),
+            PacketType::Reliable { seq, payload: payload.into() },
+        );
+    }
+
+    #[test]
+    fn ack_roundtrip(token: u64, cumulative: u32, sack: u64) {
+        client_roundtrip(
+            protocol::new_ack(token, cumulative, sack),
+            PacketType::Ack { token, cumulative, sack },
+        );
+    }
+
+    #[test]
+    fn fragment_roundtrip(
+        frag_id: u32,
+        (index, count) in (1u16..64).prop_flat_map(|count| (0..count, Just(count))),
+        chunk in proptest::collection::vec(any::<u8>(), 0..1200),
+    ) {
+        let expected = PacketType::Fragment { frag_id, index, count, chunk: chunk.clone().into() };
+        client_roundtrip(protocol::new_fragment(frag_id, index, count, &chunk), expected.clone());
+        server_roundtrip(protocol::new_fragment(frag_id, index, count, &chunk), expected);
+    }
+
+    #[test]
+    fn snapshot_parts_reassemble(
+        id: u32,
+        rooms in proptest::collection::btree_map(
+            any::<u16>(),
+            (any::<u64>(), proptest::collection::vec((any::<u64>(), cstring()), 0..60)),
+            0..12,
+        ),
+    ) {
+        let state: Vec<SnapshotRoom> = rooms
+            .iter()
+            .map(|(room_id, (seq, users))| {
+                let users = users.iter().map(|(id, name)| (*id, name.as_str().into())).collect();
+                (*room_id, *seq, users)
+            })
+            .collect();
+        let packets = protocol::new_snapshot(id, &state);
+
+        let mut merged: BTreeMap<u16, (u64, Vec<(u64, String)>)> = BTreeMap::new();
+        for (index, buf) in packets.iter().enumerate() {
+            prop_assert!(buf.len() <= 20 + protocol::SNAPSHOT_PART_LEN);
+            let PacketType::Snapshot { id: got_id, part, parts, rooms } =
+                protocol::parse_from_server_packet(buf).unwrap()
+            else {
+                panic!("expected snapshot");
+            };
+            prop_assert_eq!(got_id, id);
+            prop_assert_eq!(part as usize, index);
+            prop_assert_eq!(parts as usize, packets.len());
+            for (room_id, seq, users) in rooms {
+                let entry = merged.entry(room_id).or_default();
+                entry.0 = seq;
+                entry.1.extend(users.into_iter().map(|(id, name)| (id, name.into_owned())));
+            }
+        }
+        prop_assert_eq!(merged, rooms);
+    }
+}
+
+#[test]
+fn legacy_join_decodes_as_version_zero() {
+    let mut buf = protocol::new_join("name", "hwid", 3, 0, 0, "", None);
+    buf.truncate(buf.len() - 7);
+    assert_eq!(
+        protocol::parse_from_client_packet(&buf).unwrap(),
+        PacketType::Join {
+            name: "name".into(),
+            hwid: "hwid".into(),
+            room_id: 3,
+            version: 0,
+            capabilities: 0,
+            credential: "".into(),
+            cookie: None,
+        }
+    );
+}
+
+#[test]
+fn decoded_audio_borrows_from_input() {
+    let buf = protocol::new_talk(7, 960, protocol::PAYLOAD_OPUS, &[1, 2, 3]);
+    let PacketType::Talk { audio_data, .. } = protocol::parse_from_client_packet(&buf).unwrap()
+    else {
+        panic!("expected talk");
+    };
+    assert!(matches!(audio_data, std::borrow::Cow::Borrowed(_)));
+    assert_eq!(audio_data.as_ptr(), buf[15..].as_ptr());
+}
@@ -0,0 +1,137 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use pigeonvc2::client::Client;
+use pigeonvc2::protocol::{self, CryptoSession, KeyExchange, PacketType};
+use pigeonvc2::server::Server;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+async fn server() -> Server {
+    let server = Server::new("127.0.0.1:0".into(), |_| async { Ok(()) }, |_| async {})
+        .await
+        .unwrap();
+    server.add_room_with_id(1, "Lobby");
+    server
+}
+
+async fn recv(socket: &UdpSocket) -> Vec<u8> {
+    let mut buf = vec![0u8; 2048];
+    let len = timeout(Duration::from_secs(1), socket.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    buf.truncate(len);
+    buf
+}
+
+/// Runs a handshake for `addr` by feeding the server directly, as anyone
+/// who can forge that source address could.
+async fn handshake(server: &Server, socket: &UdpSocket, addr: SocketAddr) -> CryptoSession {
+    let kx = KeyExchange::new();
+    server
+        .receive(addr, &protocol::new_handshake(kx.public_key(), None))
+        .await
+        .unwrap();
+    let reply = recv(socket).await;
+    let Ok(PacketType::Cookie { cookie }) = protocol::parse_from_server_packet(&reply) else {
+        panic!("expected COOKIE");
+    };
+    server
+        .receive(
+            addr,
+            &protocol::new_handshake(kx.public_key(), Some(cookie)),
+        )
+        .await
+        .unwrap();
+    let reply = recv(socket).await;
+    let PacketType::Handshaked {
+        session_id,
+        public_key,
+        identity,
+    } = protocol::parse_from_server_packet(&reply).unwrap()
+    else {
+        panic!("expected HANDSHAKED");
+    };
+    assert_eq!(identity, server.identity_key());
+    kx.finish_client(public_key, identity, session_id)
+}
+
+async fn ping(server: &Server, socket: &UdpSocket, addr: SocketAddr, crypto: &CryptoSession) {
+    server
+        .receive(addr, &crypto.seal(&protocol::new_ping()))
+        .await
+        .unwrap();
+    let reply = crypto.open(&recv(socket).await).unwrap();
+    assert!(matches!(
+        protocol::parse_from_server_packet(&reply),
+        Ok(PacketType::Pong)
+    ));
+}
+
+#[tokio::test]
+async fn forged_handshake_does_not_replace_a_live_session() {
+    let server = server().await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(server.local_addr().unwrap()).await.unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    let victim = handshake(&server, &socket, addr).await;
+    ping(&server, &socket, addr, &victim).await;
+
+    // The forged exchange gets an answer, but nothing sealed with it.
+    let _forged = handshake(&server, &socket, addr).await;
+    ping(&server, &socket, addr, &victim).await;
+}
+
+#[tokio::test]
+async fn unproven_sources_only_get_a_cookie() {
+    let server = server().await;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+
+    for packet in [
+        protocol::new_handshake(KeyExchange::new().public_key(), None),
+        protocol::new_handshake(
+            KeyExchange::new().public_key(),
+            Some([0; protocol::COOKIE_LEN]),
+        ),
+        protocol::new_join("alice", "hw-a", 1, protocol::PROTOCOL_VERSION, 0, "", None),
+    ] {
+        server.receive(addr, &packet).await.unwrap();
+        let reply = recv(&socket).await;
+        assert!(matches!(
+            protocol::parse_from_server_packet(&reply),
+            Ok(PacketType::Cookie { .. })
+        ));
+    }
+}
+
+#[tokio::test]
+async fn pinned_server_key_is_enforced() {
+    let server = Arc::new(server().await);
+    let addr = server.local_addr().unwrap().to_string();
+    let identity = server.identity_key();
+    {
+        let server = server.clone();
+        tokio::spawn(async move { server.listen().await });
+    }
+
+    let client = Client::connect(addr.clone())
+        .await
+        .unwrap()
+        .with_server_key(identity)
+        .secure()
+        .await
+        .unwrap();
+    assert!(client.is_secure());
+
+    let impostor = Client::connect(addr)
+        .await
+        .unwrap()
+        .with_server_key([7; 32])
+        .secure()
+        .await;
+    assert!(impostor.is_err());
+}
@@ -0,0 +1,536 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use pigeonvc2::client::{Client, SessionEvent, VoiceSession};
+use pigeonvc2::protocol::{self, DisconnectReason, ErrorCode, PacketType, ReliableReceiver};
+use pigeonvc2::server::Server;
+use tokio::net::UdpSocket;
+use tokio::time::{Instant, timeout};
+
+async fn start() -> String {
+    let server = Server::new("127.0.0.1:0".into(), |_| async { Ok(()) }, |_| async {})
+        .await
+        .unwrap();
+    server.add_room_with_id(1, "Lobby");
+    server.add_room_with_id(2, "Music");
+    let server = Arc::new(server);
+    let addr = server.local_addr().unwrap().to_string();
+    {
+        let server = server.clone();
+        tokio::spawn(async move { server.listen().await });
+    }
+    {
+        let server = server.clone();
+        tokio::spawn(async move { server.retransmit_routine().await });
+    }
+    {
+        let server = server.clone();
+        tokio::spawn(async move { server.routine().await });
+    }
+    addr
+}
+
+async fn join(addr: &str, hwid: &str, room_id: u16) -> VoiceSession {
+    Client::connect(addr.to_string())
+        .await
+        .unwrap()
+        .join(hwid, hwid, room_id)
+        .await
+        .unwrap()
+}
+
+/// A bare socket standing in for a client whose NAT mapping changed.
+async fn rebound(addr: &str) -> UdpSocket {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(addr).await.unwrap();
+    socket
+}
+
+/// Joins through `socket` by hand and returns the user id and session token.
+async fn raw_join(socket: &UdpSocket, hwid: &str, room_id: u16, capabilities: u32) -> (u64, u64) {
+    let join = |cookie| {
+        protocol::new_join(
+            hwid,
+            hwid,
+            room_id,
+            protocol::PROTOCOL_VERSION,
+            capabilities,
+            "",
+            cookie,
+        )
+    };
+    socket.send(&join(None)).await.unwrap();
+    let cookie = wait_for(socket, |pkt| match pkt {
+        PacketType::Cookie { cookie } => Some(cookie),
+        _ => None,
+    })
+    .await;
+    socket.send(&join(Some(cookie))).await.unwrap();
+    wait_for(socket, |pkt| match pkt {
+        PacketType::Accepted { user_id, token, .. } => Some((user_id, token)),
+        _ => None,
+    })
+    .await
+}
+
+/// Waits up to a second for a packet on `socket` that `pick` accepts.
+async fn wait_for<T>(socket: &UdpSocket, mut pick: impl FnMut(PacketType) -> Option<T>) -> T {
+    let deadline = Instant::now() + Duration::from_secs(1);
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let len = timeout(deadline - Instant::now(), socket.recv(&mut buf))
+            .await
+            .expect("no matching packet arrived")
+            .unwrap();
+        if let Some(found) = protocol::parse_from_server_packet(&buf[..len])
+            .ok()
+            .and_then(&mut pick)
+        {
+            return found;
+        }
+    }
+}
+
+#[tokio::test]
+async fn valid_token_from_new_address_moves_the_user() {
+    let addr = start().await;
+    // A bare client, so no keepalive from the old address moves it back.
+    let (_, token) = raw_join(&rebound(&addr).await, "hw-a", 1, 0).await;
+    let bob = join(&addr, "hw-b", 1).await;
+
+    let socket = rebound(&addr).await;
+    socket.send(&protocol::new_alive(token, &[])).await.unwrap();
+    wait_for(&socket, |pkt| {
+        matches!(pkt, PacketType::Alived).then_some(())
+    })
+    .await;
+
+    bob.talk(protocol::PAYLOAD_OPUS, 480, b"hello")
+        .await
+        .unwrap();
+    let talker = wait_for(&socket, |pkt| match pkt {
+        PacketType::Talked { talker, .. } => Some(talker),
+        _ => None,
+    })
+    .await;
+    assert_eq!(talker, bob.user_id());
+}
+
+#[tokio::test]
+async fn any_request_with_a_valid_token_moves_the_user() {
+    let addr = start().await;
+    let (_, token) = raw_join(&rebound(&addr).await, "hw-a", 1, 0).await;
+
+    let socket = rebound(&addr).await;
+    socket
+        .send(&protocol::new_switch(2, token, 1, ""))
+        .await
+        .unwrap();
+    let room_id = wait_for(&socket, |pkt| {
+        let PacketType::Reliable { payload, .. } = pkt else {
+            return None;
+        };
+        match protocol::parse_from_server_packet(&payload) {
+            Ok(PacketType::Switched {
+                request_id: 1,
+                room_id,
+            }) => Some(room_id),
+            _ => None,
+        }
+    })
+    .await;
+    assert_eq!(room_id, 2);
+}
+
+/// The next presence change `session` sees for `user_id`, skipping the rest.
+async fn presence(session: &mut VoiceSession, user_id: u64, within: Duration) -> SessionEvent {
+    let deadline = Instant::now() + within;
+    loop {
+        let event = timeout(deadline - Instant::now(), session.recv())
+            .await
+            .expect("no presence change arrived")
+            .unwrap();
+        match &event {
+            SessionEvent::UserConnectionLost { user_id: id, .. }
+            | SessionEvent::UserReconnected { user_id: id, .. }
+            | SessionEvent::UserLeft { user_id: id, .. }
+                if *id == user_id =>
+            {
+                return event;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[tokio::test]
+async fn lost_user_resumes_with_its_token() {
+    let addr = start().await;
+    let mut bob = join(&addr, "hw-b", 1).await;
+    let (alice_id, token) = raw_join(&rebound(&addr).await, "hw-a", 1, 0).await;
+
+    // Alice goes quiet until the server gives up on her connection.
+    let lost = presence(&mut bob, alice_id, Duration::from_secs(8)).await;
+    assert!(matches!(lost, SessionEvent::UserConnectionLost { .. }));
+
+    let socket = rebound(&addr).await;
+    socket.send(&protocol::new_alive(token, &[])).await.unwrap();
+    wait_for(&socket, |pkt| {
+        matches!(pkt, PacketType::Alived).then_some(())
+    })
+    .await;
+    let back = presence(&mut bob, alice_id, Duration::from_secs(1)).await;
+    assert!(matches!(
+        back,
+        SessionEvent::UserReconnected { room_id: 1, .. }
+    ));
+}
+
+#[tokio::test]
+async fn lost_user_resumes_with_its_hwid_from_anywhere() {
+    let addr = start().await;
+    let mut bob = join(&addr, "hw-b", 1).await;
+    let (alice_id, _) = raw_join(&rebound(&addr).await, "hw-a", 1, 0).await;
+
+    let lost = presence(&mut bob, alice_id, Duration::from_secs(8)).await;
+    assert!(matches!(lost, SessionEvent::UserConnectionLost { .. }));
+
+    let socket = rebound(&addr).await;
+    let (resumed_id, _) = raw_join(&socket, "hw-a", 1, 0).await;
+    assert_eq!(resumed_id, alice_id);
+    let back = presence(&mut bob, alice_id, Duration::from_secs(1)).await;
+    assert!(matches!(
+        back,
+        SessionEvent::UserReconnected { room_id: 1, .. }
+    ));
+
+    bob.talk(protocol::PAYLOAD_OPUS, 480, b"hello")
+        .await
+        .unwrap();
+    let talker = wait_for(&socket, |pkt| match pkt {
+        PacketType::Talked { talker, .. } => Some(talker),
+        _ => None,
+    })
+    .await;
+    assert_eq!(talker, bob.user_id());
+}
+
+#[tokio::test]
+async fn new_user_at_a_lost_address_replaces_the_old_one() {
+    let addr = start().await;
+    let mut bob = join(&addr, "hw-b", 1).await;
+    let socket = rebound(&addr).await;
+    let (alice_id, token) = raw_join(&socket, "hw-a", 1, 0).await;
+
+    let lost = presence(&mut bob, alice_id, Duration::from_secs(8)).await;
+    assert!(matches!(lost, SessionEvent::UserConnectionLost { .. }));
+
+    let (carol_id, _) = raw_join(&socket, "hw-c", 1, 0).await;
+    assert_ne!(carol_id, alice_id);
+    let gone = presence(&mut bob, alice_id, Duration::from_secs(1)).await;
+    assert!(matches!(gone, SessionEvent::UserLeft { .. }));
+
+    // Alice's session went with her.
+    let other = rebound(&addr).await;
+    other.send(&protocol::new_alive(token, &[])).await.unwrap();
+    let code = wait_for(&other, |pkt| match pkt {
+        PacketType::Error { code, .. } => Some(code),
+        _ => None,
+    })
+    .await;
+    assert_eq!(code, ErrorCode::NotJoined);
+
+    // And the room lists the address once, for Carol.
+    bob.talk(protocol::PAYLOAD_OPUS, 480, b"hello")
+        .await
+        .unwrap();
+    let mut heard = 0;
+    let mut buf = vec![0u8; 2048];
+    while let Ok(Ok(len)) = timeout(Duration::from_millis(300), socket.recv(&mut buf)).await {
+        if let Ok(PacketType::Talked { .. }) = protocol::parse_from_server_packet(&buf[..len]) {
+            heard += 1;
+        }
+    }
+    assert_eq!(heard, 1);
+}
+
+/// Room updates that reach `socket` within `within`, acknowledged only if
+/// `ack` is set.
+async fn room_updates(
+    socket: &UdpSocket,
+    token: u64,
+    receiver: &mut ReliableReceiver,
+    ack: bool,
+    within: Duration,
+) -> Vec<(u16, u64)> {
+    let deadline = Instant::now() + within;
+    let mut updates = Vec::new();
+    let mut buf = vec![0u8; 2048];
+    while let Ok(Ok(len)) = timeout(deadline - Instant::now(), socket.recv(&mut buf)).await {
+        let Ok(PacketType::Reliable { seq, payload }) =
+            protocol::parse_from_server_packet(&buf[..len])
+        else {
+            continue;
+        };
+        if ack {
+            receiver.accept(seq);
+            let (cumulative, sack) = receiver.ack();
+            socket
+                .send(&protocol::new_ack(token, cumulative, sack))
+                .await
+                .unwrap();
+        }
+        if let Ok(
+            PacketType::Event { room_id, seq, .. } | PacketType::Joined { room_id, seq, .. },
+        ) = protocol::parse_from_server_packet(&payload)
+        {
+            updates.push((room_id, seq));
+        }
+    }
+    updates
+}
+
+#[tokio::test]
+async fn resync_is_not_held_up_by_other_rooms() {
+    let addr = start().await;
+    let socket = rebound(&addr).await;
+    let (_, token) = raw_join(&socket, "hw-a", 1, 0).await;
+    let mut receiver = ReliableReceiver::new();
+    let short = Duration::from_millis(300);
+    room_updates(&socket, token, &mut receiver, true, short).await;
+
+    let _bob = join(&addr, "hw-b", 1).await;
+    let lobby = room_updates(&socket, token, &mut receiver, true, short).await;
+    let &(_, last_lobby_seq) = lobby.iter().rfind(|(room_id, _)| *room_id == 1).unwrap();
+
+    // Music updates stay unacknowledged, so they are still in flight.
+    socket
+        .send(&protocol::new_subscribe(token, &[2]))
+        .await
+        .unwrap();
+    let _carol = join(&addr, "hw-c", 2).await;
+    let music = room_updates(&socket, token, &mut receiver, false, short).await;
+    let &(_, last_music_seq) = music.iter().rfind(|(room_id, _)| *room_id == 2).unwrap();
+
+    socket
+        .send(&protocol::new_alive(
+            token,
+            &[(1, last_lobby_seq - 1), (2, last_music_seq)],
+        ))
+        .await
+        .unwrap();
+    let resent = room_updates(&socket, token, &mut receiver, false, short).await;
+    assert!(resent.contains(&(1, last_lobby_seq)));
+}
+
+/// Ids of the SNAPSHOT packets that reach `socket` soon, acknowledging
+/// everything reliable on the way.
+async fn snapshots(socket: &UdpSocket, token: u64, receiver: &mut ReliableReceiver) -> Vec<u32> {
+    let deadline = Instant::now() + Duration::from_millis(300);
+    let mut ids = Vec::new();
+    let mut buf = vec![0u8; 2048];
+    while let Ok(Ok(len)) = timeout(deadline - Instant::now(), socket.recv(&mut buf)).await {
+        let Ok(PacketType::Reliable { seq, payload }) =
+            protocol::parse_from_server_packet(&buf[..len])
+        else {
+            continue;
+        };
+        receiver.accept(seq);
+        let (cumulative, sack) = receiver.ack();
+        socket
+            .send(&protocol::new_ack(token, cumulative, sack))
+            .await
+            .unwrap();
+        if let Ok(PacketType::Snapshot { id, .. }) = protocol::parse_from_server_packet(&payload) {
+            ids.push(id);
+        }
+    }
+    ids
+}
+
+#[tokio::test]
+async fn client_that_stays_behind_gets_a_snapshot() {
+    let addr = start().await;
+    let socket = rebound(&addr).await;
+    let (_, token) = raw_join(&socket, "hw-a", 1, 0).await;
+    let mut receiver = ReliableReceiver::new();
+    let _bob = join(&addr, "hw-b", 1).await;
+    assert!(snapshots(&socket, token, &mut receiver).await.is_empty());
+
+    // Resent events never seem to arrive, so the server stops replaying
+    // history and sends the room's whole state instead.
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        socket
+            .send(&protocol::new_alive(token, &[(1, 0)]))
+            .await
+            .unwrap();
+        received.push(snapshots(&socket, token, &mut receiver).await.len());
+    }
+    assert_eq!(received, vec![0, 0, 1]);
+}
+
+#[tokio::test]
+async fn unsubscribed_rooms_send_no_events() {
+    let addr = start().await;
+    let socket = rebound(&addr).await;
+    let (_, token) = raw_join(&socket, "hw-a", 1, 0).await;
+    let mut receiver = ReliableReceiver::new();
+    let short = Duration::from_millis(300);
+
+    socket
+        .send(&protocol::new_subscribe(token, &[2]))
+        .await
+        .unwrap();
+    let _bob = join(&addr, "hw-b", 2).await;
+    let updates = room_updates(&socket, token, &mut receiver, true, short).await;
+    assert!(updates.iter().any(|(room_id, _)| *room_id == 2));
+
+    socket
+        .send(&protocol::new_subscribe(token, &[]))
+        .await
+        .unwrap();
+    let _carol = join(&addr, "hw-c", 2).await;
+    let _dave = join(&addr, "hw-d", 1).await;
+    let updates = room_updates(&socket, token, &mut receiver, true, short).await;
+    assert!(updates.iter().any(|(room_id, _)| *room_id == 1));
+    assert!(updates.iter().all(|(room_id, _)| *room_id != 2));
+}
+
+#[tokio::test]
+async fn parity_is_held_to_the_media_limits() {
+    let addr = start().await;
+    let alice = rebound(&addr).await;
+    raw_join(&alice, "hw-a", 1, protocol::CAP_FEC).await;
+    let bob = rebound(&addr).await;
+    raw_join(&bob, "hw-b", 1, protocol::CAP_FEC).await;
+
+    let oversized = vec![1u8; 1400];
+    alice
+        .send(&protocol::new_parity(0, 4, 0, &oversized))
+        .await
+        .unwrap();
+    let code = wait_for(&alice, |pkt| match pkt {
+        PacketType::Error {
+            code,
+            request_type: protocol::PARITY,
+            ..
+        } => Some(code),
+        _ => None,
+    })
+    .await;
+    assert_eq!(code, ErrorCode::FrameTooLarge);
+
+    // Far more parity than the room bitrate allows in one go.
+    let parity = vec![1u8; 1000];
+    for seq in 0..100 {
+        alice
+            .send(&protocol::new_parity(seq * 4, 4, 0, &parity))
+            .await
+            .unwrap();
+    }
+    let mut forwarded = 0;
+    let mut buf = vec![0u8; 2048];
+    while let Ok(Ok(len)) = timeout(Duration::from_millis(300), bob.recv(&mut buf)).await {
+        if let Ok(PacketType::TalkedParity { .. }) = protocol::parse_from_server_packet(&buf[..len])
+        {
+            forwarded += 1;
+        }
+    }
+    assert!(forwarded > 0 && forwarded < 20, "forwarded {forwarded}");
+}
+
+#[tokio::test]
+async fn garbage_from_a_connected_address_does_not_block_it() {
+    let addr = start().await;
+    let alice = join(&addr, "hw-a", 1).await;
+
+    // Anyone can forge packets from alice's address.
+    let forger = rebound(&addr).await;
+    let mut garbage = protocol::new_ping();
+    garbage.push(0);
+    for _ in 0..35 {
+        forger.send(&garbage).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    alice.switch(2).await.unwrap();
+    assert_eq!(alice.room_id(), 2);
+}
+
+#[tokio::test]
+async fn garbage_blocks_an_address_once_its_users_leave() {
+    let addr = start().await;
+    join(&addr, "hw-a", 1).await.leave().await.unwrap();
+
+    let forger = rebound(&addr).await;
+    let mut garbage = protocol::new_ping();
+    garbage.push(0);
+    for _ in 0..35 {
+        forger.send(&garbage).await.unwrap();
+    }
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    forger.send(&protocol::new_ping()).await.unwrap();
+    let mut buf = vec![0u8; 2048];
+    assert!(
+        timeout(Duration::from_millis(300), forger.recv(&mut buf))
+            .await
+            .is_err()
+    );
+}
+
+/// The reason of the DISCONNECT that reaches `socket`, reliable or not.
+async fn disconnect_reason(socket: &UdpSocket) -> DisconnectReason {
+    wait_for(socket, |pkt| match pkt {
+        PacketType::Disconnect { reason, .. } => Some(reason),
+        PacketType::Reliable { payload, .. } => {
+            match protocol::parse_from_server_packet(&payload) {
+                Ok(PacketType::Disconnect { reason, .. }) => Some(reason),
+                _ => None,
+            }
+        }
+        _ => None,
+    })
+    .await
+}
+
+#[tokio::test]
+async fn disconnects_carry_their_reason() {
+    let addr = start().await;
+
+    // A JOIN from before version negotiation, which has no room for a cookie.
+    let old = rebound(&addr).await;
+    let mut legacy_join = protocol::MAGIC.to_vec();
+    legacy_join.extend_from_slice(&protocol::JOIN.to_be_bytes());
+    legacy_join.extend_from_slice(b"old\0hw-old\0");
+    legacy_join.extend_from_slice(&1u16.to_be_bytes());
+    old.send(&legacy_join).await.unwrap();
+    assert_eq!(
+        disconnect_reason(&old).await,
+        DisconnectReason::UnsupportedVersion
+    );
+
+    let flooder = rebound(&addr).await;
+    raw_join(&flooder, "hw-f", 1, 0).await;
+    let oversized = vec![0u8; 1300];
+    for seq in 0..250 {
+        if seq % 25 == 0 {
+            // Let the server keep up rather than lose packets in the socket.
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        flooder
+            .send(&protocol::new_talk(
+                seq,
+                0,
+                protocol::PAYLOAD_OPUS,
+                &oversized,
+            ))
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        disconnect_reason(&flooder).await,
+        DisconnectReason::Flooding
+    );
+}